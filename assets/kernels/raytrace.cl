@@ -7,6 +7,24 @@
 #define INV_PI 0.3183098f
 #define AMBIENT 0.05f
 #define GAMMA 2.2f
+#define AUX_SIZE 10
+#define DENOISE_SIGMA_NORMAL 64.0f
+#define DENOISE_SIGMA_DEPTH 0.05f
+#define DENOISE_MIN_ALBEDO 0.01f
+#define REPROJECT_DEPTH_TOLERANCE 0.05f
+#define REPROJECT_NORMAL_TOLERANCE 0.9f
+#define STAT_SIZE 2
+#define ADAPTIVE_MIN_SAMPLES 16.0f
+#define ADAPTIVE_EPSILON 0.01f
+#define ID_SIZE 3
+#define AOV_DEPTH_SCALE 10.0f
+
+// homogeneous participating medium, zero density is no medium
+struct Medium{
+    float density;
+    float3 albedo;
+    float g;
+};
 
 struct Material{
     float3 col;
@@ -20,6 +38,9 @@ struct Material{
     uint roughnessmap;
     uint metalicmap;
     float texscale;
+    uint dispersion; // 0: none, 1: cauchy, 2: sellmeier
+    float disp[6];
+    struct Medium medium;
 };
 
 #define pPLANE 6
@@ -32,12 +53,16 @@ struct RayHit{
     float t;
     uint mat_index;
     uchar ptype;
+    uint object; // index in the top level bvh, planes are not in it and have none
+    uint prim; // triangle within the mesh of a model
 };
 
 //hit nothing
 struct RayHit NullRayHit(){
     struct RayHit hit;
     hit.t = MAX_RENDER_DIST;
+    hit.object = UINT_MAX;
+    hit.prim = UINT_MAX;
     return hit;
 }
 
@@ -54,11 +79,21 @@ struct Ray{
 #define SC_TRI 4
 #define SC_SCENE 5
 // sizes, must be the same as rust provides
-#define SC_MAT_SIZE 15
-#define SC_LIGHT_SIZE 7
+#define SC_MAT_SIZE 27
+#define SC_LIGHT_SIZE 14
 #define SC_PLANE_SIZE 7
 #define SC_SPHERE_SIZE 5
 #define SC_TRI_SIZE 10
+// start of the fog in the scene params: density, albedo, g, distance
+#define SC_FOG (2 * SC_SCENE + 22)
+// light types, must be the same as LightType
+#define LIGHT_POINT 0
+#define LIGHT_DIRECTIONAL 1
+#define LIGHT_SPOT 2
+// start of the procedural sky in the scene params, must be the same as Scene::SKY_PARAM_START
+#define SC_SKY 40
+// scales the luminance of the sky model, must be the same as PREETHAM_SCALE
+#define PREETHAM_SCALE 0.05f
 
 struct Scene{
     uint *params, *tex_params;
@@ -70,6 +105,9 @@ struct Scene{
     float sky_intensity;
     float sky_min;
     float sky_pow;
+    bool proc_sky;
+    float time; // moment in the shutter interval of the current path
+    bool spectral;
 };
 
 //first byte in array where this type starts
@@ -96,6 +134,12 @@ struct Material ExtractMaterial(uint off, float *arr){
     mat.roughnessmap = (uint)arr[off + 12];
     mat.metalicmap  = (uint)arr[off + 13];
     mat.texscale = arr[off + 14];
+    mat.dispersion = (uint)arr[off + 15];
+    for(uint i = 0; i < 6; i++)
+        mat.disp[i] = arr[off + 16 + i];
+    mat.medium.density = arr[off + 22];
+    mat.medium.albedo = (float3)(arr[off + 23], arr[off + 24], arr[off + 25]);
+    mat.medium.g = arr[off + 26];
     return mat;
 }
 
@@ -373,6 +417,7 @@ struct RayHit InterSceneBvh(struct Ray *inray, struct Scene *scene){
     float3 backup_rdinv;
     float model_yaw;
     float3 model_pos;
+    uint model_object = 0;
     bool hit_in_mesh = false;
 
     while(ptr < size){
@@ -403,15 +448,19 @@ struct RayHit InterSceneBvh(struct Ray *inray, struct Scene *scene){
                     if(prim_type == 0){ // model
                         toplevel = false;
                         first_toplevel_vertex = ptr - 1;
-                        uint model_start = models_start + (prim_index * 8);
+                        model_object = i;
+                        uint model_start = models_start + (prim_index * 12);
                         model_pos = ExtractFloat3FromInts(scene->bvh, model_start + 0);
                         float3 rot = ExtractFloat3FromInts(scene->bvh, model_start + 3);
-                        uint mat = scene->bvh[model_start + 6];
-                        uint mesh = scene->bvh[model_start + 7];
+                        float3 motion = ExtractFloat3FromInts(scene->bvh, model_start + 6);
+                        float spin = as_float(scene->bvh[model_start + 9]);
+                        uint mat = scene->bvh[model_start + 10];
+                        uint mesh = scene->bvh[model_start + 11];
                         vertices_start = scene->bvh[(mesh + 1) * 2 + 2];
                         mesh_start = scene->bvh[(mesh + 1) * 2 + 3];
                         stack[ptr++] = 0;
-                        model_yaw = Orientation(rot).x;
+                        model_pos += motion * scene->time;
+                        model_yaw = Orientation(rot).x + spin * scene->time;
                         backup_ray = ray;
                         backup_rdinv = rdinv;
                         ray.pos = ray.pos - model_pos;
@@ -426,6 +475,8 @@ struct RayHit InterSceneBvh(struct Ray *inray, struct Scene *scene){
                         if(hit){
                             coff = off;
                             ptype = pSPHERE;
+                            closest.object = i;
+                            closest.prim = 0;
                         }
                     } else { // triangle
                         uint off = tri_start + prim_index * SC_TRI_SIZE;
@@ -436,6 +487,8 @@ struct RayHit InterSceneBvh(struct Ray *inray, struct Scene *scene){
                         if(hit){
                             coff = off;
                             ptype = pTRI;
+                            closest.object = i;
+                            closest.prim = 0;
                         }
                     }
                 }
@@ -450,6 +503,8 @@ struct RayHit InterSceneBvh(struct Ray *inray, struct Scene *scene){
                         // hit.mat = model.mat;
                         coff = off;
                         ptype = pTRI;
+                        closest.object = model_object;
+                        closest.prim = i;
                         hit_in_mesh = true;
                     }
                 }
@@ -619,26 +674,244 @@ uint InterTest(struct Ray *ray, struct Scene *scene){
 // #define INTER_SCENE InterScene
 #define INTER_SCENE InterSceneBvh
 
+// Preetham et al. (1999), coefficients are computed on the host in sky.rs
+float Perez(uint off, float cos_theta, float gamma, float cos_gamma, struct Scene *scene){
+    float a = as_float(scene->params[off + 0]);
+    float b = as_float(scene->params[off + 1]);
+    float c = as_float(scene->params[off + 2]);
+    float d = as_float(scene->params[off + 3]);
+    float e = as_float(scene->params[off + 4]);
+    return (1.0f + a * exp(b / max(cos_theta, 0.01f))) * (1.0f + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+}
+
+float3 XyYToRgb(float Y, float x, float y){
+    if(y < EPSILON) return (float3)(0.0f);
+    float X = x / y * Y;
+    float Z = (1.0f - x - y) / y * Y;
+    float3 rgb = (float3)(
+         3.2406f * X - 1.5372f * Y - 0.4986f * Z,
+        -0.9689f * X + 1.8758f * Y + 0.0415f * Z,
+         0.0557f * X - 0.2040f * Y + 1.0570f * Z
+    );
+    return max(rgb, 0.0f);
+}
+
+float3 ProceduralSkyCol(float3 dir, struct Scene *scene, bool with_sun){
+    if(dir.y < 0.0f)
+        return ExtractFloat3FromInts(scene->params, SC_SKY + 26);
+    float3 sun_dir = ExtractFloat3FromInts(scene->params, SC_SKY + 1);
+    float sun_cos = as_float(scene->params[SC_SKY + 4]);
+    float cos_gamma = clamp(dot(dir, sun_dir), -1.0f, 1.0f);
+    if(with_sun && cos_gamma >= sun_cos)
+        return ExtractFloat3FromInts(scene->params, SC_SKY + 5);
+    float gamma = acos(cos_gamma);
+    float3 zenith = ExtractFloat3FromInts(scene->params, SC_SKY + 23);
+    float Y = zenith.x * Perez(SC_SKY + 8, dir.y, gamma, cos_gamma, scene);
+    float x = zenith.y * Perez(SC_SKY + 13, dir.y, gamma, cos_gamma, scene);
+    float y = zenith.z * Perez(SC_SKY + 18, dir.y, gamma, cos_gamma, scene);
+    return XyYToRgb(Y, x, y) * PREETHAM_SCALE;
+}
+
 //get sky colour
 float3 SkyCol(float3 nor, struct Scene *scene){
+    if(scene->proc_sky)
+        return ProceduralSkyCol(nor, scene, true);
     if(scene->skybox == 0)
         return scene->skycol;
     float2 uv = SkySphereUV(nor);
     return GetTexCol(scene->skybox - 1, uv, scene);
 }
 
-//get diffuse light strength for hit for a light
-float2 BlinnSingle(float3 lpos, float lpow, float3 viewdir, float roughness, struct RayHit *hit, struct Scene *scene){
-    float3 toL = lpos - hit->pos;
-    float dist = fast_length(toL);
-    toL /= dist + EPSILON;
+float3 TangentToWorld(float3 wg, float3 wm){
+    float3 w = fabs(wg.x) > 0.99f ? (float3)(0.0f, 1.0f, 0.0f) : (float3)(1.0f, 0.0f, 0.0f);
+    float3 t = fast_normalize(cross(w, wg));
+    float3 b = cross(t, wg);
+    return wm.x * t + wm.y * b + wm.z * wg;
+}
+
+// -----------------------------------------
+
+// credit: George Marsaglia
+uint Xor32(uint* seed){
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    return *seed;
+}
+
+float U32tf01(uint i){
+   return (float)i * 2.3283064e-10;
+}
+
+// Wang hash
+// https://riptutorial.com/opencl/example/20715/using-thomas-wang-s-integer-hash-function
+uint WangHash(uint seed)
+{
+    seed = (seed ^ 61) ^ (seed >> 16);
+    seed *= 9;
+    seed = seed ^ (seed >> 4);
+    seed *= 0x27d4eb2d;
+    seed = seed ^ (seed >> 15);
+    return seed;
+}
+
+// samplers of the random numbers of a path, mirrors sampler.rs
+#define SAMPLER_RANDOM 0
+#define SAMPLER_SOBOL 1
+#define SAMPLER_BLUE_NOISE 2
+#define BLUE_NOISE_SIZE 64
+
+// every call to Rand is the next dimension of the sample of a pixel
+struct Sampler{
+    uint mode;
+    uint seed; // state of Xor32, the scramble of the pixel for the others
+    uint index;
+    uint dim;
+    uint x;
+    uint y;
+    __global float *blue_noise;
+};
+
+uint ReverseBits(uint x){
+    x = ((x >> 1) & 0x55555555u) | ((x & 0x55555555u) << 1);
+    x = ((x >> 2) & 0x33333333u) | ((x & 0x33333333u) << 2);
+    x = ((x >> 4) & 0x0f0f0f0fu) | ((x & 0x0f0f0f0fu) << 4);
+    x = ((x >> 8) & 0x00ff00ffu) | ((x & 0x00ff00ffu) << 8);
+    return (x >> 16) | (x << 16);
+}
+
+// Laine-Karras style permutation, only lower bits affect higher bits
+uint LaineKarras(uint x, uint seed){
+    x += seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
+}
+
+uint NestedUniformScramble(uint x, uint seed){
+    return ReverseBits(LaineKarras(ReverseBits(x), seed));
+}
+
+// second dimension of the Sobol sequence, the first is the bit reversed index
+uint SobolDim1(uint index){
+    uint res = 0;
+    for(uint v = 1u << 31; index != 0; index >>= 1, v ^= v >> 1)
+        if(index & 1) res ^= v;
+    return res;
+}
+
+// Burley (2020): Practical Hash-based Owen Scrambling
+// a shuffled and scrambled 2d Sobol sequence per pair of dimensions
+float Rand(struct Sampler *smp){
+    if(smp->mode == SAMPLER_RANDOM)
+        return U32tf01(Xor32(&smp->seed));
+    uint dim = smp->dim++;
+    uint pair_seed = WangHash(smp->seed ^ WangHash(dim >> 1));
+    uint index = NestedUniformScramble(smp->index, pair_seed);
+    uint v = (dim & 1) ? SobolDim1(index) : ReverseBits(index);
+    float r = U32tf01(NestedUniformScramble(v, WangHash(pair_seed + dim)));
+    if(smp->mode == SAMPLER_BLUE_NOISE){
+        // Cranley-Patterson rotation by the blue noise mask, shifted for every dimension
+        uint off = WangHash(dim);
+        uint bx = (smp->x + off) % BLUE_NOISE_SIZE;
+        uint by = (smp->y + (off >> 16)) % BLUE_NOISE_SIZE;
+        r += smp->blue_noise[bx + by * BLUE_NOISE_SIZE];
+        if(r >= 1.0f) r -= 1.0f;
+    }
+    return r;
+}
+
+// Sphere sampling adapted from University of Mons lecture slides
+// https://angms.science/doc/RM/randUnitVec.pdf
+float3 RandomSpherePoint(struct Sampler *smp){
+    float a = Rand(smp) * PI2;
+    float b = Rand(smp) * PI2;
+    float z = cos(b);
+    float z2 = z * z;
+    return (float3)(sqrt(1.0f - z2) * cos(a), sqrt(1.0f - z2) * sin(a), z);
+}
+
+// uniform direction in the cone around the z-axis with the given cosine of its half angle
+float3 RandomConePoint(struct Sampler *smp, float cos_max){
+    float cost = 1.0f - Rand(smp) * (1.0f - cos_max);
+    float sint = sqrt(max(0.0f, 1.0f - cost * cost));
+    float phi = Rand(smp) * PI2;
+    return (float3)(sint * cos(phi), sint * sin(phi), cost);
+}
+
+// uniform point on the unit disk, or on a regular polygon with the given amount of blades
+float2 LensPoint(struct Sampler *smp, uint blades){
+    float r1 = Rand(smp);
+    float r2 = Rand(smp);
+    if(blades < 3){
+        float phi = r2 * PI2;
+        return sqrt(r1) * (float2)(cos(phi), sin(phi));
+    }
+    //pick one of the triangles the polygon consists of, then a uniform point in it
+    float seg = PI2 / blades;
+    float i = floor(r1 * blades);
+    float2 a = (float2)(cos(i * seg), sin(i * seg));
+    float2 b = (float2)(cos((i + 1.0f) * seg), sin((i + 1.0f) * seg));
+    float t = Rand(smp);
+    return sqrt(r2) * ((1.0f - t) * a + t * b);
+}
+
+// https://www.shadertoy.com/view/4s3cRr
+// Align along normal method taken from a shadertoy
+float3 RandomHemispherePoint(struct Sampler *smp, float3 normal){
+    float3 dir = RandomSpherePoint(smp);
+    return dot(dir, normal) < 0.0 ? -dir : dir;
+}
+
+// direction around the z-axis distributed by the Henyey-Greenstein phase function
+float3 SampleHenyeyGreenstein(float g, struct Sampler *smp){
+    float u = Rand(smp);
+    float cost;
+    if(fabs(g) < 0.001f){
+        cost = 1.0f - 2.0f * u;
+    } else {
+        float s = (1.0f - g * g) / (1.0f - g + 2.0f * g * u);
+        cost = (1.0f + g * g - s * s) / (2.0f * g);
+    }
+    float sint = sqrt(max(0.0f, 1.0f - cost * cost));
+    float phi = Rand(smp) * PI2;
+    return (float3)(sint * cos(phi), sint * sin(phi), cost);
+}
+
+//get diffuse light strength for hit for the light at off
+//directional lights are sampled within their disk, which gives soft shadows over the aa passes
+float2 BlinnSingle(uint off, float3 viewdir, float roughness, struct RayHit *hit, struct Scene *scene, struct Sampler *smp){
+    float* arr = scene->items;
+    float3 lpos = ExtractFloat3(off + 0, arr);
+    float lpow = arr[off + 3];
+    uint ltype = (uint)arr[off + 7];
+    float3 ldir = ExtractFloat3(off + 8, arr);
+    float3 toL;
+    float dist, power;
+    if(ltype == LIGHT_DIRECTIONAL){
+        toL = TangentToWorld(-ldir, RandomConePoint(smp, arr[off + 11]));
+        dist = MAX_RENDER_DIST;
+        power = lpow;
+    } else {
+        toL = lpos - hit->pos;
+        dist = fast_length(toL);
+        toL /= dist + EPSILON;
+        power = lpow / (PI4 * dist * dist);
+        if(ltype == LIGHT_SPOT){
+            float cos_outer = arr[off + 11];
+            float cos_inner = arr[off + 12];
+            float t = (dot(-toL, ldir) - cos_outer) / max(cos_inner - cos_outer, EPSILON);
+            power *= pow(clamp(t, 0.0f, 1.0f), arr[off + 13]);
+        }
+    }
     //diffuse
     float3 nor = hit->nor;
     float angle = dot(nor, toL);
     if(angle <= EPSILON)
         return (float2)(0.0f);
     angle = max(0.0f, angle);
-    float power = lpow / (PI4 * dist * dist);
     if(power < 0.01f)
         return (float2)(0.0f);
     //exposed to light or not
@@ -646,7 +919,7 @@ float2 BlinnSingle(float3 lpos, float lpow, float3 viewdir, float roughness, str
     lray.pos = hit->pos + hit->nor * EPSILON;
     lray.dir = toL;
     struct RayHit lhit = INTER_SCENE(&lray, scene);
-    if(lhit.t <= dist)
+    if(lhit.t < dist)
         return (float2)(0.0f);
     //specular
     float3 halfdir = fast_normalize(toL + -viewdir);
@@ -656,7 +929,7 @@ float2 BlinnSingle(float3 lpos, float lpow, float3 viewdir, float roughness, str
 }
 
 //get diffuse light incl colour of hit with all lights
-void Blinn(struct RayHit *hit, struct Scene *scene, float3 viewdir, float3 colour, float roughness, float3 *out_diff, float3 *out_spec){
+void Blinn(struct RayHit *hit, struct Scene *scene, struct Sampler *smp, float3 viewdir, float3 colour, float roughness, float3 *out_diff, float3 *out_spec){
     float3 col = (float3)(AMBIENT);
     float3 spec = (float3)(0.0f);
     float* arr = scene->items;
@@ -664,13 +937,29 @@ void Blinn(struct RayHit *hit, struct Scene *scene, float3 viewdir, float3 colou
     uint start = ScGetStart(SC_LIGHT, scene);
     for(uint i = 0; i < count; i++){
         uint off = start + i * SC_LIGHT_SIZE;
-        float3 lpos = (float3)(arr[off + 0], arr[off + 1], arr[off + 2]);
-        float lpow = arr[off + 3];
         float3 lcol = (float3)(arr[off + 4], arr[off + 5], arr[off + 6]);
-        float2 res = BlinnSingle(lpos, lpow, viewdir, roughness, hit, scene);
+        float2 res = BlinnSingle(off, viewdir, roughness, hit, scene, smp);
         col += res.x * lcol;
         spec += res.y * lcol;
     }
+    if(scene->proc_sky){
+        float sun_cos = as_float(scene->params[SC_SKY + 4]);
+        float3 sun_dir = TangentToWorld(ExtractFloat3FromInts(scene->params, SC_SKY + 1), RandomConePoint(smp, sun_cos));
+        float3 sun_col = ExtractFloat3FromInts(scene->params, SC_SKY + 5) * PI2 * (1.0f - sun_cos);
+        float angle = dot(hit->nor, sun_dir);
+        if(angle > EPSILON){
+            struct Ray lray;
+            lray.pos = hit->pos + hit->nor * EPSILON;
+            lray.dir = sun_dir;
+            struct RayHit lhit = INTER_SCENE(&lray, scene);
+            if(lhit.t >= MAX_RENDER_DIST){
+                float3 halfdir = fast_normalize(sun_dir + -viewdir);
+                float specangle = max(dot(halfdir, hit->nor), 0.0f);
+                col += angle * sun_col;
+                spec += pow(specangle, 16.0f / roughness) * sun_col;
+            }
+        }
+    }
     *out_diff = col * colour;
     *out_spec = spec * (1.0f - roughness);
 }
@@ -718,20 +1007,76 @@ void Blinn(struct RayHit *hit, struct Scene *scene, float3 viewdir, float3 colou
     }\
 
 //Recursion only works with one function
-float3 RayTrace(struct Ray *ray, struct Scene *scene, uint depth){
+// Henyey-Greenstein phase function of the cosine between the old and new direction
+float HenyeyGreenstein(float cos_theta, float g){
+    float d = 1.0f + g * g - 2.0f * g * cos_theta;
+    return (1.0f - g * g) / (PI4 * d * sqrt(d));
+}
+
+struct Medium SceneFog(struct Scene *scene){
+    struct Medium fog;
+    fog.density = as_float(scene->params[SC_FOG + 0]);
+    fog.albedo = ExtractFloat3FromInts(scene->params, SC_FOG + 1);
+    fog.g = as_float(scene->params[SC_FOG + 4]);
+    return fog;
+}
+
+// single scattering through the fog over dist, lit by the ambient light and the sun without shadows
+float3 InFog(float3 col, float3 dir, float dist, struct Scene *scene){
+    struct Medium fog = SceneFog(scene);
+    if(fog.density <= 0.0f)
+        return col;
+    float trans = exp(-fog.density * min(dist, as_float(scene->params[SC_FOG + 5])));
+    float3 light = (float3)(AMBIENT);
+    if(scene->proc_sky){
+        float3 sun_dir = ExtractFloat3FromInts(scene->params, SC_SKY + 1);
+        float sun_cos = as_float(scene->params[SC_SKY + 4]);
+        float3 sun_rad = ExtractFloat3FromInts(scene->params, SC_SKY + 5);
+        light += sun_rad * PI2 * (1.0f - sun_cos) * PI4 * HenyeyGreenstein(dot(dir, sun_dir), fog.g);
+    }
+    return col * trans + light * fog.albedo * (1.0f - trans);
+}
+
+// transmittance of a shadow ray towards the sun. It passes through the boundaries of volumes
+// without a surface and is attenuated over the distance it travels through each medium.
+float SunTransmittance(struct Ray sray, struct Scene *scene, struct Medium medium, bool in_fog){
+    struct Medium fog = SceneFog(scene);
+    float fog_dist = as_float(scene->params[SC_FOG + 5]);
+    float travelled = 0.0f;
+    float depth = 0.0f; // optical depth
+    for(uint i = 0; i < 8; i++){
+        struct RayHit shit = INTER_SCENE(&sray, scene);
+        // the fog ends at the fog distance
+        float seg = in_fog ? clamp(fog_dist - travelled, 0.0f, shit.t) : shit.t;
+        depth += medium.density * seg;
+        if(shit.t >= MAX_RENDER_DIST) return exp(-depth);
+        struct Material mat = GetMaterialFromIndex(shit.mat_index, scene);
+        if(mat.medium.density <= 0.0f || mat.refraction > EPSILON) return 0.0f;
+        bool entering = dot(shit.nor, sray.dir) < 0.0f;
+        medium = entering ? mat.medium : fog;
+        in_fog = !entering;
+        travelled += shit.t;
+        sray.pos = shit.pos + sray.dir * EPSILON;
+    }
+    return 0.0f;
+}
+
+float3 RayTrace(struct Ray *ray, struct Scene *scene, struct Sampler *smp, uint depth){
     if(depth == 0) return SkyCol(ray->dir, scene);
 
     //hit
     struct RayHit hit = INTER_SCENE(ray, scene);
     if(hit.t >= MAX_RENDER_DIST)
-        return SkyCol(ray->dir, scene);
+        return InFog(SkyCol(ray->dir, scene), ray->dir, hit.t, scene);
     struct Material mat = GetMaterialFromIndex(hit.mat_index, scene);
+    if(mat.emittance > EPSILON)
+        return InFog(mat.col * mat.emittance, ray->dir, hit.t, scene);
 
     HANDLE_TEXTURES;
 
     //diffuse, specular
     float3 diff, spec;
-    Blinn(&hit, scene, ray->dir, mat.col, mat.roughness, &diff, &spec);
+    Blinn(&hit, scene, smp, ray->dir, mat.col, mat.roughness, &diff, &spec);
 
     //reflection
     float3 newdir = fast_normalize(reflect(ray->dir, hit.nor));
@@ -743,8 +1088,8 @@ float3 RayTrace(struct Ray *ray, struct Scene *scene, uint depth){
 
     // Does not get corrupted to version inside recursive call if not pointer
     float refl_mul = mat.reflectivity;
-    float3 refl = RayTrace(&nray, scene, depth - 1);
-    return (diff * (1.0f - refl_mul)) + (refl * refl_mul) + spec;
+    float3 refl = RayTrace(&nray, scene, smp, depth - 1);
+    return InFog((diff * (1.0f - refl_mul)) + (refl * refl_mul) + spec, ray->dir, hit.t, scene);
 }
 
 // -----------------------------------------
@@ -786,81 +1131,196 @@ float3 MicroFacet_IS_Tangent(float a2, float r0, float r1)
     return (float3)(sint * cos(phi), sint * sin(phi), cost);
 }
 
-float3 TangentToWorld(float3 wg, float3 wm){
-    float3 w = fabs(wg.x) > 0.99f ? (float3)(0.0f, 1.0f, 0.0f) : (float3)(1.0f, 0.0f, 0.0f);
-    float3 t = fast_normalize(cross(w, wg));
-    float3 b = cross(t, wg);
-    return wm.x * t + wm.y * b + wm.z * wg;
+// hero wavelength spectral sampling, mirrors spectral.rs
+#define LAMBDA_MIN 380.0f
+#define LAMBDA_RANGE 340.0f
+#define LAMBDA_D 587.6f
+
+// the wavelengths of a path, evenly rotated away from the hero wavelength
+float4 HeroWavelengths(float u){
+    float4 off = (float4)(0.0f, 0.25f, 0.5f, 0.75f) * LAMBDA_RANGE + u * LAMBDA_RANGE;
+    return LAMBDA_MIN + fmod(off, LAMBDA_RANGE);
 }
 
-// -----------------------------------------
+float CieLobe(float x, float mu, float s1, float s2){
+    float t = (x - mu) / (x < mu ? s1 : s2);
+    return exp(-0.5f * t * t);
+}
 
-// credit: George Marsaglia
-uint Xor32(uint* seed){
-    *seed ^= *seed << 13;
-    *seed ^= *seed >> 17;
-    *seed ^= *seed << 5;
-    return *seed;
+// Wyman et al. (2013): Simple analytic approximations to the CIE XYZ color matching functions
+float3 CieXyz(float l){
+    return (float3)(
+        1.056f * CieLobe(l, 599.8f, 37.9f, 31.0f) + 0.362f * CieLobe(l, 442.0f, 16.0f, 26.7f)
+            - 0.065f * CieLobe(l, 501.1f, 20.4f, 26.2f),
+        0.821f * CieLobe(l, 568.8f, 46.9f, 40.5f) + 0.286f * CieLobe(l, 530.9f, 16.3f, 31.1f),
+        1.217f * CieLobe(l, 437.0f, 11.8f, 36.0f) + 0.681f * CieLobe(l, 459.0f, 26.0f, 13.8f)
+    );
 }
 
-float U32tf01(uint i){
-   return (float)i * 2.3283064e-10;
+// value of a smooth spectrum for an rgb colour, the three basis curves sum to one
+float4 RgbToSpectrum(float3 rgb, float4 l){
+    float4 s1 = smoothstep(480.0f, 520.0f, l);
+    float4 s2 = smoothstep(570.0f, 610.0f, l);
+    return rgb.x * s2 + rgb.y * (s1 - s2) + rgb.z * (1.0f - s1);
 }
 
-// Wang hash
-// https://riptutorial.com/opencl/example/20715/using-thomas-wang-s-integer-hash-function
-uint WangHash(uint seed)
-{
-    seed = (seed ^ 61) ^ (seed >> 16);
-    seed *= 9;
-    seed = seed ^ (seed >> 4);
-    seed *= 0x27d4eb2d;
-    seed = seed ^ (seed >> 15);
-    return seed;
+// estimate of the rgb colour of the spectrum sampled at the wavelengths of one path
+float3 SpectrumToRgb(float4 v, float4 l){
+    float3 xyz = v.x * CieXyz(l.x) + v.y * CieXyz(l.y) + v.z * CieXyz(l.z) + v.w * CieXyz(l.w);
+    xyz *= LAMBDA_RANGE / 4.0f;
+    return (float3)(
+        dot((float3)( 0.025246f, -0.011976f, -0.003884f), xyz),
+        dot((float3)(-0.009542f,  0.018474f,  0.000409f), xyz),
+        dot((float3)( 0.000574f, -0.002102f,  0.010891f), xyz)
+    );
 }
 
-// Sphere sampling adapted from University of Mons lecture slides
-// https://angms.science/doc/RM/randUnitVec.pdf
-float3 RandomSpherePoint(uint* seed){
-    float a = U32tf01(Xor32(seed)) * PI2;
-    float b = U32tf01(Xor32(seed)) * PI2;
-    float z = cos(b);
-    float z2 = z * z;
-    return (float3)(sqrt(1.0f - z2) * cos(a), sqrt(1.0f - z2) * sin(a), z);
+// index of refraction at the given wavelength, Cauchy or Sellmeier
+float DispersedIor(struct Material *mat, float lambda){
+    float l = lambda * 0.001f;
+    float l2 = l * l;
+    if(mat->dispersion == 1){
+        float ld = LAMBDA_D * 0.001f;
+        return mat->refraction + mat->disp[0] * (1.0f / l2 - 1.0f / (ld * ld));
+    } else if(mat->dispersion == 2){
+        float n2 = 1.0f;
+        for(uint i = 0; i < 3; i++)
+            n2 += mat->disp[i] * l2 / (l2 - mat->disp[i + 3]);
+        return sqrt(max(n2, 1.0f));
+    }
+    return mat->refraction;
 }
 
-// https://www.shadertoy.com/view/4s3cRr
-// Align along normal method taken from a shadertoy
-float3 RandomHemispherePoint(uint* seed, float3 normal){
-    float3 dir = RandomSpherePoint(seed);
-    return dot(dir, normal) < 0.0 ? -dir : dir;
+// colours along a path are rgb, or in spectral mode the spectrum at the path's wavelengths
+float4 PathCol(float3 rgb, float4 lambdas, bool spectral){
+    return spectral ? RgbToSpectrum(rgb, lambdas) : (float4)(rgb, 0.0f);
 }
 
-float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
+// albedo, normal and depth of the first hit, guides for the denoiser, and the aovs
+struct Aux{
+    float3 albedo;
+    float3 nor;
+    float depth;
+    float3 direct; // light reaching the camera after at most one bounce
+    uint mat;
+    uint object;
+    uint prim;
+};
+
+float3 PathTrace(struct Ray ray, struct Scene *scene, struct Sampler *smp, struct Aux *aux){
     //return ((float)InterTest(&ray, scene) / 32.0f) * (float3)(1.0f);
 
-    float3 E = (float3)(1.0f); // emittance accumulator
+    bool spectral = scene->spectral;
+    float4 lambdas = spectral ? HeroWavelengths(Rand(smp)) : (float4)(0.0f);
+    bool collapsed = false; // only the hero wavelength is left after a dispersive interface
+    float4 E = (float4)(1.0f); // emittance accumulator
+    float4 L = (float4)(0.0f); // light gathered by next event estimation
     float ncontext = 1.0f; // refraction index of current medium
     float3 hitpos = ray.pos;
     float3 distacc = (float3)(1.0f);
     uint rounds = 0;
+    bool sun_sampled = false; // the sun was already sampled with nee at the last bounce
+    struct Medium fog = SceneFog(scene);
+    float fog_dist = as_float(scene->params[SC_FOG + 5]);
+    struct Medium medium = fog; // the medium the path travels through
+    bool in_fog = true;
+    bool guided = false;
+    uint bounces = 0; // scattering events so far
+    float4 D = (float4)(0.0f); // the part of E + L that is direct light
+    aux->albedo = (float3)(1.0f);
+    aux->nor = (float3)(0.0f);
+    aux->depth = MAX_RENDER_DIST;
+    aux->direct = (float3)(0.0f);
+    aux->mat = UINT_MAX;
+    aux->object = UINT_MAX;
+    aux->prim = UINT_MAX;
 
     while(rounds < 10){
         rounds++;
         struct RayHit hit = INTER_SCENE(&ray, scene);
+        // distance tracking: scatter in the medium before reaching the surface
+        if(medium.density > 0.0f){
+            float max_t = in_fog ? min(hit.t, fog_dist) : hit.t;
+            float ts = -log(1.0f - Rand(smp)) / medium.density;
+            if(ts < max_t){
+                float3 spos = ray.pos + ray.dir * ts;
+                E *= PathCol(medium.albedo, lambdas, spectral);
+                // next event estimation towards the sun gives god rays
+                sun_sampled = false;
+                if(scene->proc_sky){
+                    float3 sun_dir = ExtractFloat3FromInts(scene->params, SC_SKY + 1);
+                    float sun_cos = as_float(scene->params[SC_SKY + 4]);
+                    if(sun_dir.y > 0.0f){
+                        sun_sampled = true;
+                        float3 to_sun = TangentToWorld(sun_dir, RandomConePoint(smp, sun_cos));
+                        struct Ray sray;
+                        sray.pos = spos;
+                        sray.dir = to_sun;
+                        float trans = SunTransmittance(sray, scene, medium, in_fog);
+                        if(trans > 0.0f){
+                            float3 sun_rad = ExtractFloat3FromInts(scene->params, SC_SKY + 5);
+                            float solid_angle = PI2 * (1.0f - sun_cos);
+                            float phase = HenyeyGreenstein(dot(ray.dir, to_sun), medium.g);
+                            L += E * PathCol(sun_rad, lambdas, spectral) * phase * solid_angle * trans * scene->sky_intensity;
+                        }
+                    }
+                }
+                ray.pos = spos;
+                ray.dir = TangentToWorld(ray.dir, SampleHenyeyGreenstein(medium.g, smp));
+                hitpos = spos;
+                if(bounces++ == 0) D = L;
+                continue;
+            }
+        }
         if(hit.t >= MAX_RENDER_DIST){
+            if(scene->proc_sky){
+                float3 sky_col = ProceduralSkyCol(ray.dir, scene, !sun_sampled);
+                if(!guided) aux->albedo = sky_col;
+                if(rounds == 1){
+                    aux->direct = sky_col;
+                    return sky_col;
+                }
+                E *= PathCol(sky_col, lambdas, spectral) * scene->sky_intensity;
+                if(bounces <= 1) D += E;
+                break;
+            }
             float3 sky_col = SkyCol(ray.dir, scene);
-            if(rounds == 1) return sky_col;
-            E *= sky_col * max(scene->sky_min, pow(length(sky_col), scene->sky_pow)) * scene->sky_intensity;
+            if(!guided) aux->albedo = sky_col;
+            if(rounds == 1){
+                aux->direct = sky_col;
+                return sky_col;
+            }
+            E *= PathCol(sky_col, lambdas, spectral) * max(scene->sky_min, pow(length(sky_col), scene->sky_pow)) * scene->sky_intensity;
+            if(bounces <= 1) D += E;
             break;
         }
 
         struct Material mat = GetMaterialFromIndex(hit.mat_index, scene);
+        bool boundary = mat.medium.density > 0.0f && mat.refraction <= EPSILON;
+        if(!guided && !boundary){
+            guided = true;
+            aux->albedo = mat.col;
+            aux->nor = dot(hit.nor, ray.dir) < 0.0f ? hit.nor : -hit.nor;
+            aux->depth = hit.t;
+            aux->mat = hit.mat_index;
+            aux->object = hit.object;
+            aux->prim = hit.prim;
+        }
         if(mat.emittance > EPSILON){
-            E *= mat.col * mat.emittance;
+            E *= PathCol(mat.col, lambdas, spectral) * mat.emittance;
+            if(bounces <= 1) D += E;
             break;
         }
 
+        // boundary of a volume without a surface, continue straight on
+        if(boundary){
+            bool entering = dot(hit.nor, ray.dir) < 0.0f;
+            medium = entering ? mat.medium : fog;
+            in_fog = !entering;
+            ray.pos = hit.pos + ray.dir * EPSILON;
+            continue;
+        }
+
         struct Ray nray;
         nray.pos = hit.pos + hit.nor * EPSILON;
 
@@ -870,12 +1330,49 @@ float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
         float3 wg = hit.nor;
         float3 wi = ray.dir;
         float a2 = a * a;
-        float3 wm_tangent = MicroFacet_IS_Tangent(a2, U32tf01(Xor32(seed)), U32tf01(Xor32(seed)));
+
+        // next event estimation towards the sun of the procedural sky
+        sun_sampled = false;
+        if(scene->proc_sky){
+            float3 sun_dir = ExtractFloat3FromInts(scene->params, SC_SKY + 1);
+            float sun_cos = as_float(scene->params[SC_SKY + 4]);
+            if(sun_dir.y > 0.0f){
+                sun_sampled = true;
+                float3 nor = dot(wg, wi) < 0.0f ? wg : -wg;
+                float3 to_sun = TangentToWorld(sun_dir, RandomConePoint(smp, sun_cos));
+                float dln = dot(nor, to_sun);
+                if(dln > 0.0f){
+                    struct Ray sray;
+                    sray.pos = hit.pos + nor * EPSILON;
+                    sray.dir = to_sun;
+                    float trans = SunTransmittance(sray, scene, medium, in_fog);
+                    if(trans > 0.0f){
+                        float3 kSpecNee = kSpec;
+                        if(mat.refraction > EPSILON){
+                            float f0 = (mat.refraction - 1.0f) / (mat.refraction + 1.0f);
+                            kSpecNee = (float3)(f0 * f0);
+                        }
+                        float3 sun_rad = ExtractFloat3FromInts(scene->params, SC_SKY + 5);
+                        float solid_angle = PI2 * (1.0f - sun_cos);
+                        float3 brdf = MicroFacet_BRDF(to_sun, -wi, nor, kSpecNee, max(a, 0.01f));
+                        L += E * PathCol(mat.col * brdf * sun_rad, lambdas, spectral) * dln * solid_angle * trans * scene->sky_intensity;
+                    }
+                }
+            }
+        }
+        float3 wm_tangent = MicroFacet_IS_Tangent(a2, Rand(smp), Rand(smp));
         // answers we need
         float3 F = (float3)(1.0f), wo, wm;
 
         // handle dielectrics
         float mf = mat.refraction;
+        if(spectral && mf > EPSILON && mat.dispersion != 0){
+            mf = DispersedIor(&mat, lambdas.x);
+            if(!collapsed){ // the other wavelengths would leave in other directions
+                E = (float4)(E.x * 4.0f, 0.0f, 0.0f, 0.0f);
+                collapsed = true;
+            }
+        }
         if(mf > EPSILON){
             bool outside = dot(wg, wi) < 0.0;
             float n1, n2;
@@ -886,7 +1383,7 @@ float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
                 // do we have absorption that we should handle?
                 if(dot(mat.abs_fres, mat.abs_fres) > EPSILON){
                     float dist = fast_length(hitpos - hit.pos);
-                    E *= exp(mat.abs_fres * dist);
+                    E *= PathCol(exp(mat.abs_fres * dist), lambdas, spectral);
                 }
                 wg *= -1.0f;
                 n2 = ncontext;
@@ -907,7 +1404,7 @@ float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
                 float fr = 0.5f * (spol * spol + ppol * ppol);
 
                 // choose reflect or refract
-                float decider = U32tf01(Xor32(seed));
+                float decider = Rand(smp);
                 if(decider <= fr){
                     wo = refldir;
                 } else {
@@ -915,6 +1412,9 @@ float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
                     wo = fast_normalize((ray.dir - wm * -c) * n + wm * -sqrt(k));
                     nray.pos = hit.pos - wg * EPSILON;
                     ncontext = mf;
+                    sun_sampled = false; // nee does not cover light transmitted through dielectrics
+                    medium = outside ? mat.medium : fog;
+                    in_fog = !outside;
                 }
             }
         } else { // handle conductors
@@ -927,14 +1427,16 @@ float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
         float dgm = clamp(fabs(dot(wg, wm)), EPSILON, 1.0f);
         float dom = clamp(fabs(dot(wo, wm)), EPSILON, 1.0f);
         float G = G_GGX_Smith(dgo, a2) * G_GGX_Smith(dgm, a2);
-        E *= mat.col * F * G * dom / (dgo * dgm);
+        E *= PathCol(mat.col * F, lambdas, spectral) * G * dom / (dgo * dgm);
 
         nray.dir = wo;
         ray = nray;
+        if(bounces++ == 0) D = L;
 
         // HANDLE_TEXTURES;
     }
-    return E;
+    aux->direct = spectral ? SpectrumToRgb(D, lambdas) : D.xyz;
+    return spectral ? SpectrumToRgb(E + L, lambdas) : (E + L).xyz;
 }
 
 #define SETUP_SCENE\
@@ -949,49 +1451,86 @@ float3 PathTrace(struct Ray ray, struct Scene *scene, uint* seed){
     scene.sky_intensity = as_float(sc_params[2 * SC_SCENE + 4]);\
     scene.sky_min = as_float(sc_params[2 * SC_SCENE + 5]);\
     scene.sky_pow = as_float(sc_params[2 * SC_SCENE + 6]);\
+    scene.proc_sky = sc_params[SC_SKY] != 0;\
+    scene.time = 0.0f;\
+    scene.spectral = sc_params[2 * SC_SCENE + 21] != 0;\
+
+// distance of the screen to the camera at which the screen spans the field of view
+float UvDist(__global uint *sc_params, uint w, uint h){
+    float fov = as_float(sc_params[2 * SC_SCENE + 29]);
+    return 0.5f * w / h / tan(radians(fov) * 0.5f);
+}
 
-#define CREATE_RAY(uv)\
+//without a sampler the camera is a pinhole, with one the origin is sampled on the thin lens
+#define CREATE_RAY(uv, smp)\
     struct Ray ray;\
     ray.pos = ExtractFloat3FromInts(sc_params, 2 * SC_SCENE + 7);\
     float3 cd = fast_normalize(ExtractFloat3FromInts(sc_params, 2 * SC_SCENE + 10));\
+    ray.pos += scene.time * ExtractFloat3FromInts(sc_params, 2 * SC_SCENE + 17);\
+    cd = Yawed(cd, -scene.time * as_float(sc_params[2 * SC_SCENE + 20]));\
     float3 hor = fast_normalize(cross(cd, (float3)(0.0f, 1.0f, 0.0f)));\
     float3 ver = fast_normalize(cross(hor, cd));\
-    float3 to = ray.pos + cd;\
+    float3 to = ray.pos + cd * UvDist(sc_params, w, h);\
     to += uv.x * hor;\
     to += uv.y * ver;\
     ray.dir = fast_normalize(to - ray.pos);\
+    float aperture = as_float(sc_params[2 * SC_SCENE + 13]);\
+    if((smp) != 0 && aperture > 0.0f){\
+        float focal_dist = as_float(sc_params[2 * SC_SCENE + 14]);\
+        float3 focus = ray.pos + ray.dir * (focal_dist / dot(ray.dir, cd));\
+        float2 lens = aperture * LensPoint(smp, sc_params[2 * SC_SCENE + 15]);\
+        ray.pos += lens.x * hor + lens.y * ver;\
+        ray.dir = fast_normalize(focus - ray.pos);\
+    }\
 
-float3 RayTracing(const uint w, const uint h, const uint x, const uint y,
+float3 RayTracing(const uint w, const uint h, const uint x, const uint y, const uint sample,
     __global uint *sc_params, __global float *sc_items, __global uint *tx_params,
     __global uchar *tx_items, __global uint *bvh
 ){
     SETUP_SCENE;
+    // only the lights are sampled, the camera stays a pinhole
+    struct Sampler smp;
+    smp.mode = SAMPLER_RANDOM;
+    smp.seed = WangHash((x + y * w) * (sample + 1));
+    smp.index = sample;
+    smp.dim = 0;
+    smp.x = x;
+    smp.y = y;
+    smp.blue_noise = 0;
     float2 uv = (float2)((float)x / w, (float)y / h);
     uv -= 0.5f;
     uv *= (float2)((float)w / h, -1.0f);
-    CREATE_RAY(uv);
+    CREATE_RAY(uv, 0);
 
-    float3 col = RayTrace(&ray, &scene, MAX_RENDER_DEPTH);
-    col = pow(col, (float3)(1.0f / GAMMA));
-    col = clamp(col, 0.0f, 1.0f);
-    return col;
+    return RayTrace(&ray, &scene, &smp, MAX_RENDER_DEPTH);
 }
 
-float3 PathTracing(const uint w, const uint h, const uint x, const uint y, const uint rseed,
+float3 PathTracing(const uint w, const uint h, const uint x, const uint y, const uint sample,
     __global uint *sc_params, __global float *sc_items, __global uint *tx_params,
-    __global uchar *tx_items, __global uint *bvh
+    __global uchar *tx_items, __global uint *bvh, __global float *blue_noise, struct Aux *aux
 ){
     SETUP_SCENE;
 
-    uint hash = WangHash(rseed);
-    float u = U32tf01(Xor32(&hash));
-    float v = U32tf01(Xor32(&hash));
+    struct Sampler smp;
+    smp.mode = sc_params[2 * SC_SCENE + 28];
+    smp.index = sample;
+    smp.dim = 0;
+    smp.x = x;
+    smp.y = y;
+    smp.blue_noise = blue_noise;
+    if(smp.mode == SAMPLER_RANDOM)
+        smp.seed = WangHash((x + y * w) * (sample + 1));
+    else
+        smp.seed = smp.mode == SAMPLER_SOBOL ? WangHash(x ^ WangHash(y)) : 0;
+    float u = Rand(&smp);
+    float v = Rand(&smp);
+    scene.time = as_float(sc_params[2 * SC_SCENE + 16]) * Rand(&smp);
     float2 uv = (float2)(((float)x + u) / w, ((float)y + v) / h);
     uv -= 0.5f;
     uv *= (float2)((float)w / h, -1.0f);
-    CREATE_RAY(uv);
+    CREATE_RAY(uv, &smp);
 
-    float3 col = PathTrace(ray, &scene, &hash);
+    float3 col = PathTrace(ray, &scene, &smp, aux);
     // col = pow(col, (float3)(1.0f / GAMMA));
     return col;
 }
@@ -1023,10 +1562,87 @@ float3 HableTonemap(float3 x){
     return clamp(curr * white_scale, 0.0f, 1.0f);
 }
 
+// extended Reinhard, values at the white point map to one
+float3 ReinhardTonemap(float3 x, float white_point){
+    return x * (1.0f + x / (white_point * white_point)) / (1.0f + x);
+}
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms
+float3 AcesFullTonemap(float3 x){
+    float3 v = (float3)(
+        dot(x, (float3)(0.59719f, 0.35458f, 0.04823f)),
+        dot(x, (float3)(0.07600f, 0.90834f, 0.01566f)),
+        dot(x, (float3)(0.02840f, 0.13383f, 0.83777f))
+    );
+    v = (v * (v + 0.0245786f) - 0.000090537f) / (v * (0.983729f * v + 0.432951f) + 0.238081f);
+    v = (float3)(
+        dot(v, (float3)(1.60475f, -0.53108f, -0.07367f)),
+        dot(v, (float3)(-0.10208f, 1.10813f, -0.00605f)),
+        dot(v, (float3)(-0.00327f, -0.07276f, 1.07602f))
+    );
+    return clamp(v, 0.0f, 1.0f);
+}
+
+// Troy Sobotka's AgX with the polynomial contrast curve of Benjamin Wrensch
+float3 AgxTonemap(float3 x){
+    const float min_ev = -12.47393f;
+    const float max_ev = 4.026069f;
+    float3 v = (float3)(
+        dot(x, (float3)(0.842479f, 0.078434f, 0.079224f)),
+        dot(x, (float3)(0.042328f, 0.878469f, 0.079166f)),
+        dot(x, (float3)(0.042376f, 0.078434f, 0.879143f))
+    );
+    v = (clamp(log2(max(v, (float3)(1e-10f))), min_ev, max_ev) - min_ev) / (max_ev - min_ev);
+    float3 v2 = v * v;
+    float3 v4 = v2 * v2;
+    v = 15.5f * v4 * v2 - 40.14f * v4 * v + 31.96f * v4 - 6.868f * v2 * v + 0.4298f * v2 + 0.1191f * v - 0.00232f;
+    v = (float3)(
+        dot(v, (float3)(1.196879f, -0.098021f, -0.099030f)),
+        dot(v, (float3)(-0.052897f, 1.151903f, -0.098961f)),
+        dot(v, (float3)(-0.052972f, -0.098043f, 1.151074f))
+    );
+    // the curve ends in display space, gamma is applied after tone mapping
+    return pow(clamp(v, 0.0f, 1.0f), (float3)(GAMMA));
+}
+
+// Hajime Uchimura's Gran Turismo curve: toe, linear section and shoulder
+float3 UchimuraTonemap(float3 x){
+    const float p = 1.0f, a = 1.0f, m = 0.22f, l = 0.4f, c = 1.33f, b = 0.0f;
+    float l0 = (p - m) * l / a;
+    float s0 = m + l0;
+    float s1 = m + a * l0;
+    float cp = -(a * p / (p - s1)) / p;
+    float3 w0 = 1.0f - smoothstep(0.0f, m, x);
+    float3 w2 = step(s0, x);
+    float3 w1 = 1.0f - w0 - w2;
+    float3 toe = m * pow(max(x / m, 0.0f), (float3)(c)) + b;
+    float3 shoulder = p - (p - s1) * exp(cp * (x - s0));
+    float3 linear = m + a * (x - m);
+    return toe * w0 + linear * w1 + shoulder * w2;
+}
+
+float3 ToneMap(float3 c, uint tonemap, float white_point){
+    switch(tonemap){
+        case 1: return AcesTonemap(c);
+        case 2: return HableTonemap(c);
+        case 3: return ReinhardTonemap(c, white_point);
+        case 4: return AgxTonemap(c);
+        case 5: return AcesFullTonemap(c);
+        case 6: return UchimuraTonemap(c);
+        default: return c;
+    }
+}
+
+float3 FromMap(__global float *map, uint i){
+    return (float3)(map[i], map[i + 1], map[i + 2]);
+}
+
+// linear colour, the post stack of image_from_floatmap makes the image
 __kernel void raytracing(
-    __global uint *intmap,
+    __global float *floatmap,
     const uint w,
     const uint h,
+    const uint sample,
     __global uint *sc_params,
     __global float *sc_items,
     __global uint *bvh,
@@ -1036,11 +1652,31 @@ __kernel void raytracing(
     uint x = get_global_id(0);
     uint y = get_global_id(1);
     uint pixid = x + y * w;
-    float3 col = RayTracing(w, h, x, y,
+    float3 col = RayTracing(w, h, x, y, sample,
         sc_params, sc_items, tx_params, tx_items, bvh);
-    col *= 255;
-    uint res = ((uint)col.x << 16) + ((uint)col.y << 8) + (uint)col.z;
-    intmap[pixid] = res;
+    // the first sample starts the accumulation over
+    if(sample > 0)
+        col += FromMap(floatmap, pixid * 3);
+    floatmap[pixid * 3 + 0] = col.x;
+    floatmap[pixid * 3 + 1] = col.y;
+    floatmap[pixid * 3 + 2] = col.z;
+}
+
+float Luminance(float3 c){
+    return dot(c, (float3)(0.2126f, 0.7152f, 0.0722f));
+}
+
+// relative standard error of the mean of a pixel, the sums are accumulated over samples
+float PixelError(float3 sum, float sum_sq, float samples, float traced){
+    float mean = Luminance(sum) / samples;
+    float var = max(sum_sq / samples - mean * mean, 0.0f);
+    return sqrt(var / max(traced, 1.0f)) / (mean + ADAPTIVE_EPSILON);
+}
+
+bool Converged(__global float *floatmap, __global float *statmap, uint pixid, uint samples, float threshold){
+    float traced = statmap[pixid * STAT_SIZE + 1];
+    return traced >= ADAPTIVE_MIN_SAMPLES &&
+        PixelError(FromMap(floatmap, pixid * 3), statmap[pixid * STAT_SIZE], (float)samples, traced) < threshold;
 }
 
 __kernel void pathtracing(
@@ -1052,21 +1688,60 @@ __kernel void pathtracing(
     __global float *sc_items,
     __global uint *bvh,
     __global uint *tx_params,
-    __global uchar *tx_items
+    __global uchar *tx_items,
+    __global float *auxmap,
+    __global float *statmap,
+    const float threshold,
+    __global float *blue_noise,
+    __global uint *idmap
 ){
     uint x = get_global_id(0);
     uint y = get_global_id(1);
     uint pixid = x + y * w;
-    float3 col = PathTracing(w, h, x, y, pixid * (t + 1),
-        sc_params, sc_items, tx_params, tx_items, bvh);
     uint fid = pixid * 3;
+    uint aid = pixid * AUX_SIZE;
+    uint sid = pixid * STAT_SIZE;
+    // converged pixels repeat their mean, so the whole map keeps dividing by the samples taken
+    if(threshold > 0.0f && t > 0 && Converged(floatmap, statmap, pixid, t, threshold)){
+        float grow = 1.0f + 1.0f / t;
+        for(uint i = 0; i < 3; i++)
+            floatmap[fid + i] *= grow;
+        for(uint i = 0; i < AUX_SIZE; i++)
+            auxmap[aid + i] *= grow;
+        statmap[sid] *= grow;
+        return;
+    }
+    struct Aux aux;
+    float3 col = PathTracing(w, h, x, y, t,
+        sc_params, sc_items, tx_params, tx_items, bvh, blue_noise, &aux);
     floatmap[fid + 0] += col.x;
     floatmap[fid + 1] += col.y;
     floatmap[fid + 2] += col.z;
+    auxmap[aid + 0] += aux.albedo.x;
+    auxmap[aid + 1] += aux.albedo.y;
+    auxmap[aid + 2] += aux.albedo.z;
+    auxmap[aid + 3] += aux.nor.x;
+    auxmap[aid + 4] += aux.nor.y;
+    auxmap[aid + 5] += aux.nor.z;
+    auxmap[aid + 6] += aux.depth;
+    auxmap[aid + 7] += aux.direct.x;
+    auxmap[aid + 8] += aux.direct.y;
+    auxmap[aid + 9] += aux.direct.z;
+    // ids can not be averaged, the first sample after a clear decides
+    if(statmap[sid + 1] == 0.0f){
+        idmap[pixid * ID_SIZE + 0] = aux.mat;
+        idmap[pixid * ID_SIZE + 1] = aux.object;
+        idmap[pixid * ID_SIZE + 2] = aux.prim;
+    }
+    float lum = Luminance(col);
+    statmap[sid + 0] += lum * lum;
+    statmap[sid + 1] += 1.0f;
 }
 
 __kernel void clear(
     __global float *floatmap,
+    __global float *auxmap,
+    __global float *statmap,
     const uint w
  ){
     uint x = get_global_id(0);
@@ -1075,31 +1750,314 @@ __kernel void clear(
     floatmap[pixid + 0] = 0.0f;
     floatmap[pixid + 1] = 0.0f;
     floatmap[pixid + 2] = 0.0f;
+    uint aid = (x + y * w) * AUX_SIZE;
+    for(uint i = 0; i < AUX_SIZE; i++)
+        auxmap[aid + i] = 0.0f;
+    uint sid = (x + y * w) * STAT_SIZE;
+    for(uint i = 0; i < STAT_SIZE; i++)
+        statmap[sid + i] = 0.0f;
  }
 
+// debug view of adaptive sampling: converged pixels are tinted green, sampled pixels red
+__kernel void convergence_mask(
+    __global float *floatmap,
+    __global float *statmap,
+    __global uint *imagemap,
+    const uint w,
+    const uint samples,
+    const float threshold
+){
+    uint x = get_global_id(0);
+    uint y = get_global_id(1);
+    uint pixid = x + y * w;
+    uint tint = Converged(floatmap, statmap, pixid, samples, threshold) ? 0x008000 : 0x800000;
+    imagemap[pixid] = ((imagemap[pixid] >> 1) & 0x7f7f7f) + tint;
+}
+
+// shows an aov instead of the image, the numbering is that of the rust Aov enum
+__kernel void aov_view(
+    __global float *floatmap,
+    __global float *auxmap,
+    __global uint *idmap,
+    __global uint *imagemap,
+    const uint w,
+    const float mult,
+    const uint aov
+){
+    uint x = get_global_id(0);
+    uint y = get_global_id(1);
+    uint pixid = x + y * w;
+    uint aid = pixid * AUX_SIZE;
+    float3 c;
+    if(aov == 1){ // albedo
+        c = FromMap(auxmap, aid) * mult;
+    } else if(aov == 2){ // normal
+        c = FromMap(auxmap, aid + 3) * mult * 0.5f + 0.5f;
+    } else if(aov == 3){ // depth
+        c = (float3)(exp(-auxmap[aid + 6] * mult / AOV_DEPTH_SCALE));
+    } else if(aov >= 4 && aov <= 6){ // material, object and primitive ids
+        uint id = idmap[pixid * ID_SIZE + aov - 4];
+        uint hash = WangHash(id);
+        c = id == UINT_MAX ? (float3)(0.0f) :
+            (float3)((float)(hash & 0xff), (float)((hash >> 8) & 0xff), (float)((hash >> 16) & 0xff)) / 255.0f;
+    } else if(aov == 7){ // direct
+        c = pow(clamp(FromMap(auxmap, aid + 7) * mult, 0.0f, 1.0f), (float3)(1.0f / GAMMA));
+    } else { // indirect
+        float3 indirect = (FromMap(floatmap, pixid * 3) - FromMap(auxmap, aid + 7)) * mult;
+        c = pow(clamp(indirect, 0.0f, 1.0f), (float3)(1.0f / GAMMA));
+    }
+    c = clamp(c, 0.0f, 1.0f) * 255.0f;
+    imagemap[pixid] = ((uint)((uchar)c.r) << 16) + ((uint)((uchar)c.g) << 8) + (uint)((uchar)c.b);
+}
+
+// camera basis as CREATE_RAY builds it
+void CameraBasis(__global uint *sc_params, float3 *pos, float3 *cd, float3 *hor, float3 *ver){
+    *pos = ExtractFloat3FromInts(sc_params, 2 * SC_SCENE + 7);
+    *cd = fast_normalize(ExtractFloat3FromInts(sc_params, 2 * SC_SCENE + 10));
+    *hor = fast_normalize(cross(*cd, (float3)(0.0f, 1.0f, 0.0f)));
+    *ver = fast_normalize(cross(*hor, *cd));
+}
+
+// carries the accumulation of the previous view over into the new one. The frame holds the first
+// sample of the new view and is scaled up to count as samples, all but one of them come from the
+// history if the previous camera saw the same surface there
+__kernel void reproject(
+    __global float *floatmap,
+    __global float *auxmap,
+    __global float *statmap,
+    __global float *history,
+    __global float *history_aux,
+    __global float *history_stat,
+    __global uint *sc_params,
+    __global uint *prev_params,
+    const uint w,
+    const uint h,
+    const float hist_mult, // one over the samples in the history
+    const uint samples
+){
+    uint x = get_global_id(0);
+    uint y = get_global_id(1);
+    uint p = x + y * w;
+    float3 nor = FromMap(auxmap, p * AUX_SIZE + 3);
+    float depth = auxmap[p * AUX_SIZE + 6];
+    bool sky = depth >= MAX_RENDER_DIST;
+    // the point seen through the center of the pixel
+    float2 uv = (float2)(((float)x + 0.5f) / w, ((float)y + 0.5f) / h);
+    uv -= 0.5f;
+    uv *= (float2)((float)w / h, -1.0f);
+    float3 pos, cd, hor, ver;
+    CameraBasis(sc_params, &pos, &cd, &hor, &ver);
+    float3 dir = fast_normalize(cd * UvDist(sc_params, w, h) + uv.x * hor + uv.y * ver);
+    // where the previous camera saw it, the sky only depends on the direction
+    CameraBasis(prev_params, &pos, &cd, &hor, &ver);
+    float3 to = sky ? dir : ExtractFloat3FromInts(sc_params, 2 * SC_SCENE + 7) + dir * depth - pos;
+    float z = dot(to, cd);
+    bool valid = z > EPSILON;
+    uint q = 0;
+    if(valid){
+        float2 puv = (float2)(dot(to, hor), dot(to, ver)) / z * UvDist(prev_params, w, h);
+        int qx = (int)floor((puv.x * h / w + 0.5f) * w);
+        int qy = (int)floor((0.5f - puv.y) * h);
+        valid = qx >= 0 && qy >= 0 && qx < (int)w && qy < (int)h;
+        q = qx + qy * w;
+    }
+    // reject the history where another surface was visible
+    if(valid){
+        float hdepth = history_aux[q * AUX_SIZE + 6] * hist_mult;
+        if(sky){
+            valid = hdepth >= MAX_RENDER_DIST * 0.5f;
+        } else {
+            float3 hnor = FromMap(history_aux, q * AUX_SIZE + 3) * hist_mult;
+            valid = fabs(hdepth - fast_length(to)) < REPROJECT_DEPTH_TOLERANCE * hdepth
+                && dot(nor, hnor) > REPROJECT_NORMAL_TOLERANCE;
+        }
+    }
+    float3 col = FromMap(floatmap, p * 3);
+    if(valid){
+        col += FromMap(history, q * 3) * hist_mult * (float)(samples - 1);
+        statmap[p * STAT_SIZE] += history_stat[q * STAT_SIZE] * hist_mult * (float)(samples - 1);
+        statmap[p * STAT_SIZE + 1] += min(history_stat[q * STAT_SIZE + 1], (float)(samples - 1));
+    } else {
+        col *= (float)samples;
+        statmap[p * STAT_SIZE] *= (float)samples;
+    }
+    floatmap[p * 3 + 0] = col.x;
+    floatmap[p * 3 + 1] = col.y;
+    floatmap[p * 3 + 2] = col.z;
+    for(uint i = 0; i < AUX_SIZE; i++)
+        auxmap[p * AUX_SIZE + i] *= (float)samples;
+}
+
+// one pass of the edge-avoiding a-trous wavelet filter (Dammertz et al. 2010), guided by
+// the first hit albedo, normal and depth, the albedo is divided out so textures stay sharp
+__kernel void denoise(
+    __global float *input,
+    __global float *auxmap,
+    __global float *output,
+    const uint w,
+    const uint h,
+    const int step,
+    const float mult, // scales the input
+    const float aux_mult, // scales the guides
+    const float sigma // colour tolerance
+){
+    int x = get_global_id(0);
+    int y = get_global_id(1);
+    uint p = x + y * w;
+    const float kernel_w[3] = { 0.375f, 0.25f, 0.0625f };
+    float3 alb_p = max(FromMap(auxmap, p * AUX_SIZE) * aux_mult, (float3)(DENOISE_MIN_ALBEDO));
+    float3 nor_p = FromMap(auxmap, p * AUX_SIZE + 3) * aux_mult;
+    float z_p = auxmap[p * AUX_SIZE + 6] * aux_mult;
+    float3 col_p = FromMap(input, p * 3) * mult / alb_p;
+    float3 sum = (float3)(0.0f);
+    float wsum = 0.0f;
+    for(int dy = -2; dy <= 2; dy++){
+    for(int dx = -2; dx <= 2; dx++){
+        int qx = clamp(x + dx * step, 0, (int)w - 1);
+        int qy = clamp(y + dy * step, 0, (int)h - 1);
+        uint q = qx + qy * w;
+        float3 alb_q = max(FromMap(auxmap, q * AUX_SIZE) * aux_mult, (float3)(DENOISE_MIN_ALBEDO));
+        float3 col_q = FromMap(input, q * 3) * mult / alb_q;
+        float3 dc = col_p - col_q;
+        float wc = exp(-dot(dc, dc) / (sigma * sigma + FLT_EPSILON));
+        float wn = (dx == 0 && dy == 0) ? 1.0f :
+            pow(max(dot(nor_p, FromMap(auxmap, q * AUX_SIZE + 3) * aux_mult), 0.0f), DENOISE_SIGMA_NORMAL);
+        float z_q = auxmap[q * AUX_SIZE + 6] * aux_mult;
+        float wz = exp(-fabs(z_p - z_q) / (DENOISE_SIGMA_DEPTH * z_p * step + FLT_EPSILON));
+        float weight = kernel_w[abs(dx)] * kernel_w[abs(dy)] * wc * wn * wz;
+        sum += col_q * weight;
+        wsum += weight;
+    }
+    }
+    float3 res = sum / wsum * alb_p;
+    output[p * 3 + 0] = res.x;
+    output[p * 3 + 1] = res.y;
+    output[p * 3 + 2] = res.z;
+}
+
+// post processing, mirrors post.rs
+// post: gains(3), bloom threshold, bloom strength, bloom radius, ca shift, ca strength,
+// vignette, tone map, grain, lut size, lut domain min(3), lut domain max(3), white point
+#define PO_BLOOM_THRESHOLD 3
+#define PO_BLOOM_STRENGTH 4
+#define PO_BLOOM_RADIUS 5
+#define PO_CA_SHIFT 6
+#define PO_CA_STRENGTH 7
+#define PO_VIGNETTE 8
+#define PO_TONE_MAP 9
+#define PO_GRAIN 10
+#define PO_LUT_SIZE 11
+#define PO_LUT_MIN 12
+#define PO_LUT_MAX 15
+#define PO_WHITE_POINT 18
+
+float3 LutAt(__global float *lut, int n, int x, int y, int z){
+    return FromMap(lut, (x + y * n + z * n * n) * 3);
+}
+
+float3 LutSample(__global float *lut, __global float *post, float3 c){
+    int n = (int)post[PO_LUT_SIZE];
+    float3 dmin = FromMap(post, PO_LUT_MIN);
+    float3 dmax = FromMap(post, PO_LUT_MAX);
+    c = clamp((c - dmin) / (dmax - dmin), 0.0f, 1.0f) * (float)(n - 1);
+    int x0 = (int)c.x; int y0 = (int)c.y; int z0 = (int)c.z;
+    int x1 = min(x0 + 1, n - 1); int y1 = min(y0 + 1, n - 1); int z1 = min(z0 + 1, n - 1);
+    float3 f = c - (float3)((float)x0, (float)y0, (float)z0);
+    float3 c00 = mix(LutAt(lut, n, x0, y0, z0), LutAt(lut, n, x1, y0, z0), f.x);
+    float3 c10 = mix(LutAt(lut, n, x0, y1, z0), LutAt(lut, n, x1, y1, z0), f.x);
+    float3 c01 = mix(LutAt(lut, n, x0, y0, z1), LutAt(lut, n, x1, y0, z1), f.x);
+    float3 c11 = mix(LutAt(lut, n, x0, y1, z1), LutAt(lut, n, x1, y1, z1), f.x);
+    return mix(mix(c00, c10, f.y), mix(c01, c11, f.y), f.z);
+}
+
+// bright pass of the exposed frame
+__kernel void bloom_extract(
+    __global float *floatmap,
+    __global float *bloommap,
+    const uint w,
+    const float mult,
+    __global float *post
+){
+    uint x = get_global_id(0);
+    uint y = get_global_id(1);
+    uint p = (x + y * w) * 3;
+    float3 c = FromMap(floatmap, p) * mult * FromMap(post, 0);
+    float l = Luminance(c);
+    c *= max(l - post[PO_BLOOM_THRESHOLD], 0.0f) / max(l, 0.001f);
+    bloommap[p + 0] = c.x;
+    bloommap[p + 1] = c.y;
+    bloommap[p + 2] = c.z;
+}
+
+// one direction of the separable gaussian, sigma at half the radius
+__kernel void bloom_blur(
+    __global float *input,
+    __global float *output,
+    const uint w,
+    const uint h,
+    __global float *post,
+    const int dx,
+    const int dy
+){
+    int x = get_global_id(0);
+    int y = get_global_id(1);
+    int r = (int)post[PO_BLOOM_RADIUS];
+    float sigma = max(r * 0.5f, 0.5f);
+    float3 sum = (float3)(0.0f);
+    float wsum = 0.0f;
+    for(int i = -r; i <= r; i++){
+        int qx = clamp(x + i * dx, 0, (int)w - 1);
+        int qy = clamp(y + i * dy, 0, (int)h - 1);
+        float weight = exp(-(float)(i * i) / (2.0f * sigma * sigma));
+        sum += FromMap(input, (qx + qy * w) * 3) * weight;
+        wsum += weight;
+    }
+    sum /= wsum;
+    uint p = (x + y * w) * 3;
+    output[p + 0] = sum.x;
+    output[p + 1] = sum.y;
+    output[p + 2] = sum.z;
+}
+
 __kernel void image_from_floatmap(
     __global float *floatmap,
     __global uint *imagemap,
     const uint w,
     const float mult,
-    const uint tonemap
+    const uint h,
+    __global float *post,
+    __global float *bloommap,
+    __global float *lut,
+    const uint frame
 ){
     uint x = get_global_id(0);
     uint y = get_global_id(1);
     uint pixid = (x + y * w);
-    uint pix_float = pixid * 3;
-    float3 c = (float3)(
-                floatmap[pix_float + 0] * mult,
-                floatmap[pix_float + 1] * mult,
-                floatmap[pix_float + 2] * mult
-    );
-    if(tonemap == 1){
-        c = AcesTonemap(c);
-    } else if(tonemap == 2){
-        c = HableTonemap(c);
+    float3 c = FromMap(floatmap, pixid * 3) * mult;
+    uint ash = (uint)post[PO_CA_SHIFT];
+    float ast = post[PO_CA_STRENGTH];
+    float2 uv = (float2)((float)x / w, (float)y / h);
+    if(ash > 0 && ast > 0.001f){
+        float r = FromMap(floatmap, ((max(x, ash) - ash) + (max(y, ash) - ash) * w) * 3).x * mult;
+        float b = FromMap(floatmap, (min(x + ash, w - 1) + min(y + ash, h - 1) * w) * 3).z * mult;
+        float abr_str = clamp(pow(uv.x * uv.y * 8.0f, ast), 0.0f, 1.0f);
+        c = mix(c, (float3)(r, c.y, b), 1.0f - abr_str);
+    }
+    c *= FromMap(post, 0);
+    if(post[PO_BLOOM_STRENGTH] > 0.0f){
+        c += FromMap(bloommap, pixid * 3) * post[PO_BLOOM_STRENGTH];
     }
+    uv *= 1.0f - uv;
+    c *= clamp(pow(uv.x * uv.y * 32.0f, post[PO_VIGNETTE]), 0.0f, 1.0f);
+    c = ToneMap(c, (uint)post[PO_TONE_MAP], post[PO_WHITE_POINT]);
     c = clamp(c, 0.0f, 1.0f);
     c = pow(c, (float3)(1.0f / GAMMA));
+    if(post[PO_LUT_SIZE] > 0.0f){
+        c = LutSample(lut, post, c);
+    }
+    float grain = U32tf01(WangHash(x ^ WangHash(y ^ WangHash(frame))));
+    c += (grain - 0.5f) * post[PO_GRAIN];
+    c = clamp(c, 0.0f, 1.0f);
     c *= 255.0f;
     imagemap[pixid] = ((uint)((uchar)c.r) << 16) + ((uint)((uchar)c.g) << 8) + (uint)((uchar)c.b);
 }
@@ -0,0 +1,64 @@
+use crate::vec3::Vec3;
+
+// Per pixel statistics for adaptive sampling, mirrored by the kernel.
+// Converged pixels stop tracing and repeat their mean instead, so every pixel
+// of the float map keeps being divided by the same amount of samples.
+
+// floats per pixel in the statistics buffer: sum of squared luminance, samples actually traced
+pub const STAT_SIZE: usize = 2;
+// pixels keep tracing until they have this many samples of their own
+pub const ADAPTIVE_MIN_SAMPLES: f32 = 16.0;
+// keeps the relative error of dark pixels finite
+pub const ADAPTIVE_EPSILON: f32 = 0.01;
+// measuring the noise level downloads the frame, so it is only done every this many samples
+pub const NOISE_CHECK_INTERVAL: usize = 16;
+
+#[inline]
+pub fn luminance(c: Vec3) -> f32{
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// relative standard error of the mean of a pixel, sum and sum_sq are accumulated over samples
+pub fn pixel_error(sum: Vec3, sum_sq: f32, samples: f32, traced: f32) -> f32{
+    let mean = luminance(sum) / samples;
+    let var = (sum_sq / samples - mean * mean).max(0.0);
+    (var / traced.max(1.0)).sqrt() / (mean + ADAPTIVE_EPSILON)
+}
+
+pub fn is_converged(sum: Vec3, sum_sq: f32, samples: f32, traced: f32, threshold: f32) -> bool{
+    traced >= ADAPTIVE_MIN_SAMPLES && pixel_error(sum, sum_sq, samples, traced) < threshold
+}
+
+// mean error over the frame, the noise level a render can be stopped at
+pub fn noise_level(floatmap: &[f32], statmap: &[f32], samples: usize) -> f32{
+    let pixels = statmap.len() / STAT_SIZE;
+    if samples == 0 || pixels == 0 { return f32::MAX; }
+    let mut total = 0.0;
+    for i in 0..pixels{
+        let sum = Vec3::new(floatmap[i * 3], floatmap[i * 3 + 1], floatmap[i * 3 + 2]);
+        total += pixel_error(sum, statmap[i * STAT_SIZE], samples as f32, statmap[i * STAT_SIZE + 1]);
+    }
+    total / pixels as f32
+}
+
+#[cfg(test)]
+mod test{
+    use crate::adaptive::*;
+
+    #[test]
+    fn constant_pixel_has_no_error(){
+        let n = 32.0;
+        let sum = Vec3::uni(0.5 * n);
+        assert!(pixel_error(sum, 0.25 * n, n, n) < 0.001);
+        assert!(is_converged(sum, 0.25 * n, n, n, 0.01));
+        assert!(!is_converged(sum, 0.25 * 4.0, 4.0, 4.0, 0.01));
+    }
+
+    #[test]
+    fn error_shrinks_with_samples(){
+        // half the samples are zero and half are one
+        let err = |n: f32| pixel_error(Vec3::uni(n * 0.5), n * 0.5, n, n);
+        assert!(err(64.0) < err(16.0));
+        assert!((err(16.0) / err(64.0) - 2.0).abs() < 0.1);
+    }
+}
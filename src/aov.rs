@@ -0,0 +1,102 @@
+use crate::vec3::Vec3;
+use crate::consts::GAMMA;
+
+// Arbitrary output variables: the first hit and lighting split of a pixel, mirrored by the kernel.
+// Albedo, normal, depth and direct light are accumulated in the guide buffer of the denoiser,
+// the ids of the first sample are kept in a buffer of their own. Planes are not in the bvh
+// and the cpu does not trace them, so they only have a material id.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Aov{ Beauty = 0, Albedo = 1, Normal = 2, Depth = 3, Material = 4, Object = 5, Primitive = 6, Direct = 7, Indirect = 8 }
+
+pub const AOVS: [Aov; 9] = [
+    Aov::Beauty, Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Material,
+    Aov::Object, Aov::Primitive, Aov::Direct, Aov::Indirect,
+];
+
+// uints per pixel in the id buffer: material, object, primitive within the object
+pub const ID_SIZE: usize = 3;
+// nothing was hit
+pub const NO_ID: u32 = u32::MAX;
+// distance at which the depth view has faded to a third
+pub const AOV_DEPTH_SCALE: f32 = 10.0;
+
+impl Aov{
+    pub fn parse(name: &str) -> Option<Self>{
+        AOVS.iter().copied().find(|aov| aov.name() == name.to_lowercase())
+    }
+
+    pub fn name(self) -> &'static str{
+        match self{
+            Aov::Beauty => "beauty",
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Material => "material",
+            Aov::Object => "object",
+            Aov::Primitive => "primitive",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+        }
+    }
+
+    // the view after this one
+    pub fn cycled(self) -> Self{
+        AOVS[(self as usize + 1) % AOVS.len()]
+    }
+}
+
+// raw value of a pixel. col is the mean colour, guide the means of the guide buffer and ids the ids.
+// Ids are returned as floats, minus one if nothing was hit.
+pub fn aov_value(aov: Aov, col: Vec3, guide: &[f32], ids: &[u32]) -> Vec3{
+    let at = |i: usize| Vec3::new(guide[i], guide[i + 1], guide[i + 2]);
+    let id = |id: u32| Vec3::uni(if id == NO_ID { -1.0 } else { id as f32 });
+    match aov{
+        Aov::Beauty => col,
+        Aov::Albedo => at(0),
+        Aov::Normal => at(3),
+        Aov::Depth => Vec3::uni(guide[6]),
+        Aov::Material => id(ids[0]),
+        Aov::Object => id(ids[1]),
+        Aov::Primitive => id(ids[2]),
+        Aov::Direct => at(7),
+        Aov::Indirect => col.subed(at(7)),
+    }
+}
+
+// colour of a raw value on screen
+pub fn aov_display(aov: Aov, v: Vec3) -> Vec3{
+    match aov{
+        Aov::Normal => v.scaled(0.5).added(Vec3::uni(0.5)).clamped(0.0, 1.0),
+        Aov::Depth => Vec3::uni((-v.x / AOV_DEPTH_SCALE).exp()),
+        Aov::Material | Aov::Object | Aov::Primitive =>
+            if v.x < 0.0 { Vec3::ZERO } else { id_colour(v.x as u32) },
+        _ => v.clamped(0.0, 1.0).powed_scalar(1.0 / GAMMA),
+    }
+}
+
+// a distinct colour per id
+pub fn id_colour(id: u32) -> Vec3{
+    let h = crate::sampler::wang_hash(id);
+    Vec3::new((h & 0xff) as f32, ((h >> 8) & 0xff) as f32, ((h >> 16) & 0xff) as f32).scaled(1.0 / 255.0)
+}
+
+#[cfg(test)]
+mod test{
+    use crate::aov::*;
+
+    #[test]
+    fn cycles_and_parses_all_views(){
+        let mut aov = Aov::Beauty;
+        for expected in AOVS.iter().skip(1){
+            aov = aov.cycled();
+            assert_eq!(aov, *expected);
+            assert_eq!(Aov::parse(aov.name()), Some(aov));
+        }
+        assert_eq!(aov.cycled(), Aov::Beauty);
+        let guide = [0.5, 0.5, 0.5, 0.0, 1.0, 0.0, 3.0, 0.25, 0.0, 0.0];
+        let ind = aov_value(Aov::Indirect, Vec3::new(1.0, 0.5, 0.5), &guide, &[1, NO_ID, 0]);
+        assert_eq!(ind, Vec3::new(0.75, 0.5, 0.5));
+        assert_eq!(aov_value(Aov::Object, Vec3::ZERO, &guide, &[1, NO_ID, 0]).x, -1.0);
+    }
+}
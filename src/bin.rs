@@ -3,12 +3,17 @@ extern crate clrays_rs;
 
 use clrays_rs as clr;
 use clr::window;
-use clr::trace_processor;
+use clr::trace_processor::{ self, TraceProcessor };
 use clr::scene::{ Scene, RenderType };
 use clr::info::{ Info };
-use clr::state::{ State, Settings, log_update_fn, fps_input_fn };
+use clr::state::{ State, Settings, RenderMode, std_update_fn, fps_input_fn };
 use clr::scenes::{ gi_scene::gi_scene, whitted_scene::whitted_scene };
-use clr::config::Config;
+use clr::config::{ Config, ConfigParsed, ExportParsed, AnimationParsed };
+use clr::sky::ProceduralSky;
+use clr::material::Medium;
+use clr::vec3::Vec3;
+use clr::camera_path::CameraPath;
+use clr::export::{ export_frame, needs_linear };
 
 use std::env;
 use std::path::Path;
@@ -42,18 +47,7 @@ pub fn main() -> Result<(), String>{
         return Ok(());
     };
 
-    let mut scene = Scene::new(&conf);
-    scene.stype = render_type;
-
-    match render_type{
-        RenderType::GI => gi_scene(&mut scene),
-        RenderType::Whitted => whitted_scene(&mut scene),
-    }
-
-    scene.gen_top_bvh();
-
-    info.set_time_point("Setting up scene");
-    scene.pack_textures(&mut info);
+    let mut scene = build_scene(&conf, render_type, &mut info);
 
     let settings = Settings{
         aa_samples: conf.cpu.aa_samples,
@@ -61,32 +55,45 @@ pub fn main() -> Result<(), String>{
         start_in_focus_mode: conf.cpu.start_in_focus_mode,
         max_render_depth: conf.cpu.render_depth,
         calc_frame_energy: conf.base.frame_energy,
-        render_type: render_type,
+        render_type,
     };
 
-    let mut state = State::new(&conf.controls, settings);
+    let mut state = State::new(&conf.controls, settings).with_camera_path(&conf.animation.path);
+
+    let mut window = window::Window::new("ClRays", conf.base.w, conf.base.h)
+        .with_export(conf.export.clone())
+        .with_render_scale(conf.base.render_scale);
+    if conf.base.hot_reload{
+        window = window.with_hot_reload(&args[1], load_scene);
+    }
 
-    let mut window = window::Window::new("ClRays", conf.base.w, conf.base.h);
+    // sequences are rendered at the full size, the window at the render scale
+    let size = if conf.animation.frames > 0 { (conf.base.w, conf.base.h) } else { conf.base.render_size() };
 
     macro_rules! run{
         ($tracer:ident) => {
             info.stop_time();
             info.print_info();
-            return window.run(fps_input_fn, log_update_fn, &mut state, &mut $tracer, &mut scene);
+            if conf.animation.frames > 0{
+                return render_sequence(&mut $tracer, &mut scene, &mut state, &conf.animation, &conf.export, size);
+            }
+            window = window.with_info(&info);
+            return window.run(fps_input_fn, std_update_fn, &mut state, &mut $tracer, &mut scene);
         }
     }
 
     match (conf.base.gpu, render_type){
         (true, RenderType::GI) => {
-            let mut tracer_gpu = unpackdb!(trace_processor::GpuPath::new((conf.base.w, conf.base.h), &mut scene, &conf, &mut info), "Could not create GpuPath!");
+            let mut tracer_gpu = unpackdb!(trace_processor::GpuPath::new(size, &mut scene, &conf, &mut info), "Could not create GpuPath!");
             run!(tracer_gpu);
         },
         (true, RenderType::Whitted) => {
-            let mut tracer_gpu = unpackdb!(trace_processor::GpuWhitted::new((conf.base.w, conf.base.h), &mut scene, &mut info), "Could not create GpuPath!");
+            let mut tracer_gpu = unpackdb!(trace_processor::GpuWhitted::new(size, &mut scene, &mut info), "Could not create GpuPath!");
             run!(tracer_gpu);
         },
         (false, RenderType::Whitted) => {
-            let mut tracer_cpu = trace_processor::CpuWhitted::new(conf.base.w as usize, conf.base.h as usize, 32, &mut scene, &mut info);
+            let mut tracer_cpu = trace_processor::CpuWhitted::new(size.0 as usize, size.1 as usize, 32, &mut scene, &mut info)
+                .with_denoiser(conf.post.denoise_passes, conf.post.denoise_sigma);
             run!(tracer_cpu);
         },
         (on_gpu, rtype) => {
@@ -94,3 +101,84 @@ pub fn main() -> Result<(), String>{
         },
     }
 }
+
+fn build_scene(conf: &ConfigParsed, render_type: RenderType, info: &mut Info) -> Scene{
+    let mut scene = Scene::new(conf);
+    scene.stype = render_type;
+
+    match render_type{
+        RenderType::GI => gi_scene(&mut scene),
+        RenderType::Whitted => whitted_scene(&mut scene),
+    }
+
+    if let Some(sky) = &conf.sky{
+        scene.set_procedural_sky(ProceduralSky::new(sky.time_of_day, sky.turbidity, Vec3::uni(sky.ground_albedo))
+            .with_sun_tilt(sky.sun_tilt));
+    }
+
+    if let Some(fog) = &conf.fog{
+        scene.set_fog(Medium::new(fog.density, fog.albedo, fog.g), fog.distance);
+    }
+
+    scene.gen_top_bvh();
+
+    info.set_time_point("Setting up scene");
+    scene.pack_textures(info);
+    scene
+}
+
+// the scene of a config file, for reloading it
+fn load_scene(path: &str) -> Result<Scene, String>{
+    let conf = Config::read(Path::new(path)).map_err(|e| format!("Could not read config: {}", e))?;
+    let conf = conf.parse()?;
+    let render_type = conf.base.render_type.ok_or("The config does not render a scene!")?;
+    Ok(build_scene(&conf, render_type, &mut Info::new()))
+}
+
+// renders the camera path to a numbered image sequence without opening a window
+fn render_sequence(
+        tracer: &mut impl TraceProcessor,
+        scene: &mut Scene,
+        state: &mut State,
+        anim: &AnimationParsed,
+        export: &ExportParsed,
+        (w, h): (u32, u32),
+    ) -> Result<(), String>
+{
+    let path = CameraPath::read(Path::new(&anim.path))?;
+    if path.keys.is_empty(){
+        return Err(format!("Camera path {} has no keyframes!", anim.path));
+    }
+    state.window_size = (w, h);
+    state.aa = anim.samples;
+    for frame in 0..anim.frames{
+        let t = if anim.frames > 1 { frame as f32 / (anim.frames - 1) as f32 } else { 0.0 };
+        if let Some(key) = path.sample(t){
+            key.apply(&mut scene.cam);
+        }
+        state.render_mode = RenderMode::Full;
+        state.moved = true;
+        state.reprojectable = false;
+        state.samples_taken = 0;
+        tracer.update(scene, state);
+        let mut tex = Vec::new();
+        loop{
+            let frame_tex = tracer.render(scene, state);
+            if state.last_frame != RenderMode::None{
+                tex = frame_tex.to_vec();
+            }
+            state.render_mode = RenderMode::None;
+            state.moved = false;
+            if state.samples_taken >= anim.samples || state.last_frame == RenderMode::None{
+                break;
+            }
+        }
+        let linear = if needs_linear(export) { tracer.linear_frame(scene, state) } else { Vec::new() };
+        let aovs: Vec<_> = export.aovs.iter()
+            .filter_map(|aov| tracer.aov_frame(scene, state, *aov).map(|values| (*aov, values))).collect();
+        let mut settings = export.clone();
+        settings.path = anim.output.replace("{frame}", &format!("{:04}", frame));
+        export_frame(&settings, w, h, &tex, &linear, &aovs, state.samples_taken);
+    }
+    Ok(())
+}
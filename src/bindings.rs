@@ -0,0 +1,263 @@
+use serde::Deserialize;
+use sdl2::keyboard::{ Keycode, Mod };
+use sdl2::controller::Button;
+
+use std::collections::HashMap;
+
+// Key bindings: every action of the controls takes a key or a list of keys, each with optional modifiers,
+// like `export_frame = ["F", "Ctrl+E"]`. A press triggers the bindings of the key with the most of the held
+// modifiers, so `Ctrl+S` doesn't also trigger a plain `S`. Gamepad buttons bind the same actions by their sdl names.
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action{
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    LookUp,
+    LookDown,
+    LookLeft,
+    LookRight,
+    SunEarlier,
+    SunLater,
+    FocusMode,
+    Export,
+    ShowBvh,
+    Autofocus,
+    ConvergenceMask,
+    CycleAov,
+    ToggleAov,
+    Orbit,
+    RecordKeyframe,
+    Edit,
+    CycleMaterialProperty,
+    MaterialPropertyUp,
+    MaterialPropertyDown,
+    ToggleHud,
+    ResetCamera,
+    CycleToneMap,
+    SaveScreenshot,
+    SaveCamera,
+}
+
+// the names of the actions in the controls of the config
+pub const ACTIONS: [(Action, &str); 30] = [
+    (Action::MoveForward, "move_forward"),
+    (Action::MoveBackward, "move_backward"),
+    (Action::MoveLeft, "move_left"),
+    (Action::MoveRight, "move_right"),
+    (Action::MoveUp, "move_up"),
+    (Action::MoveDown, "move_down"),
+    (Action::LookUp, "look_up"),
+    (Action::LookDown, "look_down"),
+    (Action::LookLeft, "look_left"),
+    (Action::LookRight, "look_right"),
+    (Action::SunEarlier, "sun_earlier"),
+    (Action::SunLater, "sun_later"),
+    (Action::FocusMode, "toggle_focus_mode"),
+    (Action::Export, "export_frame"),
+    (Action::ShowBvh, "toggle_show_bvh"),
+    (Action::Autofocus, "autofocus"),
+    (Action::ConvergenceMask, "toggle_convergence_mask"),
+    (Action::CycleAov, "cycle_aov"),
+    (Action::ToggleAov, "toggle_aov"),
+    (Action::Orbit, "toggle_orbit"),
+    (Action::RecordKeyframe, "record_keyframe"),
+    (Action::Edit, "toggle_edit"),
+    (Action::CycleMaterialProperty, "cycle_material_property"),
+    (Action::MaterialPropertyUp, "material_property_up"),
+    (Action::MaterialPropertyDown, "material_property_down"),
+    (Action::ToggleHud, "toggle_hud"),
+    (Action::ResetCamera, "reset_camera"),
+    (Action::CycleToneMap, "cycle_tone_map"),
+    (Action::SaveScreenshot, "save_screenshot"),
+    (Action::SaveCamera, "save_camera"),
+];
+
+impl Action{
+    pub fn from_name(name: &str) -> Option<Self>{
+        ACTIONS.iter().find(|(_, n)| *n == name).map(|(a, _)| *a)
+    }
+
+    pub fn name(&self) -> &'static str{
+        ACTIONS.iter().find(|(a, _)| a == self).map(|(_, n)| *n).unwrap_or("")
+    }
+
+    // active while the key is down, the others happen once per press
+    pub fn is_held(&self) -> bool{
+        matches!(self,
+            Action::MoveForward | Action::MoveBackward | Action::MoveLeft | Action::MoveRight |
+            Action::MoveUp | Action::MoveDown | Action::LookUp | Action::LookDown |
+            Action::LookLeft | Action::LookRight | Action::SunEarlier | Action::SunLater)
+    }
+
+    // happens again while the key repeats
+    pub fn repeats(&self) -> bool{
+        matches!(self, Action::MaterialPropertyUp | Action::MaterialPropertyDown)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Modifiers{
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl Modifiers{
+    pub fn from_keymod(keymod: Mod) -> Self{
+        Self{
+            ctrl: keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD),
+            shift: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+            alt: keymod.intersects(Mod::LALTMOD | Mod::RALTMOD),
+        }
+    }
+
+    fn count(&self) -> usize{
+        self.ctrl as usize + self.shift as usize + self.alt as usize
+    }
+
+    // all of the other's modifiers are held
+    fn holds(&self, other: &Self) -> bool{
+        (self.ctrl || !other.ctrl) && (self.shift || !other.shift) && (self.alt || !other.alt)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Binding{
+    pub key: Keycode,
+    pub mods: Modifiers,
+}
+
+impl Binding{
+    // modifiers in front of the key name, joined by plusses: "Ctrl+Shift+S"
+    pub fn parse(name: &str) -> Result<Self, String>{
+        let mut mods = Modifiers::default();
+        let mut rest = name.trim();
+        loop{
+            let lower = rest.to_lowercase();
+            let (held, len) = if lower.starts_with("ctrl+"){
+                (&mut mods.ctrl, 5)
+            } else if lower.starts_with("shift+"){
+                (&mut mods.shift, 6)
+            } else if lower.starts_with("alt+"){
+                (&mut mods.alt, 4)
+            } else {
+                break;
+            };
+            *held = true;
+            rest = rest[len..].trim_start();
+        }
+        let key = Keycode::from_name(rest).ok_or_else(|| format!("Unknown key '{}' in binding '{}'!", rest, name))?;
+        Ok(Self{ key, mods })
+    }
+}
+
+// one key or a list of keys in the config
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum KeyNames{
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Bindings{
+    bindings: Vec<(Action, Binding)>,
+}
+
+impl Bindings{
+    // action names to key names, unknown names of either are an error
+    pub fn parse(names: &HashMap<String, KeyNames>) -> Result<Self, String>{
+        Ok(Self{ bindings: parse_names(names, Binding::parse)? })
+    }
+
+    pub fn with(mut self, action: Action, binding: Binding) -> Self{
+        self.bindings.push((action, binding));
+        self
+    }
+
+    // the actions of a press, only the bindings of the key with the most of the held modifiers
+    pub fn pressed(&self, key: Keycode, mods: Modifiers) -> Vec<Action>{
+        let matching: Vec<_> = self.bindings.iter()
+            .filter(|(_, b)| b.key == key && mods.holds(&b.mods)).collect();
+        let most = matching.iter().map(|(_, b)| b.mods.count()).max().unwrap_or(0);
+        matching.into_iter().filter(|(_, b)| b.mods.count() == most).map(|(a, _)| *a).collect()
+    }
+
+    // every action of the key whatever the modifiers, held actions stop when it is released
+    pub fn released(&self, key: Keycode) -> Vec<Action>{
+        self.bindings.iter().filter(|(_, b)| b.key == key).map(|(a, _)| *a).collect()
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ButtonBindings{
+    bindings: Vec<(Action, Button)>,
+}
+
+impl ButtonBindings{
+    // action names to gamepad button names like "a", "start" or "dpup"
+    pub fn parse(names: &HashMap<String, KeyNames>) -> Result<Self, String>{
+        let button = |name: &str| Button::from_string(name).ok_or_else(|| format!("Unknown gamepad button '{}'!", name));
+        Ok(Self{ bindings: parse_names(names, button)? })
+    }
+
+    // used without a gamepad table in the config
+    pub fn standard() -> Self{
+        Self::default()
+            .with(Action::FocusMode, Button::Y)
+            .with(Action::Export, Button::Start)
+            .with(Action::ShowBvh, Button::X)
+    }
+
+    pub fn with(mut self, action: Action, button: Button) -> Self{
+        self.bindings.push((action, button));
+        self
+    }
+
+    pub fn actions(&self, button: Button) -> Vec<Action>{
+        self.bindings.iter().filter(|(_, b)| *b == button).map(|(a, _)| *a).collect()
+    }
+}
+
+fn parse_names<T>(names: &HashMap<String, KeyNames>, parse: impl Fn(&str) -> Result<T, String>) -> Result<Vec<(Action, T)>, String>{
+    let mut res = Vec::new();
+    for (action_name, keys) in names{
+        let action = Action::from_name(action_name)
+            .ok_or_else(|| format!("Unknown control '{}'!", action_name))?;
+        let keys = match keys{
+            KeyNames::One(key) => std::slice::from_ref(key),
+            KeyNames::Many(keys) => keys.as_slice(),
+        };
+        for key in keys{
+            let binding = parse(key).map_err(|e| format!("Control '{}': {}", action_name, e))?;
+            res.push((action, binding));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test{
+    use crate::bindings::*;
+
+    #[test]
+    fn modifiers_pick_the_most_specific_binding(){
+        let ctrl = Modifiers{ ctrl: true, ..Modifiers::default() };
+        let bindings = Bindings::default()
+            .with(Action::MoveBackward, Binding{ key: Keycode::S, mods: Modifiers::default() })
+            .with(Action::SaveScreenshot, Binding{ key: Keycode::S, mods: ctrl })
+            .with(Action::Export, Binding{ key: Keycode::F, mods: Modifiers::default() });
+        assert_eq!(bindings.pressed(Keycode::S, Modifiers::default()), vec![Action::MoveBackward]);
+        assert_eq!(bindings.pressed(Keycode::S, ctrl), vec![Action::SaveScreenshot]);
+        let ctrl_shift = Modifiers{ shift: true, ..ctrl };
+        assert_eq!(bindings.pressed(Keycode::F, ctrl_shift), vec![Action::Export]);
+        assert_eq!(bindings.released(Keycode::S), vec![Action::MoveBackward, Action::SaveScreenshot]);
+        for (action, name) in ACTIONS.iter(){
+            assert_eq!(Action::from_name(name), Some(*action));
+        }
+    }
+}
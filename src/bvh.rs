@@ -3,7 +3,7 @@ use crate::cpu::inter::*;
 use crate::aabb::*;
 use crate::vec3::Vec3;
 use crate::consts::{ EPSILON };
-use crate::primitive::Primitive;
+use crate::primitive::{ Primitive, Shape };
 
 pub enum ContainerType {
     MESH,
@@ -238,6 +238,24 @@ impl Bvh{
         }
     }
 
+    // fits the bounds of the vertices around new bounds of the items, keeps the structure
+    pub fn refit(&mut self, bounds: &[AABB]){
+        fn internal_refit(bvh: &mut Bvh, current: usize, bounds: &[AABB]) -> AABB{
+            let v = bvh.vertices[current];
+            let bound = if v.count > 0 { // leaf
+                union_bound(&bounds[v.left_first..v.left_first + v.count])
+            } else { // vertex
+                internal_refit(bvh, v.left_first, bounds).combined(internal_refit(bvh, v.left_first + 1, bounds))
+            };
+            bvh.vertices[current].bound = bound;
+            bound
+        }
+
+        if !self.vertices.is_empty() && !bounds.is_empty(){
+            internal_refit(self, 0, bounds);
+        }
+    }
+
     pub fn get_item_count(&self, current: usize, vec: &mut Vec<usize>){
         if current >= self.vertices.len() { return; }
         let vs = &self.vertices;
@@ -261,14 +279,21 @@ impl Bvh{
                         let mesh = &scene.meshes[bvh.mesh_index as usize];
                         for i in (v.left_first) as usize..(v.left_first+v.count) as usize {
                             // intersect triangle
+                            let t = hit.t;
                             mesh.get_triangle(i, scene).intersect(ray, hit);
+                            if hit.t < t { hit.prim = i as u32; }
                         }
                     },
                     ContainerType::TOP => { // primitive from scene
                         for i in (v.left_first) as usize..(v.left_first+v.count) as usize {
                             // intersect primitive
                             let primitive = &scene.primitives[i];
+                            let t = hit.t;
                             let (_a, _b) = primitive.intersect(ray, scene, hit);
+                            if hit.t < t {
+                                hit.object = i as u32;
+                                if !matches!(primitive.shape_type, Shape::MODEL) { hit.prim = 0; }
+                            }
                             a += _a;
                             b += _b;
                         }
@@ -0,0 +1,131 @@
+use crate::vec3::{ Vec3, Orientation };
+use crate::scene::Camera;
+use crate::consts::PI;
+
+use serde::{ Deserialize, Serialize };
+
+use std::path::Path;
+
+// Camera keyframes, evenly spaced in time and played back along a Catmull-Rom spline through them.
+// A path is stored as toml, one [[keys]] table per keyframe.
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Keyframe{
+    pub pos: [f32; 3],
+    pub yaw: f32,
+    pub roll: f32,
+    pub fov: f32,
+    pub focal_distance: f32,
+}
+
+impl Keyframe{
+    pub fn from_camera(cam: &Camera) -> Self{
+        Self{
+            pos: cam.pos.as_array(),
+            yaw: cam.ori.yaw,
+            roll: cam.ori.roll,
+            fov: cam.fov,
+            focal_distance: cam.focal_distance,
+        }
+    }
+
+    pub fn apply(&self, cam: &mut Camera){
+        cam.pos = Vec3::new(self.pos[0], self.pos[1], self.pos[2]);
+        cam.ori = Orientation{ yaw: self.yaw, roll: self.roll };
+        cam.dir = Vec3::from_orientation(&cam.ori);
+        cam.fov = self.fov;
+        cam.focal_distance = self.focal_distance;
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String>{
+        let contents = toml::to_string(self).map_err(|e| format!("Could not serialize camera: {}", e))?;
+        std::fs::write(path, contents).map_err(|e| format!("Could not write camera {:?}: {}", path, e))
+    }
+
+    fn as_array(&self) -> [f32; 7]{
+        [self.pos[0], self.pos[1], self.pos[2], self.yaw, self.roll, self.fov, self.focal_distance]
+    }
+
+    fn from_array(a: [f32; 7]) -> Self{
+        Self{ pos: [a[0], a[1], a[2]], yaw: a[3], roll: a[4], fov: a[5], focal_distance: a[6] }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct CameraPath{
+    pub keys: Vec<Keyframe>,
+}
+
+impl CameraPath{
+    pub fn read(path: &Path) -> Result<Self, String>{
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read camera path {:?}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Could not parse camera path {:?}: {}", path, e))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String>{
+        let contents = toml::to_string(self).map_err(|e| format!("Could not serialize camera path: {}", e))?;
+        std::fs::write(path, contents).map_err(|e| format!("Could not write camera path {:?}: {}", path, e))
+    }
+
+    // the camera at t, from 0 at the first keyframe to 1 at the last
+    pub fn sample(&self, t: f32) -> Option<Keyframe>{
+        let n = self.keys.len();
+        if n < 2{
+            return self.keys.first().copied();
+        }
+        // the yaw takes the short way around between keyframes
+        let mut keys: Vec<[f32; 7]> = self.keys.iter().map(|k| k.as_array()).collect();
+        for i in 1..n{
+            let prev = keys[i - 1][3];
+            keys[i][3] = prev + (keys[i][3] - prev + PI).rem_euclid(2.0 * PI) - PI;
+        }
+        let x = t.clamp(0.0, 1.0) * (n - 1) as f32;
+        let i = (x as usize).min(n - 2);
+        let u = x - i as f32;
+        let (p0, p1, p2, p3) = (keys[i.saturating_sub(1)], keys[i], keys[i + 1], keys[(i + 2).min(n - 1)]);
+        let mut res = [0.0; 7];
+        for (j, r) in res.iter_mut().enumerate(){
+            *r = catmull_rom(p0[j], p1[j], p2[j], p3[j], u);
+        }
+        let mut key = Keyframe::from_array(res);
+        key.yaw = (key.yaw + PI).rem_euclid(2.0 * PI) - PI;
+        Some(key)
+    }
+}
+
+// uniform Catmull-Rom spline between p1 and p2
+fn catmull_rom(p0: f32, p1: f32, p2: f32, p3: f32, u: f32) -> f32{
+    let u2 = u * u;
+    let u3 = u2 * u;
+    0.5 * (2.0 * p1 + (p2 - p0) * u + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * u2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * u3)
+}
+
+#[cfg(test)]
+mod test{
+    use crate::camera_path::*;
+
+    fn key(x: f32, yaw: f32) -> Keyframe{
+        Keyframe{ pos: [x, 0.0, 0.0], yaw, roll: 0.0, fov: 90.0, focal_distance: 5.0 }
+    }
+
+    #[test]
+    fn passes_through_the_keyframes(){
+        let path = CameraPath{ keys: vec![key(0.0, 0.0), key(1.0, 0.5), key(4.0, 1.0)] };
+        for (i, t) in [0.0, 0.5, 1.0].iter().enumerate(){
+            let k = path.sample(*t).unwrap();
+            assert!((k.pos[0] - path.keys[i].pos[0]).abs() < 0.0001);
+            assert!((k.yaw - path.keys[i].yaw).abs() < 0.0001);
+        }
+        let mid = path.sample(0.25).unwrap();
+        assert!(mid.pos[0] > 0.0 && mid.pos[0] < 1.0);
+        assert_eq!(CameraPath::default().sample(0.5), None);
+    }
+
+    #[test]
+    fn yaw_takes_the_short_way_around(){
+        let path = CameraPath{ keys: vec![key(0.0, PI - 0.1), key(0.0, -PI + 0.1)] };
+        let mid = path.sample(0.5).unwrap();
+        assert!(mid.yaw.abs() > PI - 0.11);
+    }
+}
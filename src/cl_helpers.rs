@@ -69,6 +69,14 @@ impl<T: ocl::OclPrm> ClBufferRW<T>{
             .enq()
     }
 
+    // copy on the device, the client buffers are left alone
+    pub fn copy_to(&self, queue: &Queue, other: &ClBufferRW<T>) -> Result<(), ocl::Error>{
+        self.ocl_buffer.cmd()
+            .queue(queue)
+            .copy(&other.ocl_buffer, None, None)
+            .enq()
+    }
+
     pub fn get_slice(&self) -> &[T]{
         &self.client_buffer
     }
@@ -116,6 +124,15 @@ impl<T: ocl::OclPrm> ClBufferR<T>{
             .enq()
     }
 
+    // overwrites part of the buffer, starting at offset
+    pub fn upload_range(&mut self, queue: &Queue, offset: usize, data: &[T]) -> Result<(), ocl::Error>{
+        self.ocl_buffer.cmd()
+            .queue(queue)
+            .offset(offset)
+            .write(data)
+            .enq()
+    }
+
     pub fn get_ocl_buffer(&self) -> &Buffer<T>{
         &self.ocl_buffer
     }
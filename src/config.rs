@@ -1,11 +1,17 @@
 use crate::scene::RenderType;
+use crate::sampler::SamplerType;
+use crate::post::Lut;
+use crate::consts::{ EPSILON, MIN_FOV, MAX_FOV };
+use crate::aov::Aov;
+use crate::vec3::Vec3;
+use crate::bindings::{ Bindings, ButtonBindings, KeyNames };
 
 use serde::Deserialize;
-use sdl2::keyboard::Keycode;
 
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::collections::HashMap;
 
 #[derive(Deserialize, Clone)]
 pub struct Config{
@@ -14,6 +20,10 @@ pub struct Config{
     post: Option<Post>,
     controls: Option<Controls>,
     camera: Option<Camera>,
+    export: Option<Export>,
+    sky: Option<Sky>,
+    fog: Option<Fog>,
+    animation: Option<Animation>,
 }
 
 pub struct ConfigParsed{
@@ -22,6 +32,10 @@ pub struct ConfigParsed{
     pub post: PostParsed,
     pub controls: ControlsParsed,
     pub camera: CameraParsed,
+    pub export: ExportParsed,
+    pub sky: Option<SkyParsed>, // the procedural sky replaces the sky of the scene
+    pub fog: Option<FogParsed>, // fills the scene outside of closed surfaces
+    pub animation: AnimationParsed,
 }
 
 impl Config{
@@ -36,11 +50,15 @@ impl Config{
     pub fn parse(self) -> Result<ConfigParsed, String>{
         let base = self.base.parse()?;
         let cpu = self.cpu.unwrap_or_default().parse();
-        let post = self.post.unwrap_or_default().parse();
-        let controls = self.controls.unwrap_or_default().parse();
+        let post = self.post.unwrap_or_default().parse()?;
+        let controls = self.controls.unwrap_or_default().parse()?;
         let camera = self.camera.unwrap_or_default().parse();
+        let export = self.export.unwrap_or_default().parse()?;
+        let sky = self.sky.map(|sky| sky.parse());
+        let fog = self.fog.map(|fog| fog.parse());
+        let animation = self.animation.unwrap_or_default().parse();
         Ok(ConfigParsed{
-            base, cpu, post, controls, camera
+            base, cpu, post, controls, camera, export, sky, fog, animation
         })
     }
 }
@@ -53,6 +71,13 @@ struct Base{
     width: u32,
     height: u32,
     frame_energy: Option<bool>,
+    spectral: Option<bool>,
+    reprojection: Option<usize>,
+    adaptive_threshold: Option<f32>,
+    noise_target: Option<f32>,
+    sampler: Option<String>,
+    hot_reload: Option<bool>,
+    render_scale: Option<f32>,
 }
 
 pub struct BaseParsed{
@@ -62,6 +87,13 @@ pub struct BaseParsed{
     pub w: u32,
     pub h: u32,
     pub frame_energy: bool,
+    pub spectral: bool,
+    pub reprojection: usize, // most samples carried over a camera move, below two disables it
+    pub adaptive_threshold: f32, // relative error at which pixels stop sampling, zero disables it
+    pub noise_target: f32, // mean relative error at which the frame stops accumulating, zero disables it
+    pub sampler: SamplerType,
+    pub hot_reload: bool, // watches the kernel, the textures and this file and reloads them when they change
+    pub render_scale: f32, // of the window size, the frame is scaled up to the window
 }
 
 impl Base{
@@ -77,12 +109,39 @@ impl Base{
         let w = if self.width == 0 { 1024 } else { self.width };
         let h = if self.height == 0 { 1024 } else { self.height };
         let frame_energy = self.frame_energy.unwrap_or(false);
+        let spectral = self.spectral.unwrap_or(false);
+        let reprojection = self.reprojection.unwrap_or(0);
+        let adaptive_threshold = self.adaptive_threshold.unwrap_or(0.0).max(0.0);
+        let noise_target = self.noise_target.unwrap_or(0.0).max(0.0);
+        let sampler = match self.sampler.map(|s| s.to_lowercase()).as_deref(){
+            None | Some("random") => SamplerType::Random,
+            Some("sobol") => SamplerType::Sobol,
+            Some("blue_noise") => SamplerType::BlueNoise,
+            Some(s) => return Err(format!("Sampler '{}' is not supported!", s)),
+        };
+        let hot_reload = self.hot_reload.unwrap_or(false);
+        let render_scale = self.render_scale.unwrap_or(1.0);
+        if render_scale <= 0.0 || render_scale > 1.0{
+            return Err(format!("Render scale {} is not between 0 and 1!", render_scale));
+        }
         Ok(BaseParsed{
-            title, gpu, render_type, w, h, frame_energy
+            title, gpu, render_type, w, h, frame_energy, spectral, reprojection, adaptive_threshold, noise_target, sampler,
+            hot_reload, render_scale,
         })
     }
 }
 
+impl BaseParsed{
+    // the size frames are rendered at in the window
+    pub fn render_size(&self) -> (u32, u32){
+        scaled_size((self.w, self.h), self.render_scale)
+    }
+}
+
+pub fn scaled_size((w, h): (u32, u32), scale: f32) -> (u32, u32){
+    (((w as f32 * scale).round() as u32).max(1), ((h as f32 * scale).round() as u32).max(1))
+}
+
 #[derive(Deserialize, Clone, Default, Debug)]
 struct Cpu{
     aa_samples: Option<usize>,
@@ -117,10 +176,36 @@ struct Post{
     vignette_strength: Option<f32>,
     distortion_coefficient: Option<f32>,
     tone_map: Option<String>,
+    denoise_passes: Option<u32>,
+    denoise_sigma: Option<f32>,
+    exposure: Option<f32>,
+    auto_exposure: Option<bool>,
+    white_point: Option<f32>,
+    white_balance: Option<f32>,
+    bloom_threshold: Option<f32>,
+    bloom_strength: Option<f32>,
+    bloom_radius: Option<usize>,
+    film_grain: Option<f32>,
+    lut: Option<String>,
 }
 
 #[derive(Clone, Copy, Debug)]
-pub enum ToneMap{ None = 0, Aces = 1, Hable = 2 }
+pub enum ToneMap{ None = 0, Aces = 1, Hable = 2, Reinhard = 3, AgX = 4, AcesFull = 5, Uchimura = 6 }
+
+impl ToneMap{
+    // the next tone map, for cycling through them
+    pub fn cycled(self) -> Self{
+        match self{
+            ToneMap::None => ToneMap::Aces,
+            ToneMap::Aces => ToneMap::Hable,
+            ToneMap::Hable => ToneMap::Reinhard,
+            ToneMap::Reinhard => ToneMap::AgX,
+            ToneMap::AgX => ToneMap::AcesFull,
+            ToneMap::AcesFull => ToneMap::Uchimura,
+            ToneMap::Uchimura => ToneMap::None,
+        }
+    }
+}
 
 pub struct PostParsed{
     pub chromatic_aberration_shift: usize,
@@ -128,10 +213,21 @@ pub struct PostParsed{
     pub vignette_strength: f32,
     pub distortion_coefficient: f32,
     pub tone_map: ToneMap,
+    pub denoise_passes: u32, // zero disables the denoiser
+    pub denoise_sigma: f32,
+    pub exposure: f32, // in stops, compensation on top of auto exposure
+    pub auto_exposure: bool, // meter the frame's luminance histogram
+    pub white_point: f32, // of the Reinhard tone map
+    pub white_balance: f32, // temperature in kelvin of the light that is shown as white
+    pub bloom_threshold: f32,
+    pub bloom_strength: f32, // zero disables bloom
+    pub bloom_radius: usize,
+    pub film_grain: f32,
+    pub lut: Option<Lut>, // colour grading, read from a .cube file
 }
 
 impl Post{
-    pub fn parse(self) -> PostParsed{
+    pub fn parse(self) -> Result<PostParsed, String>{
         let chromatic_aberration_shift = self.chromatic_aberration_shift.unwrap_or(0);
         let chromatic_aberration_strength = self.chromatic_aberration_strength.unwrap_or(0.0);
         let vignette_strength = self.vignette_strength.unwrap_or(0.0);
@@ -139,67 +235,92 @@ impl Post{
         let tone_map = match self.tone_map.map(|s| s.to_lowercase()).as_deref(){
             Some("aces") => ToneMap::Aces,
             Some("hable") => ToneMap::Hable,
+            Some("reinhard") => ToneMap::Reinhard,
+            Some("agx") => ToneMap::AgX,
+            Some("aces_full") => ToneMap::AcesFull,
+            Some("uchimura") | Some("gt") => ToneMap::Uchimura,
             _ => ToneMap::None,
         };
-        PostParsed{
+        let denoise_passes = self.denoise_passes.unwrap_or(0).min(8);
+        let denoise_sigma = self.denoise_sigma.unwrap_or(1.0).max(0.0);
+        let exposure = self.exposure.unwrap_or(0.0);
+        let auto_exposure = self.auto_exposure.unwrap_or(false);
+        let white_point = self.white_point.unwrap_or(4.0).max(EPSILON);
+        let white_balance = self.white_balance.unwrap_or(6500.0).clamp(1000.0, 40000.0);
+        let bloom_threshold = self.bloom_threshold.unwrap_or(1.0).max(0.0);
+        let bloom_strength = self.bloom_strength.unwrap_or(0.0).max(0.0);
+        let bloom_radius = self.bloom_radius.unwrap_or(8).clamp(1, 64);
+        let film_grain = self.film_grain.unwrap_or(0.0).max(0.0);
+        let lut = match self.lut{
+            Some(path) => Some(Lut::read(Path::new(&path))?),
+            None => None,
+        };
+        Ok(PostParsed{
             chromatic_aberration_shift, chromatic_aberration_strength,
             vignette_strength, distortion_coefficient, tone_map,
-        }
+            denoise_passes, denoise_sigma, exposure, auto_exposure, white_point, white_balance,
+            bloom_threshold, bloom_strength, bloom_radius, film_grain, lut,
+        })
     }
 }
 
 #[derive(Deserialize, Clone, Default, Debug)]
 struct Controls{
-    move_forward: Option<String>,
-    move_backward: Option<String>,
-    move_left: Option<String>,
-    move_right: Option<String>,
-    move_up: Option<String>,
-    move_down: Option<String>,
-    look_up: Option<String>,
-    look_down: Option<String>,
-    look_left: Option<String>,
-    look_right: Option<String>,
-    export_frame: Option<String>,
-    toggle_focus_mode: Option<String>,
-    toggle_show_bvh: Option<String>,
     move_sensitivity: Option<f32>,
     look_sensitivity: Option<f32>,
+    mouse_sensitivity: Option<f32>,
+    invert_mouse_x: Option<bool>,
+    invert_mouse_y: Option<bool>,
+    scroll: Option<String>,
+    gamepad_deadzone: Option<f32>,
+    gamepad: Option<HashMap<String, KeyNames>>, // actions to gamepad buttons
+    // every other entry binds an action to keys
+    #[serde(flatten)]
+    bindings: HashMap<String, KeyNames>,
+}
+
+// what the scroll wheel changes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScrollAction{
+    MoveSpeed,
+    Fov,
 }
 
+#[derive(Clone, Debug)]
 pub struct ControlsParsed{
-    pub key_map: Vec<Option<Keycode>>,
-    pub move_sens: f32,
-    pub look_sens: f32,
+    pub bindings: Bindings,
+    pub move_sens: f32, // distance per frame at 60 fps
+    pub look_sens: f32, // radians per frame at 60 fps
+    pub mouse_sens: f32, // radians per pixel the mouse moves
+    pub invert_mouse_x: bool,
+    pub invert_mouse_y: bool,
+    pub scroll: ScrollAction,
+    pub gamepad: ButtonBindings,
+    pub gamepad_deadzone: f32, // part of the stick range that is ignored
 }
 
 impl Controls{
-    fn parse(self) -> ControlsParsed{
-        fn parse_kc(x: Option<String>) -> Option<Keycode>{
-            match x{
-                None => None,
-                Some(y) => Keycode::from_name(&y),
-            }
-        }
-        let mf = parse_kc(self.move_forward);
-        let mb = parse_kc(self.move_backward);
-        let ml = parse_kc(self.move_left);
-        let mr = parse_kc(self.move_right);
-        let mu = parse_kc(self.move_up);
-        let md = parse_kc(self.move_down);
-        let ll = parse_kc(self.look_left);
-        let lr = parse_kc(self.look_right);
-        let lu = parse_kc(self.look_up);
-        let ld = parse_kc(self.look_down);
-        let ef = parse_kc(self.export_frame);
-        let tfm = parse_kc(self.toggle_focus_mode);
-        let tsb = parse_kc(self.toggle_show_bvh);
+    fn parse(self) -> Result<ControlsParsed, String>{
+        let bindings = Bindings::parse(&self.bindings)?;
+        let gamepad = match &self.gamepad{
+            Some(names) => ButtonBindings::parse(names)?,
+            None => ButtonBindings::standard(),
+        };
+        let gamepad_deadzone = self.gamepad_deadzone.unwrap_or(0.15).clamp(0.0, 0.95);
         let move_sens = self.move_sensitivity.unwrap_or(0.1);
         let look_sens = self.look_sensitivity.unwrap_or(0.05);
-        ControlsParsed{
-            key_map: vec![mf, mb, ml, mr, mu, md, lu, ld, ll, lr, tfm, ef, tsb],
-            move_sens, look_sens,
-        }
+        let mouse_sens = self.mouse_sensitivity.unwrap_or(0.003);
+        let invert_mouse_x = self.invert_mouse_x.unwrap_or(false);
+        let invert_mouse_y = self.invert_mouse_y.unwrap_or(false);
+        let scroll = match self.scroll.map(|s| s.to_lowercase()).as_deref(){
+            None | Some("speed") => ScrollAction::MoveSpeed,
+            Some("fov") => ScrollAction::Fov,
+            Some(s) => return Err(format!("Scroll action '{}' is not supported!", s)),
+        };
+        Ok(ControlsParsed{
+            bindings,
+            move_sens, look_sens, mouse_sens, invert_mouse_x, invert_mouse_y, scroll, gamepad, gamepad_deadzone,
+        })
     }
 }
 
@@ -208,19 +329,168 @@ impl Controls{
 struct Camera{
     fisheye: Option<bool>,
     fov: Option<f32>,
+    aperture: Option<f32>,
+    focal_distance: Option<f32>,
+    blades: Option<u32>,
+    shutter: Option<f32>,
+    motion: Option<[f32; 3]>,
+    spin: Option<f32>,
 }
 
 pub struct CameraParsed{
     pub fisheye: bool,
     pub fov: f32,
+    pub aperture: f32,
+    pub focal_distance: f32,
+    pub blades: u32,
+    pub shutter: f32,
+    pub motion: Vec3, // translation over the shutter interval
+    pub spin: f32, // change of yaw in radians over the shutter interval
 }
 
 impl Camera{
     fn parse(self) -> CameraParsed{
         let fisheye = self.fisheye.unwrap_or(false);
-        let fov = self.fov.unwrap_or(90.0).min(1.0).max(180.0);
+        let fov = self.fov.unwrap_or(90.0).clamp(MIN_FOV, MAX_FOV);
+        let aperture = self.aperture.unwrap_or(0.0).max(0.0);
+        let focal_distance = self.focal_distance.unwrap_or(5.0).max(0.01);
+        // fewer than three blades is a round aperture
+        let blades = self.blades.unwrap_or(0);
+        let shutter = self.shutter.unwrap_or(0.0).clamp(0.0, 1.0);
+        let [x, y, z] = self.motion.unwrap_or([0.0; 3]);
+        let motion = Vec3::new(x, y, z);
+        let spin = self.spin.unwrap_or(0.0);
         CameraParsed{
-            fisheye, fov
+            fisheye, fov, aperture, focal_distance, blades, shutter, motion, spin
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+struct Sky{
+    time_of_day: Option<f32>,
+    turbidity: Option<f32>,
+    sun_tilt: Option<f32>,
+    ground_albedo: Option<f32>,
+}
+
+pub struct SkyParsed{
+    pub time_of_day: f32, // hours, sunrise at 6 and sunset at 18
+    pub turbidity: f32,
+    pub sun_tilt: f32, // radians
+    pub ground_albedo: f32,
+}
+
+impl Sky{
+    fn parse(self) -> SkyParsed{
+        let time_of_day = self.time_of_day.unwrap_or(10.0).rem_euclid(24.0);
+        let turbidity = self.turbidity.unwrap_or(3.0).clamp(1.0, 10.0);
+        let sun_tilt = self.sun_tilt.unwrap_or(0.5);
+        let ground_albedo = self.ground_albedo.unwrap_or(0.3).clamp(0.0, 1.0);
+        SkyParsed{
+            time_of_day, turbidity, sun_tilt, ground_albedo
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+struct Fog{
+    density: Option<f32>,
+    albedo: Option<[f32; 3]>,
+    g: Option<f32>,
+    distance: Option<f32>,
+}
+
+pub struct FogParsed{
+    pub density: f32, // extinction per unit of distance
+    pub albedo: Vec3,
+    pub g: f32, // Henyey-Greenstein asymmetry
+    pub distance: f32, // the fog ends this far along every ray segment
+}
+
+impl Fog{
+    fn parse(self) -> FogParsed{
+        let density = self.density.unwrap_or(0.05).max(0.0);
+        let [r, g, b] = self.albedo.unwrap_or([0.9; 3]);
+        let albedo = Vec3::new(r, g, b).clamped(0.0, 1.0);
+        let g = self.g.unwrap_or(0.0);
+        let distance = self.distance.unwrap_or(100.0).max(0.0);
+        FogParsed{
+            density, albedo, g, distance
         }
     }
 }
+
+#[derive(Deserialize, Clone, Default, Debug)]
+struct Animation{
+    path: Option<String>,
+    frames: Option<usize>,
+    samples: Option<usize>,
+    output: Option<String>,
+}
+
+pub struct AnimationParsed{
+    pub path: String, // the camera path, keyframes are recorded into it
+    pub frames: usize, // renders the path to a sequence instead of opening a window when not zero
+    pub samples: usize, // per frame
+    pub output: String, // without extension, {frame} is replaced, exported in the formats of [export]
+}
+
+impl Animation{
+    fn parse(self) -> AnimationParsed{
+        let path = self.path.unwrap_or_else(|| "camera_path.toml".to_string());
+        let frames = self.frames.unwrap_or(0);
+        let samples = self.samples.unwrap_or(64).max(1);
+        let output = self.output.unwrap_or_else(|| "frames/{frame}".to_string());
+        AnimationParsed{
+            path, frames, samples, output
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default, Debug)]
+struct Export{
+    path: Option<String>,
+    formats: Option<Vec<String>>,
+    aovs: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat{
+    Png, // 8 bit, the displayed frame
+    Png16, // 16 bit, the linear frame with gamma
+    Pfm, // 32 bit float, the linear frame
+    Exr, // 32 bit float, the linear frame
+}
+
+#[derive(Clone)]
+pub struct ExportParsed{
+    pub path: String, // without extension, {time} and {samples} are replaced
+    pub formats: Vec<ExportFormat>,
+    pub aovs: Vec<Aov>, // written next to the frame in the float formats
+}
+
+impl Default for ExportParsed{
+    fn default() -> Self{
+        Self{ path: "{time}".to_string(), formats: vec![ExportFormat::Png], aovs: Vec::new() }
+    }
+}
+
+impl Export{
+    fn parse(self) -> Result<ExportParsed, String>{
+        let path = self.path.unwrap_or_else(|| "{time}".to_string());
+        let formats = match self.formats{
+            None => vec![ExportFormat::Png],
+            Some(fs) => fs.iter().map(|f| match f.to_lowercase().as_ref(){
+                "png" => Ok(ExportFormat::Png),
+                "png16" => Ok(ExportFormat::Png16),
+                "pfm" => Ok(ExportFormat::Pfm),
+                "exr" => Ok(ExportFormat::Exr),
+                _ => Err(format!("Export format '{}' is not supported!", f)),
+            }).collect::<Result<Vec<_>, _>>()?,
+        };
+        let aovs = self.aovs.unwrap_or_default().iter().map(|a| Aov::parse(a)
+            .ok_or(format!("Aov '{}' is not supported!", a))).collect::<Result<Vec<_>, _>>()?;
+        Ok(ExportParsed{ path, formats, aovs })
+    }
+}
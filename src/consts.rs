@@ -7,6 +7,9 @@ pub const FRAC_4_PI: f32 = 0.25 * std::f32::consts::PI;
 pub const MAX_RENDER_DIST: f32 = 1000000.0;
 pub const EPSILON: f32 = 0.001;
 pub const AMBIENT: f32 = 0.05;
+// bounds of the horizontal field of view, in degrees
+pub const MIN_FOV: f32 = 1.0;
+pub const MAX_FOV: f32 = 179.0;
 
 pub const WATER_ABSORPTION: Vec3 = Vec3 { x: 0.49, y: 0.1, z: 0.04 };
 pub const AIR_ABSORPTION: Vec3 = Vec3 { x: 0.01, y: 0.01, z: 0.01 };
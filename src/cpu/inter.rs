@@ -3,11 +3,13 @@ use crate::material::MaterialIndex;
 use crate::vec3::Vec3;
 use crate::aabb::AABB;
 use crate::consts::*;
+use crate::aov::NO_ID;
 
 #[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub struct Ray{
     pub pos: Vec3,
     pub dir: Vec3,
+    pub time: f32, // moment in the shutter interval, 0..1
 }
 
 impl Ray{
@@ -51,6 +53,8 @@ pub struct RayHit{
     pub t: f32,
     pub mat: MaterialIndex,
     pub uvtype: u8,
+    pub object: u32, // index in the top level bvh
+    pub prim: u32, // triangle within the mesh of a model
 }
 
 impl RayHit{
@@ -60,6 +64,8 @@ impl RayHit{
         t: MAX_RENDER_DIST,
         uvtype: 255,
         mat: 0,
+        object: NO_ID,
+        prim: NO_ID,
     };
 
     #[inline]
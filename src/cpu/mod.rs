@@ -1,8 +1,14 @@
-use crate::scene::Scene;
-use crate::material::Material;
+use crate::scene::{ Scene, Light, LightType };
+use crate::material::{ Material, Medium };
+use crate::primitive::{ Primitive, Shape };
 use crate::vec3::Vec3;
 use crate::state::{ RenderMode, State };
 use crate::consts::*;
+use crate::sampler::Sampler;
+use crate::post::meter_exposure;
+use crate::adaptive::luminance;
+use crate::denoise::AUX_SIZE;
+use crate::aov::{ ID_SIZE, NO_ID };
 
 use rand::prelude::*;
 
@@ -13,7 +19,7 @@ use inter::*;
 #[allow(clippy::many_single_char_names)]
 pub fn whitted(
     w: usize, h: usize, threads: usize,
-    scene: &Scene, tex_params: &[u32], textures: &[u8],
+    scene: &Scene, tex_params: &[u32], textures: &[u8], blue_noise: &[f32],
     screen: &mut Vec<u32>, acc: &mut Vec<Vec3>, state: &mut State, rng: &mut ThreadRng
 ){
     let reduce = match state.render_mode{
@@ -59,8 +65,9 @@ pub fn whitted(
             let offset = t * target_strip_h;
             let seed = seeds[t];
 
-            let pos = scene.cam.pos;
-            let cd = scene.cam.dir.normalized_fast();
+            let cam = &scene.cam;
+            let pos = cam.pos;
+            let cd = cam.dir.normalized_fast();
             let aspect = rw as f32 / rh as f32;
             let uv_dist = (aspect / 2.0) / (scene.cam.fov / 2.0 * 0.01745329).tan();
 
@@ -71,30 +78,53 @@ pub fn whitted(
             let dist_coef = scene.cam.distortion_coefficient;
             let is_wide = angle > 0.0;
             let show_bvh = state.show_bvh;
+            let aperture = scene.cam.aperture;
+            let focal_dist = scene.cam.focal_distance;
+            let blades = scene.cam.blades;
+            let hor = cd.crossed(Vec3::UP).normalized_fast();
+            let ver = hor.crossed(cd).normalized_fast();
+            let shutter = cam.shutter;
+            let cam_moves = shutter > 0.0 && (cam.motion != Vec3::ZERO || cam.spin != 0.0);
 
             let handler = s.spawn(move |_|{
-                let mut seed = seed;
+                let mut smp = Sampler::new(scene.sampler, seed, blue_noise);
                 for xx in 0..rw{
                 for yy in 0..strip_h{
                     let x = xx;
                     let y = yy + offset;
-                    let aa_u = u32tf01(xor32(&mut seed));
-                    let aa_v = u32tf01(xor32(&mut seed));
+                    smp.start(x as u32, y as u32, samples_taken as u32 - 1);
+                    let aa_u = smp.sample();
+                    let aa_v = smp.sample();
+                    let time = shutter * smp.sample();
+                    let (pos, cd, hor, ver, theta_mid, phi_mid) = if cam_moves {
+                        let (pos, cd) = cam.at_time(time);
+                        let hor = cd.crossed(Vec3::UP).normalized_fast();
+                        let ver = hor.crossed(cd).normalized_fast();
+                        (pos, cd, hor, ver, cd.y.asin(), f32::atan2(cd.x, -cd.z))
+                    } else {
+                        (pos, cd, hor, ver, theta_mid, phi_mid)
+                    };
 
                     let dir = initial_ray_dir(pos, cd, x as f32, y as f32, rw as f32, rh as f32, aa_u, aa_v,
                                               aspect, uv_dist, angle, radius, theta_mid, phi_mid, dist_coef, is_wide);
                     let col = if dir.eq(&Vec3::ZERO){
                         Vec3::BLACK
                     } else {
-                        let ray = Ray { pos, dir };
-                        let contexts = Contexts::new();
-                        let mut col = if show_bvh{
-                            debug_trace(ray, scene)
+                        let ray = if aperture > 0.0 {
+                            // thin lens: move the origin over the aperture, keep the focal plane sharp
+                            let focus = pos.added(dir.scaled(focal_dist / dir.dot(cd)));
+                            let (lu, lv) = lens_point(blades, &mut smp);
+                            let lpos = pos.added(hor.scaled(lu * aperture)).added(ver.scaled(lv * aperture));
+                            Ray { pos: lpos, dir: focus.subed(lpos).normalized_fast(), time }
                         } else {
-                            whitted_trace(ray, scene, tex_params, textures, max_depth, contexts)
+                            Ray { pos, dir, time }
                         };
-                        col.pow_scalar(1.0 / GAMMA);
-                        col
+                        let contexts = Contexts::new().with_medium(scene.fog);
+                        if show_bvh{
+                            debug_trace(ray, scene)
+                        } else {
+                            whitted_trace(ray, scene, tex_params, textures, max_depth, contexts, &mut smp)
+                        }
                     };
                     strip[xx + yy * rw].add(col);
                 }
@@ -105,42 +135,41 @@ pub fn whitted(
         handlers.into_iter().for_each(|h| h.join().expect("Could not join whitted cpu thread (tracing phase)!"));
     }).expect("Could not create crossbeam threadscope (tracing phase)!");
 
-    let ash = scene.cam.chromatic_aberration_shift;
-    let ast = scene.cam.chromatic_aberration_strength;
-    let vst = scene.cam.vignette_strength;
+    let post = &scene.cam.post;
+    let mult = 1.0 / samples_taken as f32;
+    let metered = if post.auto_exposure {
+        meter_exposure(acc[..rw * rh].iter().map(|c| luminance(c.scaled(mult))))
+    } else {
+        0.0
+    };
+    let gains = post.gains(metered);
+    let bloom = if post.bloom_strength > 0.0 {
+        post.bloom(&acc[..rw * rh], rw, rh, gains.scaled(mult))
+    } else {
+        Vec::new()
+    };
+    let frame = samples_taken as u32;
 
     let target_strip_h = (h / threads) + 1;
     let target_strip_l = target_strip_h * w;
     let strips: Vec<&mut[u32]> = screen.chunks_mut(target_strip_l).collect();
 
     let acc: &[Vec3] = acc.as_ref();
+    let bloom: &[Vec3] = bloom.as_ref();
     crossbeam_utils::thread::scope(|s|{
         let mut handlers = Vec::new();
         for (t, strip) in strips.into_iter().enumerate(){
             let strip_h = strip.len() / w;
             let offset = t * target_strip_h;
             let handler = s.spawn(move |_|{
+                let index = |x: usize, y: usize| (x / reduce).min(rw - 1) + (y / reduce).min(rh - 1) * rw;
+                let fetch = |x: usize, y: usize| acc[index(x, y)].scaled(mult);
                 for xx in 0..w{
                 for yy in 0..strip_h{
                     let x = xx;
                     let y = yy + offset;
-                    let mut uv = Vec3::new(x as f32 / w as f32, y as f32 / h as f32, 0.0);
-                    let mut col = acc[(x / reduce).min(rw - 1) + (y / reduce).min(rh - 1) * rw];
-                    if ash > 0 && ast > EPSILON{
-                        let r = acc[((x.max(ash) - ash) / reduce).min(rw - 1) + ((y.max(ash) - ash) / reduce).min(rh - 1) * rw].x;
-                        let b = acc[((x.min(w - ash - 1) + ash) / reduce).min(rw - 1) + ((y.min(h - ash - 1) + ash) / reduce).min(rh - 1) * rw].z;
-                        let abr_str = (uv.x * uv.y * 8.0).powf(ast).min(1.0).max(0.0);
-                        col.mix(Vec3::new(r, col.y, b), 1.0 - abr_str);
-                    }
-                    uv.x *= 1.0 - uv.x;
-                    uv.y *= 1.0 - uv.y;
-                    let vignette = (uv.x * uv.y * 32.0).powf(vst).min(1.0).max(0.0);
-                    col.div_scalar_fast(samples_taken as f32);
-                    col.scale(vignette);
-                    col.clamp(0.0, 1.0);
-                    col.scale(255.0);
-                    let int = ((col.x as u32) << 16) + ((col.y as u32) << 8) + col.z as u32;
-                    strip[xx + yy * w] = int;
+                    let b = if bloom.is_empty() { Vec3::ZERO } else { bloom[index(x, y)] };
+                    strip[xx + yy * w] = post.apply(fetch, b, gains, (x, y), (w, h), frame);
                 }
                 }
             });
@@ -150,20 +179,122 @@ pub fn whitted(
     }).expect("Could not create crossbeam threadscope! (post phase)");
 }
 
+// first hit through the pixel centres at the resolution of the last frame, for the aovs.
+// The guides are laid out like the kernel's, the direct light is the shading without bounces.
+#[allow(clippy::too_many_arguments)]
+pub fn aov_pass(
+    rw: usize, rh: usize, threads: usize,
+    scene: &Scene, tex_params: &[u32], textures: &[u8], blue_noise: &[f32],
+    guides: &mut Vec<f32>, ids: &mut Vec<u32>
+){
+    guides.resize(rw * rh * AUX_SIZE, 0.0);
+    ids.resize(rw * rh * ID_SIZE, NO_ID);
+    let threads = threads.max(1);
+    let target_strip_h = (rh / threads) + 1;
+    let guide_strips: Vec<&mut[f32]> = guides.chunks_mut(target_strip_h * rw * AUX_SIZE).take(threads).collect();
+    let id_strips: Vec<&mut[u32]> = ids.chunks_mut(target_strip_h * rw * ID_SIZE).take(threads).collect();
+
+    crossbeam_utils::thread::scope(|s|{
+        let mut handlers = Vec::new();
+        for (t, (guide_strip, id_strip)) in guide_strips.into_iter().zip(id_strips).enumerate(){
+            let strip_h = guide_strip.len() / (rw * AUX_SIZE);
+            let offset = t * target_strip_h;
+            let cam = &scene.cam;
+            let pos = cam.pos;
+            let cd = cam.dir.normalized_fast();
+            let aspect = rw as f32 / rh as f32;
+            let uv_dist = (aspect / 2.0) / (cam.fov / 2.0 * 0.01745329).tan();
+            let phi_mid = f32::atan2(cd.x, -cd.z);
+            let theta_mid = cd.y.asin();
+            let angle = cam.angle_radius;
+            let radius = usize::min(rw, rh) as f32 * 0.5;
+
+            let handler = s.spawn(move |_|{
+                let mut smp = Sampler::new(scene.sampler, 0, blue_noise);
+                for xx in 0..rw{
+                for yy in 0..strip_h{
+                    let (x, y) = (xx, yy + offset);
+                    let i = xx + yy * rw;
+                    let guide = &mut guide_strip[i * AUX_SIZE..(i + 1) * AUX_SIZE];
+                    let id = &mut id_strip[i * ID_SIZE..(i + 1) * ID_SIZE];
+                    smp.start(x as u32, y as u32, 0);
+                    let dir = initial_ray_dir(pos, cd, x as f32, y as f32, rw as f32, rh as f32, 0.5, 0.5,
+                                              aspect, uv_dist, angle, radius, theta_mid, phi_mid, cam.distortion_coefficient, angle > 0.0);
+                    let ray = Ray { pos, dir, time: 0.0 };
+                    let mut hit = RayHit::NULL;
+                    if !dir.eq(&Vec3::ZERO){
+                        scene.top_bvh.intersect(ray, scene, &mut hit);
+                    }
+                    let (albedo, nor) = if hit.is_null(){
+                        (get_sky_col(dir, scene, tex_params, textures), Vec3::ZERO)
+                    } else {
+                        let nor = if dir.dot(hit.nor) < 0.0 { hit.nor } else { hit.nor.neged() };
+                        (scene.mats[hit.mat as usize].col, nor)
+                    };
+                    let direct = if dir.eq(&Vec3::ZERO){
+                        Vec3::BLACK
+                    } else {
+                        whitted_trace(ray, scene, tex_params, textures, 1, Contexts::new().with_medium(scene.fog), &mut smp)
+                    };
+                    guide[..3].copy_from_slice(&albedo.as_array());
+                    guide[3..6].copy_from_slice(&nor.as_array());
+                    guide[6] = hit.t;
+                    guide[7..10].copy_from_slice(&direct.as_array());
+                    let mat = if hit.is_null() { NO_ID } else { hit.mat };
+                    id.copy_from_slice(&[mat, hit.object, hit.prim]);
+                }
+                }
+            });
+            handlers.push(handler);
+        }
+        handlers.into_iter().for_each(|h| h.join().expect("Could not join aov cpu thread!"));
+    }).expect("Could not create crossbeam threadscope (aov pass)!");
+}
+
 // RANDOM ------------------------------------------------------------
 
-// credit: George Marsaglia
+// uniform point on the unit sphere
+#[inline]
+fn random_sphere_point(smp: &mut Sampler) -> Vec3{
+    let z = 1.0 - 2.0 * smp.sample();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * smp.sample();
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// uniform direction within the cone around dir with cosine of the half angle cos_max
 #[inline]
-fn xor32(seed: &mut u32) -> u32{
-    *seed ^= *seed << 13;
-    *seed ^= *seed >> 17;
-    *seed ^= *seed << 5;
-    *seed
+fn random_cone_dir(dir: Vec3, cos_max: f32, smp: &mut Sampler) -> Vec3{
+    if cos_max >= 1.0 - EPSILON { return dir; }
+    let z = 1.0 - smp.sample() * (1.0 - cos_max);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * smp.sample();
+    let helper = if dir.x.abs() > 0.99 { Vec3::UP } else { Vec3::LEFT };
+    let t = dir.crossed(helper).normalized_fast();
+    let b = dir.crossed(t);
+    t.scaled(r * phi.cos()).added(b.scaled(r * phi.sin())).added(dir.scaled(z)).normalized_fast()
 }
 
+// uniform point on the unit disk, or on a regular polygon with the given amount of blades
 #[inline]
-fn u32tf01(int: u32) -> f32{
-   int as f32 * 2.3283064e-10
+fn lens_point(blades: u32, smp: &mut Sampler) -> (f32, f32){
+    let r1 = smp.sample();
+    let r2 = smp.sample();
+    if blades < 3 {
+        let r = r1.sqrt();
+        let phi = 2.0 * PI * r2;
+        return (r * phi.cos(), r * phi.sin());
+    }
+    // pick one of the triangles the polygon consists of, then a uniform point in it
+    let seg = 2.0 * PI / blades as f32;
+    let i = (r1 * blades as f32).floor();
+    let (a, b) = (i * seg, (i + 1.0) * seg);
+    let su = r2.sqrt();
+    let t = smp.sample();
+    (
+        su * ((1.0 - t) * a.cos() + t * b.cos()),
+        su * ((1.0 - t) * a.sin() + t * b.sin()),
+    )
 }
 
 // WHITTED ------------------------------------------------------------
@@ -192,27 +323,49 @@ fn debug_trace(ray: Ray, scene: &Scene) -> Vec3{
 }
 
 // trace light ray through scene
-fn whitted_trace(ray: Ray, scene: &Scene, tps: &[u32], ts: &[u8], depth: u8, contexts: Contexts) -> Vec3{
+fn whitted_trace(ray: Ray, scene: &Scene, tps: &[u32], ts: &[u8], depth: u8, contexts: Contexts, smp: &mut Sampler) -> Vec3{
     let mut hit = RayHit::NULL;
     // trace top-level bvh
     scene.top_bvh.intersect(ray, scene, &mut hit);
 
-    if depth == 0 || hit.is_null() {
-        return get_sky_col(ray.dir, scene, tps, ts);
-    }
-
     let absorption_context;
     let refraction_context;
+    let medium_context;
     if let Some(context) = contexts.current() {
         absorption_context = context.absorption;
         refraction_context = context.refraction;
+        medium_context = context.medium;
     } else {
         absorption_context = Vec3::BLACK;
         refraction_context = 1.0;
+        medium_context = None;
+    }
+    // the fog of the scene is the bottom context and ends at the fog distance
+    let medium_dist = if contexts.index == 0 { hit.t.min(scene.fog_distance) } else { hit.t };
+
+    if depth == 0 || hit.is_null() {
+        let sky = get_sky_col(ray.dir, scene, tps, ts);
+        return in_medium(sky, &medium_context, ray.dir, medium_dist, scene);
     }
 
     let is_inbound = ray.dir.dot(hit.nor) < 0.0;
     let mat = &scene.mats[hit.mat as usize];
+    // the medium the ray travelled through to get here
+    let (medium, medium_dist) = if is_inbound { (medium_context, medium_dist) } else { (mat.medium, hit.t) };
+    if mat.emittance > 0.0 {
+        return in_medium(mat.col.scaled(mat.emittance), &medium, ray.dir, medium_dist, scene);
+    }
+    if mat.medium.is_some() && !mat.is_dielectric {
+        // boundary of a volume without a surface, continue straight on
+        let ray_next = Ray{ pos: hit.pos.added(ray.dir.scaled(EPSILON)), ..ray };
+        let contexts_next = if is_inbound {
+            contexts.pushed(Context { absorption: Vec3::BLACK, refraction: refraction_context, medium: mat.medium })
+        } else {
+            contexts.popped()
+        };
+        let col = whitted_trace(ray_next, scene, tps, ts, depth - 1, contexts_next, smp);
+        return in_medium(col, &medium, ray.dir, medium_dist, scene);
+    }
     let refraction = mat.refraction;
     let absorption = mat.abs_fres;
     let normal = if is_inbound { hit.nor } else { hit.nor.neged() };
@@ -282,7 +435,7 @@ fn whitted_trace(ray: Ray, scene: &Scene, tps: &[u32], ts: &[u8], depth: u8, con
     }
 
     // diffuse, specular
-    let (mut diff, spec) = blinn(&hit, mat, roughness, scene, ray.dir);
+    let (mut diff, spec) = blinn(&hit, mat, roughness, scene, ray.dir, ray.time, smp);
     diff.mul(texcol);
 
     // dielectric: transparency / refraction and reflection
@@ -305,19 +458,19 @@ fn whitted_trace(ray: Ray, scene: &Scene, tps: &[u32], ts: &[u8], depth: u8, con
 
     // transparency / refraction
     let tran = if transparency > EPSILON {
-        let ray_next = Ray{ pos: hit.pos.subed(normal.scaled(EPSILON)), dir: transparency_dir };
+        let ray_next = Ray{ pos: hit.pos.subed(normal.scaled(EPSILON)), dir: transparency_dir, time: ray.time };
         let contexts_next = if is_inbound {
-            contexts.pushed(Context { absorption, refraction })
+            contexts.pushed(Context { absorption, refraction, medium: mat.medium })
         } else {
             contexts.popped()
         };
-        whitted_trace(ray_next, scene, tps, ts, depth - 1, contexts_next).scaled(transparency)
+        whitted_trace(ray_next, scene, tps, ts, depth - 1, contexts_next, smp).scaled(transparency)
     } else { Vec3::BLACK };
 
     // reflection
     let refl = if reflectivity > EPSILON {
-        let ray_next = Ray{ pos: hit.pos.added(normal.scaled(EPSILON)), dir: ray.dir.reflected(normal) };
-        whitted_trace(ray_next, scene, tps, ts, depth - 1, contexts).scaled(reflectivity)
+        let ray_next = Ray{ pos: hit.pos.added(normal.scaled(EPSILON)), dir: ray.dir.reflected(normal), time: ray.time };
+        whitted_trace(ray_next, scene, tps, ts, depth - 1, contexts, smp).scaled(reflectivity)
     } else { Vec3::BLACK };
 
     let color = (diff).scaled(1.0 - reflectivity - transparency)
@@ -325,11 +478,32 @@ fn whitted_trace(ray: Ray, scene: &Scene, tps: &[u32], ts: &[u8], depth: u8, con
         .added(tran)
         .added(refl);
 
-    if is_inbound {
+    let color = if is_inbound {
         absorp(&color, &absorption_context, hit.t)
     } else {
         absorp(&color, &absorption, hit.t)
+    };
+    in_medium(color, &medium, ray.dir, medium_dist, scene)
+}
+
+// single scattering through a homogeneous medium over dist, lit by the ambient light, the sun
+// and directional lights without shadows
+fn in_medium(col: Vec3, medium: &Option<Medium>, dir: Vec3, dist: f32, scene: &Scene) -> Vec3{
+    let medium = match medium{
+        Some(m) => m,
+        None => return col,
+    };
+    let trans = medium.transmittance(dist);
+    let mut light = Vec3::uni(AMBIENT);
+    if let Some(sky) = &scene.procedural_sky{
+        let c = sky.coefficients();
+        let sun = c.sun_radiance.scaled(sky.sun_solid_angle() * 4.0 * PI * medium.phase(dir.dot(c.sun_dir)));
+        light.add(sun);
+    }
+    for l in scene.lights.iter().filter(|l| l.ltype == LightType::Directional){
+        light.add(l.col.scaled(l.intensity * 4.0 * PI * medium.phase(dir.dot(l.dir.neged()))));
     }
+    col.scaled(trans).added(light.muled(medium.albedo).scaled(1.0 - trans))
 }
 
 // LENS ------------------------------------------------------------
@@ -407,6 +581,10 @@ impl Contexts {
             None
         }
     }
+    pub fn with_medium(mut self, medium: Option<Medium>) -> Self{
+        self.stack[self.index].medium = medium;
+        self
+    }
     pub fn pushed(mut self, context: Context) -> Self{
         self.index = (self.index+1).min(2);
         self.stack[self.index] = context;
@@ -422,11 +600,12 @@ impl Contexts {
 pub struct Context {
     pub absorption: Vec3,
     pub refraction: f32,
+    pub medium: Option<Medium>,
 }
 
 impl Context {
     pub fn new() -> Self{
-        Self { absorption: Vec3::BLACK, refraction: 1.0 }
+        Self { absorption: Vec3::BLACK, refraction: 1.0, medium: None }
     }
 }
 
@@ -480,21 +659,107 @@ fn resolve_dielectric(ni: f32, nt: f32, dir: Vec3, normal: Vec3) -> (f32, Vec3){
 
 // get diffuse light incl colour of hit with all lights
 #[inline]
-fn blinn(hit: &RayHit, mat: &Material, roughness: f32, scene: &Scene, viewdir: Vec3) -> (Vec3, Vec3){
+fn blinn(hit: &RayHit, mat: &Material, roughness: f32, scene: &Scene, viewdir: Vec3, time: f32, smp: &mut Sampler) -> (Vec3, Vec3){
     let mut col = Vec3::ONE.scaled(AMBIENT);
     let mut spec = Vec3::ZERO;
     for light in &scene.lights{
-        let res = blinn_single(roughness, light.pos, light.intensity, viewdir, hit, scene);
+        let res = blinn_single(roughness, light, viewdir, hit, scene, time, smp);
         col.add(light.col.scaled(res.0));
         spec.add(light.col.scaled(res.1));
     }
+    if let Some(sky) = &scene.procedural_sky{
+        let c = sky.coefficients();
+        let sun_col = c.sun_radiance.scaled(sky.sun_solid_angle());
+        let to_l = random_cone_dir(c.sun_dir, c.sun_cos, smp);
+        let res = blinn_directional(roughness, to_l, viewdir, hit, scene, time);
+        col.add(sun_col.scaled(res.0));
+        spec.add(sun_col.scaled(res.1));
+    }
+    for light in &scene.area_lights{
+        let (lcol, res) = blinn_area(roughness, light, viewdir, hit, scene, time, smp);
+        col.add(lcol.scaled(res.0));
+        spec.add(lcol.scaled(res.1));
+    }
     (col.muled(mat.col), spec.scaled(1.0 - roughness))
 }
 
+// get diffuse light strength for hit for one random point on an area light
+// converges to soft shadows over the progressive anti aliasing passes
+#[inline]
+fn blinn_area(roughness: f32, light: &Primitive, viewdir: Vec3, hit: &RayHit, scene: &Scene, time: f32, smp: &mut Sampler) -> (Vec3, (f32, f32)){
+    let (lpos, lnor, area, lmat) = match light.shape_type{
+        Shape::SPHERE => { // point on the half of the sphere that faces the hit
+            let sphere = &scene.spheres[light.index];
+            let mut nor = random_sphere_point(smp);
+            if nor.dot(hit.pos.subed(sphere.pos)) < 0.0 { nor.neg(); }
+            (sphere.pos.added(nor.scaled(sphere.rad)), nor, 2.0 * PI * sphere.rad * sphere.rad, sphere.mat)
+        },
+        Shape::TRIANGLE => { // uniform point on the triangle
+            let tri = &scene.triangles[light.index];
+            let su = smp.sample().sqrt();
+            let v = smp.sample();
+            let edge1 = tri.b.subed(tri.a);
+            let edge2 = tri.c.subed(tri.a);
+            let pos = tri.a.added(edge1.scaled(su * (1.0 - v))).added(edge2.scaled(su * v));
+            let cross = edge1.crossed(edge2);
+            let len = cross.len();
+            (pos, cross.scaled(1.0 / len), 0.5 * len, tri.mat)
+        },
+        Shape::MODEL => return (Vec3::ZERO, (0.0, 0.0)),
+    };
+    let mat = &scene.mats[lmat as usize];
+    let mut to_l = lpos.subed(hit.pos);
+    let dist = to_l.len();
+    to_l.scale(1.0 / (dist + EPSILON));
+    // diffuse
+    let angle = Vec3::dot(hit.nor, to_l);
+    if angle < EPSILON{
+        return (Vec3::ZERO, (0.0, 0.0));
+    }
+    // area over pdf of the sample, projected on the hemisphere of the hit
+    let power = area * lnor.dot(to_l).abs() / (PI * dist * dist);
+    if power < EPSILON{
+        return (Vec3::ZERO, (0.0, 0.0));
+    }
+    // exposed to light or not, the light itself is hit at dist
+    let lray = Ray { pos: hit.pos.added(hit.nor.scaled(EPSILON)), dir: to_l, time };
+    if scene.top_bvh.occluded(lray, scene, dist - 2.0 * EPSILON){
+        return (Vec3::ZERO, (0.0, 0.0));
+    }
+    // specular
+    let halfdir = Vec3::normalized_fast(to_l.subed(viewdir));
+    let specangle = Vec3::dot(halfdir, hit.nor).max(0.0);
+    let spec = specangle.powf(16.0 / roughness);
+    (mat.col.scaled(mat.emittance), (angle * power, spec * power))
+}
+
+// get diffuse light strength for hit for a light infinitely far away
+#[inline]
+fn blinn_directional(roughness: f32, to_l: Vec3, viewdir: Vec3, hit: &RayHit, scene: &Scene, time: f32) -> (f32, f32){
+    let angle = Vec3::dot(hit.nor, to_l);
+    if angle < EPSILON{
+        return (0.0, 0.0);
+    }
+    let lray = Ray { pos: hit.pos.added(hit.nor.scaled(EPSILON)), dir: to_l, time };
+    if scene.top_bvh.occluded(lray, scene, MAX_RENDER_DIST){
+        return (0.0, 0.0);
+    }
+    let halfdir = Vec3::normalized_fast(to_l.subed(viewdir));
+    let specangle = Vec3::dot(halfdir, hit.nor).max(0.0);
+    let spec = specangle.powf(16.0 / roughness);
+    (angle, spec)
+}
+
 // get diffuse light strength for hit for a light
 #[inline]
-fn blinn_single(roughness: f32, lpos: Vec3, lpow: f32, viewdir: Vec3, hit: &RayHit, scene: &Scene) -> (f32, f32){
-    let mut to_l = Vec3::subed(lpos, hit.pos);
+fn blinn_single(roughness: f32, light: &Light, viewdir: Vec3, hit: &RayHit, scene: &Scene, time: f32, smp: &mut Sampler) -> (f32, f32){
+    if light.ltype == LightType::Directional{
+        // a random direction within the light's disk gives soft shadows over the aa passes
+        let to_l = random_cone_dir(light.dir.neged(), light.angle.cos(), smp);
+        let (diff, spec) = blinn_directional(roughness, to_l, viewdir, hit, scene, time);
+        return (diff * light.intensity, spec * light.intensity);
+    }
+    let mut to_l = Vec3::subed(light.pos, hit.pos);
     let dist = Vec3::len(to_l);
     to_l.scale(1.0 / (dist + EPSILON));
     // diffuse
@@ -503,12 +768,15 @@ fn blinn_single(roughness: f32, lpos: Vec3, lpow: f32, viewdir: Vec3, hit: &RayH
         return (0.0, 0.0);
     }
     angle = angle.max(0.0);
-    let power = lpow / (PI * 4.0 * dist * dist);
+    let mut power = light.intensity / (PI * 4.0 * dist * dist);
+    if light.ltype == LightType::Spot{
+        power *= spot_factor(light, to_l);
+    }
     if power < EPSILON{
         return (0.0, 0.0);
     }
     // exposed to light or not
-    let lray = Ray { pos: hit.pos.added(hit.nor.scaled(EPSILON)), dir: to_l };
+    let lray = Ray { pos: hit.pos.added(hit.nor.scaled(EPSILON)), dir: to_l, time };
 
     if scene.top_bvh.occluded(lray, scene, dist){
         return (0.0, 0.0);
@@ -520,6 +788,16 @@ fn blinn_single(roughness: f32, lpos: Vec3, lpow: f32, viewdir: Vec3, hit: &RayH
     (angle * power, spec * power)
 }
 
+// smooth falloff from the inner to the outer cone of a spot light
+#[inline]
+fn spot_factor(light: &Light, to_l: Vec3) -> f32{
+    let cos_a = light.dir.dot(to_l.neged());
+    let cos_outer = light.angle.cos();
+    let cos_inner = light.inner_angle.cos();
+    let t = ((cos_a - cos_outer) / (cos_inner - cos_outer).max(EPSILON)).clamp(0.0, 1.0);
+    t.powf(light.falloff)
+}
+
 // UV's ------------------------------------------------------------
 
 // plane uv
@@ -600,6 +878,9 @@ fn uv_to_xy(uv: (f32, f32), tex: u32, tps: &[u32]) -> (u32, f32, f32){
 // get sky colour
 #[inline]
 fn get_sky_col(nor: Vec3, scene: &Scene, tps: &[u32], ts: &[u8]) -> Vec3{
+    if let Some(sky) = &scene.procedural_sky{
+        return sky.radiance(nor, true);
+    }
     if scene.sky_box == 0{
         return scene.sky_col;
     }
@@ -906,13 +1187,15 @@ mod test {
     fn ray_negated_dir() {
         let ray = Ray {
             pos: Vec3::ZERO,
-            dir: Vec3::ONE.normalized()
+            dir: Vec3::ONE.normalized(),
+            time: 0.0,
         };
         assert_eq!(ray.direction_negations(), [0,0,0]);
 
         let ray = Ray {
             pos: Vec3::ZERO,
-            dir: Vec3::ONE.neged().normalized()
+            dir: Vec3::ONE.neged().normalized(),
+            time: 0.0,
         };
         assert_eq!(ray.direction_negations(), [1,1,1]);
     }
@@ -921,7 +1204,8 @@ mod test {
     fn ray_inv_dir() {
         let ray = Ray {
             pos: Vec3::ZERO,
-            dir: Vec3::ONE.normalized()
+            dir: Vec3::ONE.normalized(),
+            time: 0.0,
         };
         let inv_dir = ray.inverted().dir;
         assert!(inv_dir.x > 1.0 && inv_dir.x < 2.0);
@@ -930,7 +1214,8 @@ mod test {
 
         let ray = Ray {
             pos: Vec3::ZERO,
-            dir: Vec3::FORWARD
+            dir: Vec3::FORWARD,
+            time: 0.0,
         };
         let inv_dir = ray.inverted().dir;
         assert!(inv_dir.x > 1.0 && inv_dir.x == f32::INFINITY);
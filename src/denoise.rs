@@ -0,0 +1,121 @@
+use crate::vec3::Vec3;
+
+// Edge-avoiding a-trous wavelet filter (Dammertz et al. 2010), mirrored by the kernel.
+// The filter is guided by the albedo, normal and depth of the first hit. The albedo is
+// divided out before filtering and multiplied back after, so textures stay sharp.
+
+// floats per pixel in the guide buffer: albedo, normal, depth and the direct light of the aovs
+pub const AUX_SIZE: usize = 10;
+// exponent on the cosine between normals
+pub const DENOISE_SIGMA_NORMAL: f32 = 64.0;
+// tolerated depth difference relative to the depth of the pixel, per pixel of step
+pub const DENOISE_SIGMA_DEPTH: f32 = 0.05;
+// keeps the demodulation of black albedo finite
+pub const DENOISE_MIN_ALBEDO: f32 = 0.01;
+
+const KERNEL: [f32; 3] = [0.375, 0.25, 0.0625];
+
+struct Guides{
+    albedo: Vec<Vec3>,
+    normal: Vec<Vec3>,
+    depth: Vec<f32>,
+}
+
+// denoise a float map and guide buffer accumulated over samples, laid out like the kernel's buffers.
+// The colour tolerance is halved every pass and shrinks as the noise goes down with more samples.
+pub fn denoise_floatmap(floatmap: &[f32], auxmap: &[f32], w: usize, h: usize, samples: usize, passes: u32, sigma: f32) -> Vec<f32>{
+    let inv = 1.0 / samples.max(1) as f32;
+    let fetch = |map: &[f32], stride: usize, off: usize, i: usize|
+        Vec3::new(map[i * stride + off], map[i * stride + off + 1], map[i * stride + off + 2]).scaled(inv);
+    let guides = Guides{
+        albedo: (0..w * h).map(|i| fetch(auxmap, AUX_SIZE, 0, i).clamped(DENOISE_MIN_ALBEDO, f32::MAX)).collect(),
+        normal: (0..w * h).map(|i| fetch(auxmap, AUX_SIZE, 3, i)).collect(),
+        depth: (0..w * h).map(|i| auxmap[i * AUX_SIZE + 6] * inv).collect(),
+    };
+    let mut col: Vec<Vec3> = (0..w * h).map(|i| fetch(floatmap, 3, 0, i)).collect();
+    for pass in 0..passes{
+        let s = sigma * inv.sqrt() * 0.5f32.powi(pass as i32);
+        col = atrous_pass(&col, &guides, w, h, 1 << pass, s);
+    }
+    col.iter().flat_map(|c| c.as_array()).collect()
+}
+
+fn atrous_pass(col: &[Vec3], guides: &Guides, w: usize, h: usize, step: i32, sigma: f32) -> Vec<Vec3>{
+    let mut res = vec![Vec3::ZERO; w * h];
+    for y in 0..h{
+    for x in 0..w{
+        let p = x + y * w;
+        let alb_p = guides.albedo[p];
+        let nor_p = guides.normal[p];
+        let z_p = guides.depth[p];
+        let col_p = col[p].dived_fast(alb_p);
+        let mut sum = Vec3::ZERO;
+        let mut wsum = 0.0;
+        for dy in -2i32..=2{
+        for dx in -2i32..=2{
+            let qx = (x as i32 + dx * step).clamp(0, w as i32 - 1) as usize;
+            let qy = (y as i32 + dy * step).clamp(0, h as i32 - 1) as usize;
+            let q = qx + qy * w;
+            let col_q = col[q].dived_fast(guides.albedo[q]);
+            let dc = col_p.subed(col_q);
+            let wc = (-dc.dot(dc) / (sigma * sigma + f32::EPSILON)).exp();
+            let wn = if dx == 0 && dy == 0 { 1.0 } else {
+                nor_p.dot(guides.normal[q]).max(0.0).powf(DENOISE_SIGMA_NORMAL)
+            };
+            let wz = (-(z_p - guides.depth[q]).abs() / (DENOISE_SIGMA_DEPTH * z_p * step as f32 + f32::EPSILON)).exp();
+            let weight = KERNEL[dx.unsigned_abs() as usize] * KERNEL[dy.unsigned_abs() as usize] * wc * wn * wz;
+            sum.add(col_q.scaled(weight));
+            wsum += weight;
+        }
+        }
+        res[p] = sum.scaled(1.0 / wsum).muled(alb_p);
+    }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test{
+    use crate::denoise::*;
+
+    fn flat_guides(w: usize, h: usize, samples: usize) -> Vec<f32>{
+        let mut aux = Vec::new();
+        for _ in 0..w * h{
+            aux.extend_from_slice(&[0.5, 0.5, 0.5, 0.0, 1.0, 0.0, 2.0, 0.25, 0.25, 0.25]);
+        }
+        aux.iter().map(|v| v * samples as f32).collect()
+    }
+
+    #[test]
+    fn smooths_noise_on_a_plane(){
+        let (w, h) = (16, 16);
+        let mut seed = 7u32;
+        let floatmap: Vec<f32> = (0..w * h * 3).map(|_| {
+            seed ^= seed << 13; seed ^= seed >> 17; seed ^= seed << 5;
+            0.5 + (seed % 1000) as f32 / 2000.0 - 0.25
+        }).collect();
+        let res = denoise_floatmap(&floatmap, &flat_guides(w, h, 1), w, h, 1, 3, 1.0);
+        let var = |m: &[f32]| m.iter().map(|v| (v - 0.5) * (v - 0.5)).sum::<f32>();
+        assert!(var(&res) < var(&floatmap) * 0.25);
+    }
+
+    #[test]
+    fn keeps_depth_edges(){
+        let (w, h) = (8, 8);
+        let samples = 4;
+        let mut aux = flat_guides(w, h, samples);
+        let mut floatmap = vec![0.0; w * h * 3];
+        for y in 0..h{
+        for x in 0..w{
+            let i = x + y * w;
+            let far = x >= w / 2;
+            if far { aux[i * AUX_SIZE + 6] = 20.0 * samples as f32; }
+            let v = if far { 0.0 } else { samples as f32 };
+            floatmap[i * 3..i * 3 + 3].copy_from_slice(&[v, v, v]);
+        }
+        }
+        let res = denoise_floatmap(&floatmap, &aux, w, h, samples, 3, 10.0);
+        assert!((res[(w / 2 - 1) * 3] - 1.0).abs() < 0.01);
+        assert!(res[(w / 2) * 3].abs() < 0.01);
+    }
+}
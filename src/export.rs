@@ -0,0 +1,215 @@
+use crate::config::{ ExportParsed, ExportFormat };
+use crate::consts::GAMMA;
+use crate::aov::Aov;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{ BufWriter, Write };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+// Frame export. The displayed frame is packed like the image map, the linear frame
+// is the accumulation divided by the samples, three floats per pixel, top row first.
+
+// write the frame in every configured format, linear may be empty if no format needs it.
+// The aovs are written next to it in the float formats, as exr if none is configured.
+pub fn export_frame(settings: &ExportParsed, w: u32, h: u32, tex: &[u32], linear: &[f32], aovs: &[(Aov, Vec<f32>)], samples: usize){
+    let base = expand_path(&settings.path, samples);
+    if let Some(dir) = Path::new(&base).parent(){
+        if !dir.as_os_str().is_empty(){
+            if let Err(e) = std::fs::create_dir_all(dir){
+                println!("Could not create export directory {:?}: {}", dir, e);
+                return;
+            }
+        }
+    }
+    for format in &settings.formats{
+        let filename = format!("{}.{}", base, extension(*format));
+        let file = match File::create(&filename){
+            Ok(file) => file,
+            Err(e) => { println!("Could not open file {} for frame image: {}", filename, e); continue; },
+        };
+        let writer = BufWriter::new(file);
+        let (w, h) = (w as usize, h as usize);
+        let res = match format{
+            ExportFormat::Png => write_png(writer, w, h, tex),
+            ExportFormat::Png16 => write_png16(writer, w, h, linear),
+            ExportFormat::Pfm => write_pfm(writer, w, h, linear),
+            ExportFormat::Exr => write_exr(writer, w, h, linear),
+        };
+        match res{
+            Ok(()) => println!("Frame exported to {}!", filename),
+            Err(e) => println!("Could not save frame image {}: {}", filename, e),
+        }
+    }
+    let mut float_formats: Vec<ExportFormat> = settings.formats.iter().copied()
+        .filter(|f| *f == ExportFormat::Pfm || *f == ExportFormat::Exr).collect();
+    if float_formats.is_empty(){
+        float_formats.push(ExportFormat::Exr);
+    }
+    for (aov, values) in aovs{
+        for format in &float_formats{
+            let filename = format!("{}.{}.{}", base, aov.name(), extension(*format));
+            let file = match File::create(&filename){
+                Ok(file) => file,
+                Err(e) => { println!("Could not open file {} for aov image: {}", filename, e); continue; },
+            };
+            let writer = BufWriter::new(file);
+            let (w, h) = (w as usize, h as usize);
+            let res = if *format == ExportFormat::Pfm {
+                write_pfm(writer, w, h, values)
+            } else {
+                write_exr(writer, w, h, values)
+            };
+            match res{
+                Ok(()) => println!("Aov exported to {}!", filename),
+                Err(e) => println!("Could not save aov image {}: {}", filename, e),
+            }
+        }
+    }
+}
+
+// the shown frame as png, named by the time
+pub fn save_screenshot(w: u32, h: u32, tex: &[u32]){
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let filename = format!("screenshot_{}.png", millis);
+    let res = File::create(&filename).map_err(|e| e.to_string())
+        .and_then(|file| write_png(BufWriter::new(file), w as usize, h as usize, tex));
+    match res{
+        Ok(()) => println!("Screenshot saved to {}!", filename),
+        Err(e) => println!("Could not save screenshot {}: {}", filename, e),
+    }
+}
+
+pub fn needs_linear(settings: &ExportParsed) -> bool{
+    settings.formats.iter().any(|f| *f != ExportFormat::Png)
+}
+
+pub fn extension(format: ExportFormat) -> &'static str{
+    match format{
+        ExportFormat::Png => "png",
+        ExportFormat::Png16 => "16.png",
+        ExportFormat::Pfm => "pfm",
+        ExportFormat::Exr => "exr",
+    }
+}
+
+// replaces {time} by the unix time in seconds and {samples} by the samples taken
+pub fn expand_path(pattern: &str, samples: usize) -> String{
+    let time = match SystemTime::now().duration_since(UNIX_EPOCH){
+        Ok(n) => n.as_secs(),
+        Err(_) => 0,
+    };
+    pattern.replace("{time}", &time.to_string()).replace("{samples}", &samples.to_string())
+}
+
+pub fn write_png(out: impl Write, w: usize, h: usize, tex: &[u32]) -> Result<(), String>{
+    let mut transformed = vec![0; w * h * 3];
+    for (i, int) in tex.iter().take(w * h).enumerate(){
+        transformed[i * 3    ] = ((int & 0x00ff0000) >> 16) as u8;
+        transformed[i * 3 + 1] = ((int & 0x0000ff00) >> 8) as u8;
+        transformed[i * 3 + 2] = (int & 0x000000ff) as u8;
+    }
+    encode_png(out, w, h, png::BitDepth::Eight, &transformed)
+}
+
+// the linear frame with gamma, clamped, no tone mapping
+pub fn write_png16(out: impl Write, w: usize, h: usize, linear: &[f32]) -> Result<(), String>{
+    let data: Vec<u8> = linear.iter().take(w * h * 3).flat_map(|v| {
+        let v = (v.clamp(0.0, 1.0).powf(1.0 / GAMMA) * 65535.0 + 0.5) as u16;
+        v.to_be_bytes()
+    }).collect();
+    encode_png(out, w, h, png::BitDepth::Sixteen, &data)
+}
+
+fn encode_png(out: impl Write, w: usize, h: usize, depth: png::BitDepth, data: &[u8]) -> Result<(), String>{
+    let mut encoder = png::Encoder::new(out, w as u32, h as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(depth);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(data).map_err(|e| e.to_string())
+}
+
+// portable float map, little endian, bottom row first
+pub fn write_pfm(mut out: impl Write, w: usize, h: usize, linear: &[f32]) -> Result<(), String>{
+    let mut bytes = format!("PF\n{} {}\n-1.0\n", w, h).into_bytes();
+    for y in (0..h).rev(){
+        for v in &linear[y * w * 3..(y + 1) * w * 3]{
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    out.write_all(&bytes).map_err(|e| e.to_string())
+}
+
+// single part scanline OpenEXR without compression, 32 bit float channels
+pub fn write_exr(mut out: impl Write, w: usize, h: usize, linear: &[f32]) -> Result<(), String>{
+    fn attribute(header: &mut Vec<u8>, name: &str, atype: &str, value: &[u8]){
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(atype.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    }
+    let ints = |v: &[i32]| v.iter().flat_map(|i| i.to_le_bytes()).collect::<Vec<u8>>();
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    // channels are stored in alphabetical order
+    let mut chlist = Vec::new();
+    for name in &["B", "G", "R"]{
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&ints(&[2])); // float
+        chlist.extend_from_slice(&[0, 0, 0, 0]); // linear flag and reserved
+        chlist.extend_from_slice(&ints(&[1, 1])); // sampling
+    }
+    chlist.push(0);
+    let window = ints(&[0, 0, w as i32 - 1, h as i32 - 1]);
+    attribute(&mut header, "channels", "chlist", &chlist);
+    attribute(&mut header, "compression", "compression", &[0]);
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+    // offset table, one chunk per scanline
+    let line = w * 3 * 4;
+    let start = header.len() + h * 8;
+    for y in 0..h{
+        header.extend_from_slice(&((start + y * (8 + line)) as u64).to_le_bytes());
+    }
+    for y in 0..h{
+        header.extend_from_slice(&ints(&[y as i32, line as i32]));
+        for c in (0..3).rev(){
+            for x in 0..w{
+                header.extend_from_slice(&linear[(x + y * w) * 3 + c].to_le_bytes());
+            }
+        }
+    }
+    out.write_all(&header).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test{
+    use crate::export::*;
+
+    #[test]
+    fn float_formats_have_expected_layout(){
+        let (w, h) = (3, 2);
+        let linear: Vec<f32> = (0..w * h * 3).map(|i| i as f32).collect();
+        let mut pfm = Vec::new();
+        write_pfm(&mut pfm, w, h, &linear).unwrap();
+        assert!(pfm.starts_with(b"PF\n3 2\n-1.0\n"));
+        // bottom row first
+        let first = f32::from_le_bytes([pfm[12], pfm[13], pfm[14], pfm[15]]);
+        assert_eq!(first, linear[w * 3]);
+        let mut exr = Vec::new();
+        write_exr(&mut exr, w, h, &linear).unwrap();
+        assert_eq!(&exr[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        // the last value is the red of the last pixel
+        let n = exr.len();
+        let last = f32::from_le_bytes([exr[n - 4], exr[n - 3], exr[n - 2], exr[n - 1]]);
+        assert_eq!(last, linear[w * h * 3 - 3]);
+        assert_eq!(expand_path("out/frame_{samples}", 64), "out/frame_64");
+    }
+}
@@ -0,0 +1,139 @@
+use crate::state::{ State, RenderMode };
+use crate::scene::{ Scene, RenderType };
+
+// Text overlay of the window: lines of a 5x7 bitmap font drawn into an image on a dark box,
+// blitted over the frame. Lower case is shown as upper case, unknown characters as a question mark.
+
+const GLYPH_W: usize = 5;
+const GLYPH_H: usize = 7;
+// pixels between characters and between lines, before scaling
+const SPACING: usize = 1;
+const PADDING: usize = 2;
+pub const HUD_SCALE: usize = 2;
+const TEXT_COLOUR: u32 = 0xffffff;
+const BACKGROUND: u32 = 0x202020;
+
+// rows from the top, the left pixel is bit four
+const GLYPHS: [(char, [u8; GLYPH_H]); 50] = [
+    (' ', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+    ('0', [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E]),
+    ('1', [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('2', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F]),
+    ('3', [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E]),
+    ('4', [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02]),
+    ('5', [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E]),
+    ('6', [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E]),
+    ('7', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+    ('8', [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E]),
+    ('9', [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C]),
+    ('A', [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11]),
+    ('B', [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E]),
+    ('C', [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E]),
+    ('D', [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C]),
+    ('E', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F]),
+    ('F', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10]),
+    ('G', [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F]),
+    ('H', [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('I', [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('J', [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C]),
+    ('K', [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11]),
+    ('L', [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F]),
+    ('M', [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11]),
+    ('N', [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11]),
+    ('O', [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('P', [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10]),
+    ('Q', [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D]),
+    ('R', [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11]),
+    ('S', [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E]),
+    ('T', [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04]),
+    ('U', [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('V', [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04]),
+    ('W', [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A]),
+    ('X', [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11]),
+    ('Y', [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04]),
+    ('Z', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F]),
+    ('.', [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C]),
+    (',', [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08]),
+    (':', [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00]),
+    ('-', [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00]),
+    ('+', [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00]),
+    ('=', [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00]),
+    ('/', [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00]),
+    ('%', [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03]),
+    ('(', [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02]),
+    (')', [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08]),
+    ('[', [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E]),
+    (']', [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E]),
+    ('?', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04]),
+];
+
+fn glyph(c: char) -> &'static [u8; GLYPH_H]{
+    let c = c.to_ascii_uppercase();
+    let unknown = &GLYPHS[GLYPHS.len() - 1].1;
+    GLYPHS.iter().find(|(g, _)| *g == c).map(|(_, rows)| rows).unwrap_or(unknown)
+}
+
+// the lines drawn on a box, top row first like the frame; width, height and pixels
+pub fn text_image(lines: &[String], scale: usize) -> (usize, usize, Vec<u32>){
+    let cols = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let w = (PADDING * 2 + cols * (GLYPH_W + SPACING)) * scale;
+    let h = (PADDING * 2 + lines.len() * (GLYPH_H + SPACING)) * scale;
+    let mut pixels = vec![BACKGROUND; w * h];
+    for (row, line) in lines.iter().enumerate(){
+        for (col, c) in line.chars().enumerate(){
+            let (x0, y0) = (PADDING + col * (GLYPH_W + SPACING), PADDING + row * (GLYPH_H + SPACING));
+            for (y, bits) in glyph(c).iter().enumerate(){
+                for x in 0..GLYPH_W{
+                    if bits & (1 << (GLYPH_W - 1 - x)) == 0 { continue; }
+                    for (sx, sy) in (0..scale * scale).map(|i| (i % scale, i / scale)){
+                        pixels[((x0 + x) * scale + sx) + ((y0 + y) * scale + sy) * w] = TEXT_COLOUR;
+                    }
+                }
+            }
+        }
+    }
+    (w, h, pixels)
+}
+
+// what the overlay shows; (w, h) is the size the frame is rendered at
+pub fn hud_lines(state: &State, scene: &Scene, (w, h): (u32, u32), memory: &str) -> Vec<String>{
+    let ms = state.frame_ms.max(0.001);
+    // primary rays, the reduced frames trace fewer pixels
+    let rays = match state.last_frame{
+        RenderMode::Full => w as f32 * h as f32,
+        RenderMode::Reduced => w as f32 * h as f32 / (state.reduced_rate * state.reduced_rate) as f32,
+        RenderMode::None => 0.0,
+    };
+    let p = scene.cam.pos;
+    let mut lines = vec![
+        format!("{:.1} fps ({:.1} ms)", 1000.0 / ms, ms),
+        format!("samples: {}", state.samples_taken),
+        format!("mode: {:?}, reduced rate: {}", state.last_frame, state.reduced_rate),
+        format!("resolution: {}x{}", w, h),
+        format!("camera: {:.2} {:.2} {:.2}", p.x, p.y, p.z),
+        format!("{:.2} mrays/s", rays / ms / 1000.0),
+        memory.to_string(),
+    ];
+    if state.settings.render_type == RenderType::GI && state.settings.calc_frame_energy{
+        lines.push(format!("frame energy: {:.3}", state.frame_energy));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test{
+    use crate::hud::*;
+
+    #[test]
+    fn draws_lines_on_a_box(){
+        let lines = vec!["1".to_string(), "a?b~".to_string()];
+        let (w, h, pixels) = text_image(&lines, 1);
+        assert_eq!(w, PADDING * 2 + 4 * (GLYPH_W + SPACING));
+        assert_eq!(h, PADDING * 2 + 2 * (GLYPH_H + SPACING));
+        // the top of the one and the corner of the box
+        assert_eq!(pixels[PADDING + 2 + PADDING * w], TEXT_COLOUR);
+        assert_eq!(pixels[0], BACKGROUND);
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('~'), glyph('?'));
+    }
+}
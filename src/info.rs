@@ -42,6 +42,16 @@ impl Info{
         println!("Total: {} ms.", last);
     }
 
+    // one line of the sizes for the overlay
+    pub fn memory_summary(&self) -> String{
+        let textures: u64 = self.textures.iter().map(|(_, size)| size).sum();
+        let frame = self.int_buffer_size + self.float_buffer_size;
+        let total = textures + frame + self.meta_size + self.scene_size + self.bvh_size;
+        format!("scene: {}, bvh: {}, frame: {}, textures: {}, total: {}",
+            Self::format_size(self.scene_size), Self::format_size(self.bvh_size), Self::format_size(frame),
+            Self::format_size(textures), Self::format_size(total))
+    }
+
     pub fn print_size_verbose(size: u64){
         println!("\t{} B.", size);
         println!("\t{} KB.", size / 1024);
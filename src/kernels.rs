@@ -2,6 +2,14 @@ use crate::cl_helpers::{ ClBufferRW, ClBufferR };
 use crate::scene::Scene;
 use crate::info::Info;
 use crate::state::State;
+use crate::misc::make_nonzero_len;
+use crate::denoise::AUX_SIZE;
+use crate::adaptive::{ STAT_SIZE, noise_level, luminance };
+use crate::sampler::{ SamplerType, blue_noise_mask };
+use crate::post::{ PostStack, meter_exposure };
+use crate::vec3::Vec3;
+use crate::aov::{ Aov, ID_SIZE, NO_ID, aov_value };
+use crate::material::MaterialIndex;
 
 use ocl::{ Kernel, Program, Queue };
 
@@ -22,13 +30,15 @@ pub struct ClearKernel{
 }
 
 impl ClearKernel{
-    pub fn new(name: &str, (w, h): (u32, u32), program: &Program, queue: &Queue, buffer: &ClBufferRW<f32>) -> Result<Self, ocl::Error>{
+    pub fn new(name: &str, (w, h): (u32, u32), program: &Program, queue: &Queue, buffer: &ClBufferRW<f32>, aux: &ClBufferRW<f32>, stat: &ClBufferRW<f32>) -> Result<Self, ocl::Error>{
         let kernel = Kernel::builder()
             .program(program)
             .name(name)
             .queue(queue.clone())
             .global_work_size([w, h])
             .arg(buffer.get_ocl_buffer())
+            .arg(aux.get_ocl_buffer())
+            .arg(stat.get_ocl_buffer())
             .arg(w)
             .build()?;
         Result::Ok(Self{ kernel })
@@ -46,13 +56,22 @@ impl VoidKernel for ClearKernel{
 pub struct ImageKernel{
     kernel: Kernel,
     buffer: ClBufferRW<u32>,
+    post: ClBufferR<f32>,
     dirty: bool,
 }
 
 impl ImageKernel{
-    pub fn new(name: &str, (w, h): (u32, u32), tm: u32, program: &Program, queue: &Queue, input: &ClBufferRW<f32>) -> Result<Self, ocl::Error>{
+    // runs the post stack, bloom is the result of the bloom kernel if the stack has bloom
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(name: &str, (w, h): (u32, u32), stack: &PostStack, program: &Program, queue: &Queue,
+               input: &ClBufferRW<f32>, bloom: Option<&ClBufferRW<f32>>) -> Result<Self, ocl::Error>{
         let dirty = false;
         let buffer = ClBufferRW::<u32>::new(queue, w as usize * h as usize, 0)?;
+        let post_raw = stack.get_data(0.0);
+        let mut lut_raw = stack.get_lut_buffer();
+        make_nonzero_len(&mut lut_raw);
+        let mut post = ClBufferR::new(queue, post_raw.len(), 0.0)?;
+        let mut lut = ClBufferR::new(queue, lut_raw.len(), 0.0)?;
         let kernel = Kernel::builder()
             .program(program)
             .name(name)
@@ -62,15 +81,31 @@ impl ImageKernel{
             .arg(buffer.get_ocl_buffer())
             .arg(w as u32)
             .arg(1.0f32) // scaling
-            .arg(tm) // tonemap
+            .arg(h)
+            .arg(post.get_ocl_buffer())
+            .arg(bloom.unwrap_or(input).get_ocl_buffer())
+            .arg(lut.get_ocl_buffer())
+            .arg(0u32) // frame, seeds the grain
             .build()?;
-        Ok(Self{ buffer, kernel, dirty })
+        post.upload_new(queue, &post_raw)?;
+        lut.upload_new(queue, &lut_raw)?;
+        Ok(Self{ buffer, kernel, post, dirty })
     }
 
     pub fn set_divider(&mut self, div: f32) -> Result<(), ocl::Error>{
         self.kernel.set_arg(3, 1.0 / div)?;
         Ok(())
     }
+
+    pub fn set_frame(&mut self, frame: u32) -> Result<(), ocl::Error>{
+        self.kernel.set_arg(8, frame)?;
+        Ok(())
+    }
+
+    // new parameters of the stack, metered is the exposure of auto exposure
+    pub fn set_post(&mut self, queue: &Queue, stack: &PostStack, metered: f32) -> Result<(), ocl::Error>{
+        self.post.upload_new(queue, &stack.get_data(metered))
+    }
 }
 
 impl VoidKernel for ImageKernel{
@@ -97,23 +132,314 @@ impl ResultKernel<u32> for ImageKernel{
     }
 }
 
+pub struct BloomKernel{
+    extract: Kernel,
+    blur: [Kernel; 2],
+    buffers: [ClBufferRW<f32>; 2],
+    post: ClBufferR<f32>,
+}
+
+impl BloomKernel{
+    // bright pass into the first buffer, blurred horizontally into the second and vertically back
+    pub fn new((w, h): (u32, u32), stack: &PostStack, program: &Program, queue: &Queue, input: &ClBufferRW<f32>) -> Result<Self, ocl::Error>{
+        let size = w as usize * h as usize * 3;
+        let buffers = [ClBufferRW::<f32>::new(queue, size, 0.0)?, ClBufferRW::<f32>::new(queue, size, 0.0)?];
+        let post_raw = stack.get_data(0.0);
+        let mut post = ClBufferR::new(queue, post_raw.len(), 0.0)?;
+        let extract = Kernel::builder()
+            .program(program)
+            .name("bloom_extract")
+            .queue(queue.clone())
+            .global_work_size([w, h])
+            .arg(input.get_ocl_buffer())
+            .arg(buffers[0].get_ocl_buffer())
+            .arg(w)
+            .arg(1.0f32) // input scaling
+            .arg(post.get_ocl_buffer())
+            .build()?;
+        let blur = |from: &ClBufferRW<f32>, to: &ClBufferRW<f32>, dx: i32, dy: i32| Kernel::builder()
+            .program(program)
+            .name("bloom_blur")
+            .queue(queue.clone())
+            .global_work_size([w, h])
+            .arg(from.get_ocl_buffer())
+            .arg(to.get_ocl_buffer())
+            .arg(w)
+            .arg(h)
+            .arg(post.get_ocl_buffer())
+            .arg(dx)
+            .arg(dy)
+            .build();
+        let blur = [blur(&buffers[0], &buffers[1], 1, 0)?, blur(&buffers[1], &buffers[0], 0, 1)?];
+        post.upload_new(queue, &post_raw)?;
+        Ok(Self{ extract, blur, buffers, post })
+    }
+
+    pub fn set_divider(&mut self, div: f32) -> Result<(), ocl::Error>{
+        self.extract.set_arg(3, 1.0 / div)?;
+        Ok(())
+    }
+
+    pub fn set_post(&mut self, queue: &Queue, stack: &PostStack, metered: f32) -> Result<(), ocl::Error>{
+        self.post.upload_new(queue, &stack.get_data(metered))
+    }
+}
+
+impl VoidKernel for BloomKernel{
+    fn execute(&mut self, queue: &Queue) -> Result<(), ocl::Error>{
+        unsafe {
+            self.extract.cmd().queue(queue).enq()?;
+            for kernel in &self.blur{
+                kernel.cmd().queue(queue).enq()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BufferKernel<f32> for BloomKernel{
+    fn get_buffer(&self) -> &ClBufferRW<f32>{
+        &self.buffers[0]
+    }
+}
+
+pub struct DenoiseKernel{
+    kernels: Vec<Kernel>,
+    buffers: [ClBufferRW<f32>; 2],
+    sigma: f32,
+}
+
+impl DenoiseKernel{
+    // one a-trous pass per kernel, ping-ponging between two buffers with a doubling step
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(name: &str, (w, h): (u32, u32), passes: u32, sigma: f32, program: &Program, queue: &Queue,
+               input: &ClBufferRW<f32>, aux: &ClBufferRW<f32>) -> Result<Self, ocl::Error>{
+        let size = w as usize * h as usize * 3;
+        let buffers = [ClBufferRW::<f32>::new(queue, size, 0.0)?, ClBufferRW::<f32>::new(queue, size, 0.0)?];
+        let mut kernels = Vec::new();
+        for i in 0..passes.max(1) as usize{
+            let from = if i == 0 { input } else { &buffers[(i - 1) % 2] };
+            let kernel = Kernel::builder()
+                .program(program)
+                .name(name)
+                .queue(queue.clone())
+                .global_work_size([w, h])
+                .arg(from.get_ocl_buffer())
+                .arg(aux.get_ocl_buffer())
+                .arg(buffers[i % 2].get_ocl_buffer())
+                .arg(w)
+                .arg(h)
+                .arg(1i32 << i) // step
+                .arg(1.0f32) // input scaling
+                .arg(1.0f32) // guide scaling
+                .arg(sigma)
+                .build()?;
+            kernels.push(kernel);
+        }
+        Ok(Self{ kernels, buffers, sigma })
+    }
+
+    // the input and guides are accumulated, the colour tolerance shrinks with the noise
+    pub fn set_samples(&mut self, samples: usize) -> Result<(), ocl::Error>{
+        let inv = 1.0 / samples.max(1) as f32;
+        for (i, kernel) in self.kernels.iter_mut().enumerate(){
+            if i == 0{
+                kernel.set_arg(6, inv)?;
+            }
+            kernel.set_arg(7, inv)?;
+            kernel.set_arg(8, self.sigma * inv.sqrt() * 0.5f32.powi(i as i32))?;
+        }
+        Ok(())
+    }
+}
+
+impl VoidKernel for DenoiseKernel{
+    fn execute(&mut self, queue: &Queue) -> Result<(), ocl::Error>{
+        for kernel in &self.kernels{
+            unsafe {
+                kernel.cmd().queue(queue).enq()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BufferKernel<f32> for DenoiseKernel{
+    fn get_buffer(&self) -> &ClBufferRW<f32>{
+        &self.buffers[(self.kernels.len() - 1) % 2]
+    }
+}
+
+pub struct ReprojectKernel{
+    kernel: Kernel,
+    history: ClBufferRW<f32>,
+    history_aux: ClBufferRW<f32>,
+    history_stat: ClBufferRW<f32>,
+}
+
+impl ReprojectKernel{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(name: &str, (w, h): (u32, u32), program: &Program, queue: &Queue, buffer: &ClBufferRW<f32>,
+               aux: &ClBufferRW<f32>, stat: &ClBufferRW<f32>, scene_params: &ClBufferRW<u32>, prev_params: &ClBufferRW<u32>) -> Result<Self, ocl::Error>{
+        let history = ClBufferRW::<f32>::new(queue, w as usize * h as usize * 3, 0.0)?;
+        let history_aux = ClBufferRW::<f32>::new(queue, w as usize * h as usize * AUX_SIZE, 0.0)?;
+        let history_stat = ClBufferRW::<f32>::new(queue, w as usize * h as usize * STAT_SIZE, 0.0)?;
+        let kernel = Kernel::builder()
+            .program(program)
+            .name(name)
+            .queue(queue.clone())
+            .global_work_size([w, h])
+            .arg(buffer.get_ocl_buffer())
+            .arg(aux.get_ocl_buffer())
+            .arg(stat.get_ocl_buffer())
+            .arg(history.get_ocl_buffer())
+            .arg(history_aux.get_ocl_buffer())
+            .arg(history_stat.get_ocl_buffer())
+            .arg(scene_params.get_ocl_buffer())
+            .arg(prev_params.get_ocl_buffer())
+            .arg(w)
+            .arg(h)
+            .arg(1.0f32) // history scaling
+            .arg(1u32) // samples the frame counts as
+            .build()?;
+        Ok(Self{ kernel, history, history_aux, history_stat })
+    }
+
+    // keep the accumulation of the previous view before it is cleared
+    pub fn store_history(&mut self, queue: &Queue, buffer: &ClBufferRW<f32>, aux: &ClBufferRW<f32>, stat: &ClBufferRW<f32>) -> Result<(), ocl::Error>{
+        buffer.copy_to(queue, &self.history)?;
+        aux.copy_to(queue, &self.history_aux)?;
+        stat.copy_to(queue, &self.history_stat)
+    }
+
+    pub fn set_samples(&mut self, history: usize, samples: usize) -> Result<(), ocl::Error>{
+        self.kernel.set_arg(10, 1.0 / history.max(1) as f32)?;
+        self.kernel.set_arg(11, samples as u32)?;
+        Ok(())
+    }
+}
+
+impl VoidKernel for ReprojectKernel{
+    fn execute(&mut self, queue: &Queue) -> Result<(), ocl::Error>{
+        unsafe {
+            self.kernel.cmd().queue(queue).enq()
+        }
+    }
+}
+
+pub struct ConvergenceMaskKernel{
+    kernel: Kernel,
+}
+
+impl ConvergenceMaskKernel{
+    // tints the image of the image kernel, converged pixels green and sampled pixels red
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(name: &str, (w, h): (u32, u32), threshold: f32, program: &Program, queue: &Queue,
+               buffer: &ClBufferRW<f32>, stat: &ClBufferRW<f32>, image: &ClBufferRW<u32>) -> Result<Self, ocl::Error>{
+        let kernel = Kernel::builder()
+            .program(program)
+            .name(name)
+            .queue(queue.clone())
+            .global_work_size([w, h])
+            .arg(buffer.get_ocl_buffer())
+            .arg(stat.get_ocl_buffer())
+            .arg(image.get_ocl_buffer())
+            .arg(w)
+            .arg(1u32) // samples taken
+            .arg(threshold)
+            .build()?;
+        Ok(Self{ kernel })
+    }
+
+    pub fn set_samples(&mut self, samples: usize) -> Result<(), ocl::Error>{
+        self.kernel.set_arg(4, samples as u32)?;
+        Ok(())
+    }
+}
+
+impl VoidKernel for ConvergenceMaskKernel{
+    fn execute(&mut self, queue: &Queue) -> Result<(), ocl::Error>{
+        unsafe {
+            self.kernel.cmd().queue(queue).enq()
+        }
+    }
+}
+
+pub struct AovKernel{
+    kernel: Kernel,
+}
+
+impl AovKernel{
+    // overwrites the image of the image kernel with an aov
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(name: &str, (w, h): (u32, u32), program: &Program, queue: &Queue, buffer: &ClBufferRW<f32>,
+               aux: &ClBufferRW<f32>, ids: &ClBufferRW<u32>, image: &ClBufferRW<u32>) -> Result<Self, ocl::Error>{
+        let kernel = Kernel::builder()
+            .program(program)
+            .name(name)
+            .queue(queue.clone())
+            .global_work_size([w, h])
+            .arg(buffer.get_ocl_buffer())
+            .arg(aux.get_ocl_buffer())
+            .arg(ids.get_ocl_buffer())
+            .arg(image.get_ocl_buffer())
+            .arg(w)
+            .arg(1.0f32) // one over the samples taken
+            .arg(Aov::Beauty as u32)
+            .build()?;
+        Ok(Self{ kernel })
+    }
+
+    pub fn set_samples(&mut self, samples: usize) -> Result<(), ocl::Error>{
+        self.kernel.set_arg(5, 1.0 / samples.max(1) as f32)?;
+        Ok(())
+    }
+
+    pub fn set_aov(&mut self, aov: Aov) -> Result<(), ocl::Error>{
+        self.kernel.set_arg(6, aov as u32)?;
+        Ok(())
+    }
+}
+
+impl VoidKernel for AovKernel{
+    fn execute(&mut self, queue: &Queue) -> Result<(), ocl::Error>{
+        unsafe {
+            self.kernel.cmd().queue(queue).enq()
+        }
+    }
+}
+
+// the texture parameters and items are arguments 7 and 8 of both trace kernels
+fn upload_texture_args(kernel: &Kernel, queue: &Queue, scene: &Scene) -> Result<(), ocl::Error>{
+    let tex_raw = scene.get_textures_buffer();
+    let tex_params_raw = scene.get_texture_params_buffer();
+    let mut tex_params = ClBufferR::new(queue, tex_params_raw.len(), 0)?;
+    let mut tex_items = ClBufferR::new(queue, tex_raw.len(), 0)?;
+    tex_params.upload_new(queue, &tex_params_raw)?;
+    tex_items.upload_new(queue, &tex_raw)?;
+    kernel.set_arg(7, tex_params.get_ocl_buffer())?;
+    kernel.set_arg(8, tex_items.get_ocl_buffer())?;
+    Ok(())
+}
+
 // Wrong name for this struct breaks clippy/compiler
 // If you name it back TraceKernelReal it's fine
 // Crashes with `cargo clippy --all-features` and not with `cargo check`
 pub struct TraceKernelWhitted{
     kernel: Kernel,
-    dirty: bool,
-    buffer: ClBufferRW<u32>,
+    buffer: ClBufferRW<f32>,
     scene_params: ClBufferRW<u32>,
+    scene_items: ClBufferR<f32>,
+    bvh: ClBufferR<u32>,
 }
 
 impl TraceKernelWhitted{
     pub fn new(name: &str, (w, h): (u32, u32), program: &Program, queue: &Queue, scene: &mut Scene, info: &mut Info) -> Result<Self, ocl::Error>{
         info.set_time_point("Start constructing kernel");
-        let dirty = false;
-        let buffer = ClBufferRW::<u32>::new(queue, w as usize * h as usize, 0)?;
-        info.int_buffer_size = w as u64 * h as u64;
-        info.set_time_point("Build int frame buffer");
+        let bsize = w as usize * h as usize * 3;
+        let buffer = ClBufferRW::<f32>::new(queue, bsize, 0.0)?;
+        info.float_buffer_size = bsize as u64;
+        info.set_time_point("Build float frame buffer");
         let scene_params_raw = scene.get_scene_params_buffer();
         let scene_raw = scene.get_scene_buffer();
         let bvh_raw = scene.get_bvh_buffer();
@@ -139,6 +465,7 @@ impl TraceKernelWhitted{
         kbuilder.arg(buffer.get_ocl_buffer());
         kbuilder.arg(w as u32);
         kbuilder.arg(h as u32);
+        kbuilder.arg(0u32); // sample, the first one restarts the accumulation
         kbuilder.arg(scene_params.get_ocl_buffer());
         kbuilder.arg(scene_items.get_ocl_buffer());
         kbuilder.arg(bvh.get_ocl_buffer());
@@ -149,8 +476,9 @@ impl TraceKernelWhitted{
         /*Choice: Either: upload an let ClBufferRW's go out of scope
         Or: store ClBufferRW's in the struct so they still live.
         They will be uploaded automatically then.
-        I choose to upload here and let them go, as i don't need them later on and i can time the uploading.
-        Except the scene_params. It is small and used to change camera etc. */
+        I choose to upload here and let the textures go, as i don't need them later on and i can time the uploading.
+        The scene_params are small and used to change camera etc.
+        The scene items and the bvh are uploaded again when objects are edited. */
         scene_params.upload(queue)?;
         info.set_time_point("Upload scene parameters");
         scene_items.upload_new(queue, &scene_raw)?;
@@ -161,7 +489,7 @@ impl TraceKernelWhitted{
         info.set_time_point("Upload texture parameters");
         tex_items.upload_new(queue, &tex_raw)?;
         info.set_time_point("Upload textures");
-        Ok(Self{ kernel, dirty, buffer, scene_params })
+        Ok(Self{ kernel, buffer, scene_params, scene_items, bvh })
     }
 
     pub fn update(&mut self, queue: &Queue, scene: &mut Scene) -> Result<(), ocl::Error>{
@@ -169,45 +497,85 @@ impl TraceKernelWhitted{
         self.scene_params.upload_new(queue, &scene_params_raw)?;
         Ok(())
     }
+
+    // objects moved, the scene keeps its size
+    pub fn upload_scene(&mut self, queue: &Queue, scene: &mut Scene) -> Result<(), ocl::Error>{
+        self.scene_items.upload_new(queue, &scene.get_scene_buffer())?;
+        self.bvh.upload_new(queue, &scene.get_bvh_buffer())?;
+        Ok(())
+    }
+
+    // only the part of the scene items that holds the material
+    pub fn upload_material(&mut self, queue: &Queue, scene: &Scene, mat: MaterialIndex) -> Result<(), ocl::Error>{
+        let (offset, data) = scene.get_material_buffer(mat);
+        self.scene_items.upload_range(queue, offset, &data)
+    }
+
+    // textures change size when reloaded, so they get new buffers; the kernel keeps them alive
+    pub fn upload_textures(&mut self, queue: &Queue, scene: &Scene) -> Result<(), ocl::Error>{
+        upload_texture_args(&self.kernel, queue, scene)
+    }
+
+    pub fn set_sample(&mut self, sample: usize) -> Result<(), ocl::Error>{
+        self.kernel.set_arg(3, sample as u32)?;
+        Ok(())
+    }
+
+    // the accumulation divided by the samples taken
+    pub fn linear_frame(&mut self, queue: &Queue, samples: usize) -> Vec<f32>{
+        self.buffer.download(queue).expect("Could not download buffer to export the linear frame!");
+        let mult = 1.0 / samples.max(1) as f32;
+        self.buffer.get_slice().iter().map(|v| v * mult).collect()
+    }
+
+    // exposure in stops that brings the frame to middle grey
+    pub fn meter_exposure(&mut self, queue: &Queue, samples: usize) -> f32{
+        self.buffer.download(queue).expect("Could not download buffer to meter the exposure!");
+        let mult = 1.0 / samples.max(1) as f32;
+        meter_exposure(self.buffer.get_slice().chunks_exact(3).map(|c| luminance(Vec3::new(c[0], c[1], c[2]).scaled(mult))))
+    }
 }
 
 impl VoidKernel for TraceKernelWhitted{
     fn execute(&mut self, queue: &Queue) -> Result<(), ocl::Error>{
         unsafe {
-            self.kernel.cmd().queue(queue).enq().map(|_| self.dirty = true)
+            self.kernel.cmd().queue(queue).enq()
         }
     }
 }
 
-impl BufferKernel<u32> for TraceKernelWhitted{
-    fn get_buffer(&self) -> &ClBufferRW<u32>{
+impl BufferKernel<f32> for TraceKernelWhitted{
+    fn get_buffer(&self) -> &ClBufferRW<f32>{
         &self.buffer
     }
 }
 
-impl ResultKernel<u32> for TraceKernelWhitted{
-    fn get_result(&mut self, queue: &Queue) -> Result<&[u32], ocl::Error>{
-        if self.dirty {
-            self.buffer.download(queue)?;
-        }
-        self.dirty = false;
-        Ok(self.buffer.get_slice())
-    }
-}
-
 pub struct TraceKernelPath{
     kernel: Kernel,
     buffer: ClBufferRW<f32>,
+    aux: ClBufferRW<f32>,
+    stat: ClBufferRW<f32>,
+    ids: ClBufferRW<u32>,
     scene_params: ClBufferRW<u32>,
+    prev_params: ClBufferRW<u32>,
+    scene_items: ClBufferR<f32>,
+    bvh: ClBufferR<u32>,
 }
 
 impl TraceKernelPath{
-    pub fn new(name: &str, (w, h): (u32, u32), program: &Program, queue: &Queue, scene: &mut Scene, info: &mut Info) -> Result<Self, ocl::Error>{
+    // pixels with a relative error below the threshold stop tracing, zero traces all pixels
+    pub fn new(name: &str, (w, h): (u32, u32), threshold: f32, program: &Program, queue: &Queue, scene: &mut Scene, info: &mut Info) -> Result<Self, ocl::Error>{
         info.set_time_point("Start constructing kernel");
         let bsize = w as usize * h as usize * 3;
         let buffer = ClBufferRW::<f32>::new(queue, bsize, 0.0)?;
         info.float_buffer_size = bsize as u64;
         info.set_time_point("Build float frame buffer");
+        let aux = ClBufferRW::<f32>::new(queue, w as usize * h as usize * AUX_SIZE, 0.0)?;
+        info.set_time_point("Build denoiser guide buffer");
+        let stat = ClBufferRW::<f32>::new(queue, w as usize * h as usize * STAT_SIZE, 0.0)?;
+        info.set_time_point("Build pixel statistics buffer");
+        let ids = ClBufferRW::<u32>::new(queue, w as usize * h as usize * ID_SIZE, NO_ID)?;
+        info.set_time_point("Build id buffer");
         let scene_params_raw = scene.get_scene_params_buffer();
         let scene_raw = scene.get_scene_buffer();
         let bvh_raw = scene.get_bvh_buffer();
@@ -215,6 +583,7 @@ impl TraceKernelPath{
         info.meta_size = scene_params_raw.len() as u64;
         info.bvh_size = bvh_raw.len() as u64;
         info.set_time_point("Build scene data");
+        let mut prev_params = ClBufferRW::from(queue, scene_params_raw.clone())?;
         let mut scene_params = ClBufferRW::from(queue, scene_params_raw)?;
         let mut scene_items = ClBufferR::new(queue, scene_raw.len(), 0.0)?;
         let mut bvh = ClBufferR::new(queue, bvh_raw.len(), 0)?;
@@ -225,6 +594,10 @@ impl TraceKernelPath{
         let mut tex_params = ClBufferR::new(queue, tex_params_raw.len(), 0)?;
         let mut tex_items = ClBufferR::new(queue, tex_raw.len(), 0)?;
         info.set_time_point("Build texture buffers");
+        let mut blue_noise_raw = if scene.sampler == SamplerType::BlueNoise { blue_noise_mask() } else { Vec::new() };
+        make_nonzero_len(&mut blue_noise_raw);
+        let mut blue_noise = ClBufferR::new(queue, blue_noise_raw.len(), 0.0)?;
+        info.set_time_point("Generate blue noise");
         let mut kbuilder = Kernel::builder();
         kbuilder.program(program);
         kbuilder.name(name);
@@ -239,9 +612,15 @@ impl TraceKernelPath{
         kbuilder.arg(bvh.get_ocl_buffer());
         kbuilder.arg(tex_params.get_ocl_buffer());
         kbuilder.arg(tex_items.get_ocl_buffer());
+        kbuilder.arg(aux.get_ocl_buffer());
+        kbuilder.arg(stat.get_ocl_buffer());
+        kbuilder.arg(threshold);
+        kbuilder.arg(blue_noise.get_ocl_buffer());
+        kbuilder.arg(ids.get_ocl_buffer());
         let kernel = kbuilder.build()?;
         info.set_time_point("Create kernel");
         scene_params.upload(queue)?;
+        prev_params.upload(queue)?;
         info.set_time_point("Upload scene parameters");
         scene_items.upload_new(queue, &scene_raw)?;
         info.set_time_point("Upload scene items");
@@ -251,16 +630,54 @@ impl TraceKernelPath{
         info.set_time_point("Upload texture parameters");
         tex_items.upload_new(queue, &tex_raw)?;
         info.set_time_point("Upload textures");
-        Ok(Self{ kernel, buffer, scene_params })
+        blue_noise.upload_new(queue, &blue_noise_raw)?;
+        info.set_time_point("Upload blue noise");
+        Ok(Self{ kernel, buffer, aux, stat, ids, scene_params, prev_params, scene_items, bvh })
+    }
+
+    pub fn get_aux_buffer(&self) -> &ClBufferRW<f32>{
+        &self.aux
+    }
+
+    pub fn get_stat_buffer(&self) -> &ClBufferRW<f32>{
+        &self.stat
+    }
+
+    pub fn get_id_buffer(&self) -> &ClBufferRW<u32>{
+        &self.ids
+    }
+
+    pub fn get_params_buffers(&self) -> (&ClBufferRW<u32>, &ClBufferRW<u32>){
+        (&self.scene_params, &self.prev_params)
     }
 
     pub fn update(&mut self, queue: &Queue, scene: &mut Scene, state: &State) -> Result<(), ocl::Error>{
         let scene_params_raw = scene.get_scene_params_buffer();
+        // the parameters of the last frame, to reproject its accumulation from
+        self.prev_params.upload_new(queue, self.scene_params.get_slice())?;
         self.scene_params.upload_new(queue, &scene_params_raw)?;
         self.kernel.set_arg(3, state.samples_taken as u32)?;
         Ok(())
     }
 
+    // objects moved, the scene keeps its size
+    pub fn upload_scene(&mut self, queue: &Queue, scene: &mut Scene) -> Result<(), ocl::Error>{
+        self.scene_items.upload_new(queue, &scene.get_scene_buffer())?;
+        self.bvh.upload_new(queue, &scene.get_bvh_buffer())?;
+        Ok(())
+    }
+
+    // only the part of the scene items that holds the material
+    pub fn upload_material(&mut self, queue: &Queue, scene: &Scene, mat: MaterialIndex) -> Result<(), ocl::Error>{
+        let (offset, data) = scene.get_material_buffer(mat);
+        self.scene_items.upload_range(queue, offset, &data)
+    }
+
+    // textures change size when reloaded, so they get new buffers; the kernel keeps them alive
+    pub fn upload_textures(&mut self, queue: &Queue, scene: &Scene) -> Result<(), ocl::Error>{
+        upload_texture_args(&self.kernel, queue, scene)
+    }
+
     pub fn frame_energy(&mut self, queue: &Queue) -> f32{
         self.buffer.download(queue).expect("Could not download buffer to calculate frame energy!");
         let mut e = 0.0;
@@ -269,6 +686,41 @@ impl TraceKernelPath{
         }
         e
     }
+
+    // the accumulation divided by the samples taken
+    pub fn linear_frame(&mut self, queue: &Queue, samples: usize) -> Vec<f32>{
+        self.buffer.download(queue).expect("Could not download buffer to export the linear frame!");
+        let mult = 1.0 / samples.max(1) as f32;
+        self.buffer.get_slice().iter().map(|v| v * mult).collect()
+    }
+
+    // raw values of an aov, laid out like the linear frame
+    pub fn aov_frame(&mut self, queue: &Queue, samples: usize, aov: Aov) -> Vec<f32>{
+        self.buffer.download(queue).expect("Could not download buffer to export an aov!");
+        self.aux.download(queue).expect("Could not download guide buffer to export an aov!");
+        self.ids.download(queue).expect("Could not download id buffer to export an aov!");
+        let mult = 1.0 / samples.max(1) as f32;
+        let (col, aux, ids) = (self.buffer.get_slice(), self.aux.get_slice(), self.ids.get_slice());
+        (0..col.len() / 3).flat_map(|i| {
+            let guide: Vec<f32> = aux[i * AUX_SIZE..(i + 1) * AUX_SIZE].iter().map(|v| v * mult).collect();
+            let c = Vec3::new(col[i * 3], col[i * 3 + 1], col[i * 3 + 2]).scaled(mult);
+            aov_value(aov, c, &guide, &ids[i * ID_SIZE..(i + 1) * ID_SIZE]).as_array()
+        }).collect()
+    }
+
+    // exposure in stops that brings the frame to middle grey
+    pub fn meter_exposure(&mut self, queue: &Queue, samples: usize) -> f32{
+        self.buffer.download(queue).expect("Could not download buffer to meter the exposure!");
+        let mult = 1.0 / samples.max(1) as f32;
+        meter_exposure(self.buffer.get_slice().chunks_exact(3).map(|c| luminance(Vec3::new(c[0], c[1], c[2]).scaled(mult))))
+    }
+
+    // the same metric adaptive sampling uses, averaged over the frame
+    pub fn noise_level(&mut self, queue: &Queue, samples: usize) -> f32{
+        self.buffer.download(queue).expect("Could not download buffer to calculate the noise level!");
+        self.stat.download(queue).expect("Could not download statistics to calculate the noise level!");
+        noise_level(self.buffer.get_slice(), self.stat.get_slice(), samples)
+    }
 }
 
 impl VoidKernel for TraceKernelPath{
@@ -27,6 +27,18 @@ pub mod primitive;
 pub mod scenes;
 pub mod config;
 pub mod material;
+pub mod sky;
+pub mod spectral;
+pub mod denoise;
+pub mod adaptive;
+pub mod sampler;
+pub mod post;
+pub mod export;
+pub mod aov;
+pub mod camera_path;
+pub mod watch;
+pub mod hud;
+pub mod bindings;
 
 pub fn test(t: test_platform::PlatformTest){
     test_platform::run_platform_test(t);
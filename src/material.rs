@@ -1,8 +1,75 @@
 use crate::vec3::Vec3;
 use crate::scene::{ Scene, SceneItem };
+use crate::spectral::Dispersion;
+use crate::consts::PI;
 
 pub type MaterialIndex = u32;
 
+// part of a material that can be tweaked while rendering
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MaterialProperty{ Roughness, Refraction, Red, Green, Blue, Emittance }
+
+pub const MATERIAL_PROPERTIES: [MaterialProperty; 6] = [
+    MaterialProperty::Roughness, MaterialProperty::Refraction, MaterialProperty::Red,
+    MaterialProperty::Green, MaterialProperty::Blue, MaterialProperty::Emittance,
+];
+
+impl MaterialProperty{
+    const STEP: f32 = 0.05;
+    const EMITTANCE_FACTOR: f32 = 1.25;
+    // below this an emittance step down turns the light off
+    const MIN_EMITTANCE: f32 = 0.1;
+
+    pub fn name(self) -> &'static str{
+        match self{
+            Self::Roughness => "roughness",
+            Self::Refraction => "refraction",
+            Self::Red => "red",
+            Self::Green => "green",
+            Self::Blue => "blue",
+            Self::Emittance => "emittance",
+        }
+    }
+
+    // the property after this one
+    pub fn cycled(self) -> Self{
+        MATERIAL_PROPERTIES[(self as usize + 1) % MATERIAL_PROPERTIES.len()]
+    }
+}
+
+// homogeneous participating medium, inside a closed mesh or sphere or as the scene's fog
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Medium{
+    pub density: f32, // extinction per unit of distance
+    pub albedo: Vec3, // part of the extinction that scatters instead of absorbs
+    pub g: f32, // Henyey-Greenstein asymmetry: -1 back, 0 isotropic, 1 forward scattering
+}
+
+impl Medium{
+    pub fn new(density: f32, albedo: Vec3, g: f32) -> Self{
+        Self{ density, albedo, g: g.clamp(-0.99, 0.99) }
+    }
+
+    // Henyey-Greenstein phase function of the cosine between the old and new direction
+    pub fn phase(&self, cos_theta: f32) -> f32{
+        let g = self.g;
+        let d = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * d * d.sqrt())
+    }
+
+    pub fn transmittance(&self, dist: f32) -> f32{
+        (-self.density * dist).exp()
+    }
+
+    // density, albedo and g in the layout the kernel expects, zero density is no medium
+    pub fn get_data(medium: &Option<Self>) -> [f32; 5]{
+        match medium{
+            Some(m) => [m.density, m.albedo.x, m.albedo.y, m.albedo.z, m.g],
+            None => [0.0; 5],
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Material{ // 62 bytes =  2*12 + 9*4 + 2
     pub col: Vec3,
@@ -19,6 +86,8 @@ pub struct Material{ // 62 bytes =  2*12 + 9*4 + 2
     pub is_checkerboard: bool,
     pub is_dielectric: bool,
     pub emittance: f32,
+    pub dispersion: Dispersion, // only used by the spectral path tracer
+    pub medium: Option<Medium>, // fills the inside of the surface, a surface without refraction is invisible
 }
 
 impl Material{
@@ -46,6 +115,8 @@ impl Material{
             is_checkerboard: false,
             is_dielectric: false,
             emittance: 0.0,
+            dispersion: Dispersion::None,
+            medium: None,
         }
     }
 
@@ -90,6 +161,16 @@ impl Material{
         self
     }
 
+    pub fn with_dispersion(mut self, dispersion: Dispersion) -> Self{
+        self.dispersion = dispersion;
+        self
+    }
+
+    pub fn with_medium(mut self, medium: Medium) -> Self{
+        self.medium = Some(medium);
+        self
+    }
+
     pub fn with_absorption(mut self, absorption: Vec3) -> Self{
         let ab = Vec3{
             x: (1.0 - absorption.x).ln(),
@@ -135,6 +216,28 @@ impl Material{
         self
     }
 
+    // steps a property up or down, returns its new value
+    pub fn tweak(&mut self, prop: MaterialProperty, up: bool) -> f32{
+        let step = if up { MaterialProperty::STEP } else { -MaterialProperty::STEP };
+        let channel = |c: &mut f32| { *c = (*c + step).clamp(0.0, 1.0); *c };
+        match prop{
+            MaterialProperty::Roughness => { self.roughness = (self.roughness + step).clamp(0.0, 1.0); self.roughness },
+            MaterialProperty::Refraction => { self.refraction = (self.refraction + step).clamp(1.0, 3.0); self.refraction },
+            MaterialProperty::Red => channel(&mut self.col.x),
+            MaterialProperty::Green => channel(&mut self.col.y),
+            MaterialProperty::Blue => channel(&mut self.col.z),
+            MaterialProperty::Emittance => {
+                let e = if up {
+                    (self.emittance * MaterialProperty::EMITTANCE_FACTOR).max(MaterialProperty::MIN_EMITTANCE)
+                } else {
+                    self.emittance / MaterialProperty::EMITTANCE_FACTOR
+                };
+                self.emittance = if e < MaterialProperty::MIN_EMITTANCE { 0.0 } else { e };
+                self.emittance
+            },
+        }
+    }
+
     pub fn add_to_scene(self, scene: &mut Scene) -> MaterialIndex{
         scene.get_mat_index(self)
     }
@@ -144,14 +247,17 @@ impl Material{
 impl SceneItem for Material{
     fn get_data(&self) -> Vec<f32>{
         let refraction = if self.is_dielectric { self.refraction } else { -1.0 };
-        vec![
+        let mut data = vec![
             self.col.x, self.col.y, self.col.z, self.reflectivity,
             self.abs_fres.x, self.abs_fres.y, self.abs_fres.z, refraction,
             self.roughness, self.emittance,
             self.texture as f32, self.normal_map as f32,
             self.roughness_map as f32, self.metalic_map as f32,
             self.tex_scale
-        ]
+        ];
+        data.extend_from_slice(&self.dispersion.get_data());
+        data.extend_from_slice(&Medium::get_data(&self.medium));
+        data
     }
 
     fn add(self, scene: &mut Scene){
@@ -0,0 +1,424 @@
+use crate::vec3::Vec3;
+use crate::config::{ PostParsed, ToneMap };
+use crate::adaptive::luminance;
+use crate::sampler::{ wang_hash, u32tf01 };
+use crate::consts::{ GAMMA, EPSILON };
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Post processing of an accumulated linear frame, shared by the cpu and the kernel.
+// Order: chromatic aberration, exposure, white balance, bloom, vignette, tone map,
+// gamma, colour grading by a 3d lut and film grain.
+
+// floats in the parameter buffer of the kernel, see PostStack::get_data
+pub const POST_SIZE: usize = 19;
+// auto exposure meters the log2 luminance between these bounds
+pub const AE_MIN_LOG: f32 = -12.0;
+pub const AE_MAX_LOG: f32 = 8.0;
+pub const AE_BINS: usize = 64;
+// mean of the bins between these percentiles is exposed as middle grey
+pub const AE_LOW_PERCENTILE: f32 = 0.5;
+pub const AE_HIGH_PERCENTILE: f32 = 0.95;
+pub const AE_KEY: f32 = 0.18;
+// samples between meterings on the gpu, which downloads the whole frame
+pub const AE_METER_INTERVAL: usize = 16;
+// keeps the gains of channels missing from a temperature finite
+const WB_MIN_CHANNEL: f32 = 0.01;
+
+// 3d colour lookup table, red changes fastest
+#[derive(Clone, Debug)]
+pub struct Lut{
+    pub size: usize,
+    pub domain_min: Vec3,
+    pub domain_max: Vec3,
+    pub data: Vec<Vec3>,
+}
+
+impl Lut{
+    pub fn read(path: &Path) -> Result<Self, String>{
+        let mut file = File::open(path).map_err(|e| format!("Could not open lut {:?}: {}", path, e))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).map_err(|e| format!("Could not read lut {:?}: {}", path, e))?;
+        Self::parse(&contents)
+    }
+
+    // Adobe .cube format, only 3d tables
+    pub fn parse(src: &str) -> Result<Self, String>{
+        let mut size = 0;
+        let mut domain_min = Vec3::ZERO;
+        let mut domain_max = Vec3::ONE;
+        let mut data = Vec::new();
+        let triple = |words: &[&str]| -> Result<Vec3, String>{
+            if words.len() != 3 { return Err(format!("Lut: expected three values, got: {:?}", words)); }
+            let p = |s: &str| s.parse::<f32>().map_err(|_| format!("Lut: could not parse number: {}", s));
+            Ok(Vec3::new(p(words[0])?, p(words[1])?, p(words[2])?))
+        };
+        for line in src.lines(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[0]{
+                "TITLE" => {},
+                "LUT_1D_SIZE" => return Err("Lut: 1d tables are not supported!".to_string()),
+                "LUT_3D_SIZE" => size = words.get(1).and_then(|s| s.parse().ok())
+                    .ok_or_else(|| format!("Lut: invalid size: {}", line))?,
+                "DOMAIN_MIN" => domain_min = triple(&words[1..])?,
+                "DOMAIN_MAX" => domain_max = triple(&words[1..])?,
+                _ => data.push(triple(&words)?),
+            }
+        }
+        if size < 2 { return Err("Lut: LUT_3D_SIZE missing or smaller than two!".to_string()); }
+        if data.len() != size * size * size{
+            return Err(format!("Lut: expected {} entries, got {}!", size * size * size, data.len()));
+        }
+        Ok(Self{ size, domain_min, domain_max, data })
+    }
+
+    // trilinear lookup
+    pub fn sample(&self, c: Vec3) -> Vec3{
+        let n = self.size;
+        let range = self.domain_max.subed(self.domain_min);
+        let c = c.subed(self.domain_min).dived_fast(range).clamped(0.0, 1.0).scaled((n - 1) as f32);
+        let (x0, y0, z0) = (c.x as usize, c.y as usize, c.z as usize);
+        let (x1, y1, z1) = ((x0 + 1).min(n - 1), (y0 + 1).min(n - 1), (z0 + 1).min(n - 1));
+        let (fx, fy, fz) = (c.x - x0 as f32, c.y - y0 as f32, c.z - z0 as f32);
+        let at = |x: usize, y: usize, z: usize| self.data[x + y * n + z * n * n];
+        let c00 = at(x0, y0, z0).mixed(at(x1, y0, z0), fx);
+        let c10 = at(x0, y1, z0).mixed(at(x1, y1, z0), fx);
+        let c01 = at(x0, y0, z1).mixed(at(x1, y0, z1), fx);
+        let c11 = at(x0, y1, z1).mixed(at(x1, y1, z1), fx);
+        c00.mixed(c10, fy).mixed(c01.mixed(c11, fy), fz)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PostStack{
+    pub exposure: f32, // in stops, the frame is scaled by 2^exposure, compensation with auto exposure
+    pub auto_exposure: bool,
+    pub white_balance: Vec3, // gains per channel
+    pub bloom_threshold: f32, // luminance above which pixels bloom
+    pub bloom_strength: f32, // zero disables bloom
+    pub bloom_radius: usize,
+    pub chromatic_aberration_shift: usize,
+    pub chromatic_aberration_strength: f32,
+    pub vignette_strength: f32,
+    pub tone_map: ToneMap,
+    pub white_point: f32, // smallest value Reinhard maps to white
+    pub grain: f32, // amplitude of the film grain
+    pub lut: Option<Lut>,
+}
+
+impl PostStack{
+    pub fn new(conf: &PostParsed) -> Self{
+        Self{
+            exposure: conf.exposure,
+            auto_exposure: conf.auto_exposure,
+            white_balance: white_balance_gains(conf.white_balance),
+            bloom_threshold: conf.bloom_threshold,
+            bloom_strength: conf.bloom_strength,
+            bloom_radius: conf.bloom_radius,
+            chromatic_aberration_shift: conf.chromatic_aberration_shift,
+            chromatic_aberration_strength: conf.chromatic_aberration_strength,
+            vignette_strength: conf.vignette_strength,
+            tone_map: conf.tone_map,
+            white_point: conf.white_point,
+            grain: conf.film_grain,
+            lut: conf.lut.clone(),
+        }
+    }
+
+    // exposure and white balance, the scaling of the linear colour before anything else.
+    // metered is the exposure auto exposure measured, in stops
+    pub fn gains(&self, metered: f32) -> Vec3{
+        let ev = if self.auto_exposure { self.exposure + metered } else { self.exposure };
+        self.white_balance.scaled(2f32.powf(ev))
+    }
+
+    // bright pass of the exposed frame blurred by a gaussian, added to the frame before tone mapping
+    pub fn bloom(&self, frame: &[Vec3], w: usize, h: usize, gains: Vec3) -> Vec<Vec3>{
+        let bright: Vec<Vec3> = frame.iter().map(|c| {
+            let c = c.muled(gains);
+            let l = luminance(c);
+            c.scaled((l - self.bloom_threshold).max(0.0) / l.max(EPSILON))
+        }).collect();
+        let weights = bloom_weights(self.bloom_radius);
+        let r = self.bloom_radius as i32;
+        let blur = |input: &[Vec3], dx: i32, dy: i32| -> Vec<Vec3>{
+            (0..w * h).map(|p| {
+                let (x, y) = ((p % w) as i32, (p / w) as i32);
+                let mut sum = Vec3::ZERO;
+                for i in -r..=r{
+                    let qx = (x + i * dx).clamp(0, w as i32 - 1) as usize;
+                    let qy = (y + i * dy).clamp(0, h as i32 - 1) as usize;
+                    sum.add(input[qx + qy * w].scaled(weights[i.unsigned_abs() as usize]));
+                }
+                sum
+            }).collect()
+        };
+        blur(&blur(&bright, 1, 0), 0, 1)
+    }
+
+    // the stack on pixel (x, y) of a (w, h) frame. fetch gives the linear colour at
+    // clamped pixel coordinates, bloom the bloom of the pixel. Packed like the image map.
+    pub fn apply(&self, fetch: impl Fn(usize, usize) -> Vec3, bloom: Vec3, gains: Vec3, (x, y): (usize, usize), (w, h): (usize, usize), frame: u32) -> u32{
+        let mut col = fetch(x, y);
+        let (ash, ast) = (self.chromatic_aberration_shift, self.chromatic_aberration_strength);
+        let mut uv = Vec3::new(x as f32 / w as f32, y as f32 / h as f32, 0.0);
+        if ash > 0 && ast > EPSILON{
+            let r = fetch(x.max(ash) - ash, y.max(ash) - ash).x;
+            let b = fetch((x + ash).min(w - 1), (y + ash).min(h - 1)).z;
+            let abr_str = (uv.x * uv.y * 8.0).powf(ast).clamp(0.0, 1.0);
+            col.mix(Vec3::new(r, col.y, b), 1.0 - abr_str);
+        }
+        col.mul(gains);
+        col.add(bloom.scaled(self.bloom_strength));
+        uv.x *= 1.0 - uv.x;
+        uv.y *= 1.0 - uv.y;
+        col.scale((uv.x * uv.y * 32.0).powf(self.vignette_strength).clamp(0.0, 1.0));
+        col = tone_map(col, self.tone_map, self.white_point);
+        col.clamp(0.0, 1.0);
+        col.pow_scalar(1.0 / GAMMA);
+        if let Some(lut) = &self.lut{
+            col = lut.sample(col);
+        }
+        col.add(Vec3::uni((grain_noise(x as u32, y as u32, frame) - 0.5) * self.grain));
+        col.clamp(0.0, 1.0);
+        col.scale(255.0);
+        ((col.x as u32) << 16) + ((col.y as u32) << 8) + col.z as u32
+    }
+
+    // parameters in the layout the kernel reads them
+    pub fn get_data(&self, metered: f32) -> Vec<f32>{
+        let gains = self.gains(metered);
+        let (size, dmin, dmax) = match &self.lut{
+            Some(lut) => (lut.size, lut.domain_min, lut.domain_max),
+            None => (0, Vec3::ZERO, Vec3::ONE),
+        };
+        vec![
+            gains.x, gains.y, gains.z,
+            self.bloom_threshold,
+            self.bloom_strength,
+            self.bloom_radius as f32,
+            self.chromatic_aberration_shift as f32,
+            self.chromatic_aberration_strength,
+            self.vignette_strength,
+            self.tone_map as u32 as f32,
+            self.grain,
+            size as f32,
+            dmin.x, dmin.y, dmin.z,
+            dmax.x, dmax.y, dmax.z,
+            self.white_point,
+        ]
+    }
+
+    pub fn get_lut_buffer(&self) -> Vec<f32>{
+        match &self.lut{
+            Some(lut) => lut.data.iter().flat_map(|c| c.as_array()).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// normalized gaussian with sigma at half the radius
+pub fn bloom_weights(radius: usize) -> Vec<f32>{
+    let sigma = (radius as f32 * 0.5).max(0.5);
+    let raw: Vec<f32> = (0..=radius).map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp()).collect();
+    let total = raw[0] + 2.0 * raw[1..].iter().sum::<f32>();
+    raw.iter().map(|w| w / total).collect()
+}
+
+// gains that make a light of the given temperature white, at constant luminance
+pub fn white_balance_gains(kelvin: f32) -> Vec3{
+    let gains = temperature_rgb(6500.0).dived_fast(temperature_rgb(kelvin));
+    gains.scaled(1.0 / luminance(gains))
+}
+
+// Tanner Helland's fit of the blackbody colour of a temperature
+// https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html
+pub fn temperature_rgb(kelvin: f32) -> Vec3{
+    let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+    let r = if t <= 66.0 { 255.0 } else { 329.69873 * (t - 60.0).powf(-0.13320476) };
+    let g = if t <= 66.0 { 99.4708 * t.ln() - 161.11957 } else { 288.12217 * (t - 60.0).powf(-0.07551485) };
+    let b = if t >= 66.0 { 255.0 } else if t <= 19.0 { 0.0 } else { 138.51773 * (t - 10.0).ln() - 305.0448 };
+    Vec3::new(r, g, b).scaled(1.0 / 255.0).clamped(WB_MIN_CHANNEL, 1.0)
+}
+
+// exposure in stops that brings the frame to middle grey, from the histogram of its luminance
+pub fn meter_exposure(lum: impl Iterator<Item = f32>) -> f32{
+    let mut hist = [0usize; AE_BINS];
+    let range = AE_MAX_LOG - AE_MIN_LOG;
+    for l in lum{
+        // black pixels are most likely the background and not metered
+        if l <= 0.0 { continue; }
+        let bin = ((l.log2() - AE_MIN_LOG) / range * AE_BINS as f32).clamp(0.0, AE_BINS as f32 - 1.0);
+        hist[bin as usize] += 1;
+    }
+    let total: usize = hist.iter().sum();
+    if total == 0 { return 0.0; }
+    let (low, high) = (total as f32 * AE_LOW_PERCENTILE, total as f32 * AE_HIGH_PERCENTILE);
+    let mut seen = 0.0;
+    let mut sum = 0.0;
+    let mut count = 0.0;
+    for (i, n) in hist.iter().enumerate(){
+        // the part of this bin that falls between the percentiles
+        let n = *n as f32;
+        let part = (seen + n).min(high) - seen.max(low);
+        seen += n;
+        if part <= 0.0 { continue; }
+        sum += part * (AE_MIN_LOG + (i as f32 + 0.5) / AE_BINS as f32 * range);
+        count += part;
+    }
+    if count <= 0.0 { return 0.0; }
+    AE_KEY.log2() - sum / count
+}
+
+pub fn tone_map(c: Vec3, tm: ToneMap, white_point: f32) -> Vec3{
+    match tm{
+        ToneMap::None => c,
+        ToneMap::Aces => aces(c),
+        ToneMap::Hable => hable(c),
+        ToneMap::Reinhard => reinhard(c, white_point),
+        ToneMap::AgX => agx(c),
+        ToneMap::AcesFull => aces_full(c),
+        ToneMap::Uchimura => uchimura(c),
+    }
+}
+
+#[inline]
+fn mat_mul(m: [[f32; 3]; 3], v: Vec3) -> Vec3{
+    let row = |r: [f32; 3]| r[0] * v.x + r[1] * v.y + r[2] * v.z;
+    Vec3::new(row(m[0]), row(m[1]), row(m[2]))
+}
+
+// extended Reinhard, values at the white point map to one
+fn reinhard(x: Vec3, white_point: f32) -> Vec3{
+    let w2 = white_point * white_point;
+    let f = |x: f32| x * (1.0 + x / w2) / (1.0 + x);
+    Vec3::new(f(x.x), f(x.y), f(x.z))
+}
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms
+fn aces_full(x: Vec3) -> Vec3{
+    const INPUT: [[f32; 3]; 3] = [[0.59719, 0.35458, 0.04823], [0.07600, 0.90834, 0.01566], [0.02840, 0.13383, 0.83777]];
+    const OUTPUT: [[f32; 3]; 3] = [[1.60475, -0.53108, -0.07367], [-0.10208, 1.10813, -0.00605], [-0.00327, -0.07276, 1.07602]];
+    let f = |v: f32| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081);
+    let v = mat_mul(INPUT, x);
+    mat_mul(OUTPUT, Vec3::new(f(v.x), f(v.y), f(v.z))).clamped(0.0, 1.0)
+}
+
+// Troy Sobotka's AgX with the polynomial contrast curve of Benjamin Wrensch
+fn agx(x: Vec3) -> Vec3{
+    const INSET: [[f32; 3]; 3] = [[0.842479, 0.078434, 0.079224], [0.042328, 0.878469, 0.079166], [0.042376, 0.078434, 0.879143]];
+    const OUTSET: [[f32; 3]; 3] = [[1.196879, -0.098021, -0.099030], [-0.052897, 1.151903, -0.098961], [-0.052972, -0.098043, 1.151074]];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+    let curve = |v: f32| {
+        let v = (v.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let (v2, v4) = (v * v, v * v * v * v);
+        15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v - 0.00232
+    };
+    let v = mat_mul(INSET, x);
+    let v = mat_mul(OUTSET, Vec3::new(curve(v.x), curve(v.y), curve(v.z)));
+    // the curve ends in display space, the stack applies gamma after tone mapping
+    v.clamped(0.0, 1.0).powed_scalar(GAMMA)
+}
+
+// Hajime Uchimura's Gran Turismo curve: toe, linear section and shoulder
+fn uchimura(x: Vec3) -> Vec3{
+    let (p, a, m, l, c, b) = (1.0f32, 1.0f32, 0.22f32, 0.4f32, 1.33f32, 0.0f32);
+    let l0 = (p - m) * l / a;
+    let s0 = m + l0;
+    let s1 = m + a * l0;
+    let c2 = a * p / (p - s1);
+    let cp = -c2 / p;
+    let f = |x: f32| {
+        let t = (x / m).clamp(0.0, 1.0);
+        let w0 = 1.0 - t * t * (3.0 - 2.0 * t);
+        let w2 = if x >= s0 { 1.0 } else { 0.0 };
+        let w1 = 1.0 - w0 - w2;
+        let toe = m * (x / m).max(0.0).powf(c) + b;
+        let shoulder = p - (p - s1) * (cp * (x - s0)).exp();
+        let linear = m + a * (x - m);
+        toe * w0 + linear * w1 + shoulder * w2
+    };
+    Vec3::new(f(x.x), f(x.y), f(x.z))
+}
+
+fn aces(x: Vec3) -> Vec3{
+    let f = |x: f32| (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    Vec3::new(f(x.x), f(x.y), f(x.z))
+}
+
+fn hable(x: Vec3) -> Vec3{
+    let partial = |x: f32| {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    };
+    let white_scale = 1.0 / partial(11.2);
+    let f = |x: f32| (partial(x * 4.0) * white_scale).clamp(0.0, 1.0);
+    Vec3::new(f(x.x), f(x.y), f(x.z))
+}
+
+// uniform noise per pixel that changes every frame
+#[inline]
+pub fn grain_noise(x: u32, y: u32, frame: u32) -> f32{
+    u32tf01(wang_hash(x ^ wang_hash(y ^ wang_hash(frame))))
+}
+
+#[cfg(test)]
+mod test{
+    use crate::post::*;
+
+    #[test]
+    fn identity_lut_keeps_colours(){
+        let mut src = String::from("TITLE \"identity\"\n# comment\nLUT_3D_SIZE 3\n");
+        for b in 0..3{
+        for g in 0..3{
+        for r in 0..3{
+            src.push_str(&format!("{} {} {}\n", r as f32 * 0.5, g as f32 * 0.5, b as f32 * 0.5));
+        }
+        }
+        }
+        let lut = Lut::parse(&src).unwrap();
+        let c = Vec3::new(0.2, 0.7, 0.9);
+        let s = lut.sample(c);
+        assert!(s.subed(c).len() < 0.0001);
+        assert!(Lut::parse("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn neutral_white_balance_and_bloom_weights(){
+        let g = white_balance_gains(6500.0);
+        assert!(g.subed(Vec3::ONE).len() < 0.001);
+        // warm light gets its blue boosted
+        let warm = white_balance_gains(3000.0);
+        assert!(warm.z > warm.x);
+        let w = bloom_weights(4);
+        assert!((w[0] + 2.0 * w[1..].iter().sum::<f32>() - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn tone_maps_are_monotonic(){
+        let maps = [ToneMap::Aces, ToneMap::Hable, ToneMap::Reinhard, ToneMap::AgX, ToneMap::AcesFull, ToneMap::Uchimura];
+        for tm in maps.iter(){
+            let mut last = -1.0;
+            for i in 0..64{
+                // the stack clamps after tone mapping, the Aces fit slightly overshoots one
+                let v = tone_map(Vec3::uni(i as f32 * 0.0625), *tm, 4.0).y;
+                assert!(v >= last - 0.0001 && v <= 1.05, "{:?} at {}", tm, i);
+                last = v;
+            }
+        }
+    }
+
+    #[test]
+    fn metering_exposes_middle_grey(){
+        let ev = meter_exposure((0..1000).map(|_| AE_KEY));
+        assert!(ev.abs() < 0.2);
+        let ev = meter_exposure((0..1000).map(|_| AE_KEY * 4.0));
+        assert!((ev + 2.0).abs() < 0.2);
+        assert_eq!(meter_exposure((0..10).map(|_| 0.0)), 0.0);
+    }
+}
@@ -1,14 +1,14 @@
 use crate::scene::{ Scene, Model, Intersectable};
 use crate::cpu::inter::{ Ray, RayHit, dist_sphere, dist_triangle};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Shape {
     MODEL = 0,
     SPHERE = 1,
     TRIANGLE = 2,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Primitive {
     pub shape_type: Shape,
     pub index: usize,
@@ -42,10 +42,9 @@ impl Primitive {
                 let model = scene.models[self.index];
                 let t = hit.t;
                 // transform ray
-                let ori = model.rot.orientation();
-                let yaw = ori.yaw;
+                let (pos, yaw) = model.transform_at(ray.time);
                 let mut ray = ray;
-                ray.pos = ray.pos.subed(model.pos);
+                ray.pos = ray.pos.subed(pos);
                 // rotate pos clockwise x-axis
                 ray.pos = ray.pos.yawed(-yaw);
                 // rotate dir clockwise x-axis
@@ -56,7 +55,7 @@ impl Primitive {
                     hit.nor = hit.nor.yawed(yaw);
 
                     hit.pos = hit.pos.yawed(yaw);
-                    hit.pos = hit.pos.added(model.pos);
+                    hit.pos = hit.pos.added(pos);
                 }
                 return (a, b);
             },
@@ -71,10 +70,9 @@ impl Primitive {
             Shape::MODEL => {
                 let model = scene.models[self.index];
                 // transform ray
-                let ori = model.rot.orientation();
-                let yaw = ori.yaw;
+                let (pos, yaw) = model.transform_at(ray.time);
                 let mut ray = ray;
-                ray.pos = ray.pos.subed(model.pos);
+                ray.pos = ray.pos.subed(pos);
                 // rotate pos clockwise x-axis
                 ray.pos = ray.pos.yawed(-yaw);
                 // rotate dir clockwise x-axis
@@ -0,0 +1,211 @@
+// Samplers of the random numbers of a path, mirrored by the kernel.
+// Every call to sample is the next dimension of the sample of a pixel.
+// Sobol: a shuffled and Owen-scrambled 2d Sobol sequence per pair of dimensions,
+// Burley (2020): Practical Hash-based Owen Scrambling
+// https://jcgt.org/published/0009/04/01/
+// BlueNoise: the same sequence for every pixel, rotated per pixel by a blue noise mask,
+// which leaves the error of low sample counts distributed as blue noise.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SamplerType{ Random = 0, Sobol = 1, BlueNoise = 2 }
+
+// width and height of the tiled blue noise mask
+pub const BLUE_NOISE_SIZE: usize = 64;
+
+pub struct Sampler<'a>{
+    stype: SamplerType,
+    seed: u32, // state of the random sampler, the scramble of the pixel for the others
+    index: u32,
+    dim: u32,
+    x: u32,
+    y: u32,
+    mask: &'a [f32],
+}
+
+impl<'a> Sampler<'a>{
+    pub fn new(stype: SamplerType, seed: u32, mask: &'a [f32]) -> Self{
+        Self{ stype, seed, index: 0, dim: 0, x: 0, y: 0, mask }
+    }
+
+    // start the given sample of a pixel, the random sampler just keeps its state
+    pub fn start(&mut self, x: u32, y: u32, index: u32){
+        self.index = index;
+        self.dim = 0;
+        self.x = x;
+        self.y = y;
+        match self.stype{
+            SamplerType::Random => {},
+            SamplerType::Sobol => self.seed = wang_hash(x ^ wang_hash(y)),
+            SamplerType::BlueNoise => self.seed = 0,
+        }
+    }
+
+    pub fn sample(&mut self) -> f32{
+        if self.stype == SamplerType::Random{
+            return u32tf01(xor32(&mut self.seed));
+        }
+        let dim = self.dim;
+        self.dim += 1;
+        let pair_seed = wang_hash(self.seed ^ wang_hash(dim >> 1));
+        let index = nested_uniform_scramble(self.index, pair_seed);
+        let v = if dim & 1 == 1 { sobol_dim1(index) } else { index.reverse_bits() };
+        let r = u32tf01(nested_uniform_scramble(v, wang_hash(pair_seed.wrapping_add(dim))));
+        if self.stype == SamplerType::BlueNoise && !self.mask.is_empty(){
+            // Cranley-Patterson rotation by the mask, shifted for every dimension
+            let off = wang_hash(dim);
+            let bx = (self.x.wrapping_add(off) as usize) % BLUE_NOISE_SIZE;
+            let by = (self.y.wrapping_add(off >> 16) as usize) % BLUE_NOISE_SIZE;
+            let r = r + self.mask[bx + by * BLUE_NOISE_SIZE];
+            return if r >= 1.0 { r - 1.0 } else { r };
+        }
+        r
+    }
+}
+
+// credit: George Marsaglia
+#[inline]
+pub fn xor32(seed: &mut u32) -> u32{
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    *seed
+}
+
+#[inline]
+pub fn u32tf01(int: u32) -> f32{
+   int as f32 * 2.3283064e-10
+}
+
+// https://riptutorial.com/opencl/example/20715/using-thomas-wang-s-integer-hash-function
+#[inline]
+pub fn wang_hash(seed: u32) -> u32{
+    let mut seed = (seed ^ 61) ^ (seed >> 16);
+    seed = seed.wrapping_mul(9);
+    seed ^= seed >> 4;
+    seed = seed.wrapping_mul(0x27d4_eb2d);
+    seed ^ (seed >> 15)
+}
+
+// Laine-Karras style permutation, only lower bits affect higher bits
+#[inline]
+fn laine_karras(mut x: u32, seed: u32) -> u32{
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+#[inline]
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32{
+    laine_karras(x.reverse_bits(), seed).reverse_bits()
+}
+
+// second dimension of the Sobol sequence, the first is the bit reversed index
+#[inline]
+fn sobol_dim1(mut index: u32) -> u32{
+    let mut res = 0;
+    let mut v = 1u32 << 31;
+    while index != 0{
+        if index & 1 == 1 { res ^= v; }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    res
+}
+
+// Ulichney (1993): The void-and-cluster method for dither array generation
+pub fn blue_noise_mask() -> Vec<f32>{
+    let n = BLUE_NOISE_SIZE;
+    let len = n * n;
+    // gaussian energy of a point on the torus
+    let sigma = 1.5f32;
+    let lut: Vec<f32> = (0..len).map(|i| {
+        let (dx, dy) = (i % n, i / n);
+        let (dx, dy) = (dx.min(n - dx) as f32, dy.min(n - dy) as f32);
+        (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+    }).collect();
+    let splat = |energy: &mut [f32], p: usize, sign: f32|{
+        let (px, py) = (p % n, p / n);
+        for y in 0..n{
+        for x in 0..n{
+            energy[x + y * n] += sign * lut[(x + n - px) % n + ((y + n - py) % n) * n];
+        }
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]|
+        (0..len).filter(|i| pattern[*i]).fold(0, |b, i| if !pattern[b] || energy[i] > energy[b] { i } else { b });
+    let largest_void = |pattern: &[bool], energy: &[f32]|
+        (0..len).filter(|i| !pattern[*i]).fold(0, |b, i| if pattern[b] || energy[i] < energy[b] { i } else { b });
+
+    // random initial pattern on a tenth of the pixels
+    let ones = len / 10;
+    let mut pattern = vec![false; len];
+    let mut energy = vec![0.0; len];
+    let mut seed = 0x2545_f491;
+    let mut placed = 0;
+    while placed < ones{
+        let p = xor32(&mut seed) as usize % len;
+        if !pattern[p]{
+            pattern[p] = true;
+            splat(&mut energy, p, 1.0);
+            placed += 1;
+        }
+    }
+    // spread it by moving the tightest cluster into the largest void until that changes nothing
+    loop{
+        let c = tightest_cluster(&pattern, &energy);
+        pattern[c] = false;
+        splat(&mut energy, c, -1.0);
+        let v = largest_void(&pattern, &energy);
+        pattern[v] = true;
+        splat(&mut energy, v, 1.0);
+        if v == c { break; }
+    }
+    let mut rank = vec![0; len];
+    // rank the initial points by removing the tightest clusters first
+    let mut proto = pattern.clone();
+    let mut proto_energy = energy.clone();
+    for r in (0..ones).rev(){
+        let c = tightest_cluster(&proto, &proto_energy);
+        proto[c] = false;
+        splat(&mut proto_energy, c, -1.0);
+        rank[c] = r;
+    }
+    // rank the other points by filling the largest voids first
+    for r in ones..len{
+        let v = largest_void(&pattern, &energy);
+        pattern[v] = true;
+        splat(&mut energy, v, 1.0);
+        rank[v] = r;
+    }
+    rank.iter().map(|r| (*r as f32 + 0.5) / len as f32).collect()
+}
+
+#[cfg(test)]
+mod test{
+    use crate::sampler::*;
+
+    #[test]
+    fn sobol_is_stratified(){
+        for pixel in 0..8{
+            let mut smp = Sampler::new(SamplerType::Sobol, 0, &[]);
+            let mut quadrants = [false; 4];
+            for i in 0..4{
+                smp.start(pixel, 3, i);
+                let (u, v) = (smp.sample(), smp.sample());
+                quadrants[(u >= 0.5) as usize + 2 * (v >= 0.5) as usize] = true;
+            }
+            assert!(quadrants.iter().all(|q| *q));
+        }
+    }
+
+    #[test]
+    fn blue_noise_mask_is_a_permutation(){
+        let mask = blue_noise_mask();
+        let mut ranks: Vec<usize> = mask.iter().map(|v| (v * mask.len() as f32) as usize).collect();
+        ranks.sort_unstable();
+        assert!(ranks.iter().enumerate().all(|(i, r)| i == *r));
+    }
+}
@@ -8,8 +8,11 @@ use crate::aabb::AABB;
 use crate::primitive::{ Primitive, Shape };
 use crate::cpu::inter::{ Ray, RayHit, inter_plane, inter_sphere, inter_triangle };
 use crate::config::ConfigParsed;
-use crate::consts::FRAC_2_PI;
-use crate::material::{ Material, MaterialIndex };
+use crate::consts::{ PI, FRAC_2_PI, EPSILON };
+use crate::material::{ Material, MaterialIndex, Medium };
+use crate::sky::ProceduralSky;
+use crate::sampler::SamplerType;
+use crate::post::PostStack;
 
 use std::collections::HashMap;
 use std::convert::TryInto;
@@ -103,6 +106,22 @@ impl Intersectable for Triangle {
     }
 }
 
+// Rectangle made of two triangles, with an emissive material it's a rectangle area light
+pub struct Rect{
+    pub pos: Vec3, // corner
+    pub u: Vec3, // first edge
+    pub v: Vec3, // second edge
+    pub mat: MaterialIndex,
+}
+
+impl SceneItem for Rect{
+    fn add(self, scene: &mut Scene){
+        scene.add_rect(self);
+    }
+
+    fn get_data(&self) -> Vec<f32>{ vec![] }
+}
+
 pub type MeshIndex = u32;
 
 #[derive(Clone, Copy)]
@@ -110,10 +129,31 @@ pub struct Model{
     pub pos: Vec3,
     // pub scale: Vec3,
     pub rot: Vec3,
+    pub motion: Vec3, // translation over the shutter interval
+    pub spin: f32, // change of yaw over the shutter interval
     pub mat: MaterialIndex,
     pub mesh: MeshIndex
 }
 
+impl Model{
+    // the model starts at its transform and ends at pos + motion, yawed by spin
+    pub fn with_motion(mut self, motion: Vec3, spin: f32) -> Self{
+        self.motion = motion;
+        self.spin = spin;
+        self
+    }
+
+    // position and yaw at time t of the shutter interval
+    #[inline]
+    pub fn transform_at(&self, t: f32) -> (Vec3, f32){
+        (self.pos.added(self.motion.scaled(t)), self.rot.orientation().yaw + self.spin * t)
+    }
+
+    pub fn is_moving(&self) -> bool{
+        self.motion != Vec3::ZERO || self.spin != 0.0
+    }
+}
+
 impl SceneItem for Model{
     fn add(self, scene: &mut Scene){
         scene.add_model(self);
@@ -122,17 +162,81 @@ impl SceneItem for Model{
     fn get_data(&self) -> Vec<f32>{ vec![] }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightType{
+    Point = 0,
+    Directional = 1,
+    Spot = 2,
+}
+
 pub struct Light{
     pub pos: Vec3,
     pub intensity: f32,
     pub col: Vec3,
+    pub ltype: LightType,
+    pub dir: Vec3, // direction the light shines in, for directional and spot lights
+    pub angle: f32, // angular radius of a directional light, outer cone angle of a spot light
+    pub inner_angle: f32, // spot light is at full intensity within this angle
+    pub falloff: f32, // exponent of the spot light falloff between the inner and outer angle
+}
+
+impl Light{
+    pub fn point(pos: Vec3, intensity: f32, col: Vec3) -> Self{
+        Self{
+            pos, intensity, col,
+            ltype: LightType::Point,
+            dir: Vec3::DOWN,
+            angle: 0.0,
+            inner_angle: 0.0,
+            falloff: 1.0,
+        }
+    }
+
+    // intensity is the irradiance it delivers, there is no falloff over distance
+    pub fn directional(dir: Vec3, intensity: f32, col: Vec3) -> Self{
+        Self{
+            ltype: LightType::Directional,
+            dir: dir.normalized(),
+            ..Self::point(Vec3::ZERO, intensity, col)
+        }
+    }
+
+    pub fn spot(pos: Vec3, dir: Vec3, intensity: f32, col: Vec3) -> Self{
+        Self{
+            ltype: LightType::Spot,
+            dir: dir.normalized(),
+            angle: 0.5,
+            inner_angle: 0.4,
+            ..Self::point(pos, intensity, col)
+        }
+    }
+
+    // angular radius of a directional light in radians, gives soft shadows
+    pub fn with_angle(mut self, angle: f32) -> Self{
+        self.angle = angle;
+        self
+    }
+
+    // inner and outer cone angle of a spot light in radians
+    pub fn with_cone(mut self, inner: f32, outer: f32) -> Self{
+        self.inner_angle = inner.min(outer);
+        self.angle = outer;
+        self
+    }
+
+    pub fn with_falloff(mut self, falloff: f32) -> Self{
+        self.falloff = falloff;
+        self
+    }
 }
 
 impl SceneItem for Light{
     fn get_data(&self) -> Vec<f32>{
         vec![
             self.pos.x, self.pos.y, self.pos.z, self.intensity,
-            self.col.x, self.col.y, self.col.z
+            self.col.x, self.col.y, self.col.z, self.ltype as u32 as f32,
+            self.dir.x, self.dir.y, self.dir.z,
+            self.angle.cos(), self.inner_angle.cos(), self.falloff,
         ]
     }
 
@@ -149,14 +253,22 @@ pub struct Camera{
     pub move_sensitivity: f32,
     pub look_sensitivity: f32,
     pub fov: f32,
-    pub chromatic_aberration_shift: usize,
-    pub chromatic_aberration_strength: f32,
-    pub vignette_strength: f32,
+    pub post: PostStack,
     pub angle_radius: f32,
-    pub distortion_coefficient: f32
+    pub distortion_coefficient: f32,
+    pub aperture: f32, // radius of the lens, zero is a pinhole camera
+    pub focal_distance: f32, // distance to the plane in focus, along the camera direction
+    pub blades: u32, // polygonal bokeh with this many sides, round below three
+    pub shutter: f32, // part of the shutter interval the shutter is open, zero disables motion blur
+    pub motion: Vec3, // translation over the shutter interval
+    pub spin: f32, // change of yaw over the shutter interval
+    pub target: Option<Vec3>, // the point the camera circles in orbit mode, none flies
 }
 
 impl Camera{
+    // closest the camera gets to its orbit target
+    const MIN_ORBIT_DIST: f32 = 0.01;
+
     fn new(conf: &ConfigParsed) -> Self{
         Self{
             pos: Vec3::ZERO,
@@ -165,13 +277,79 @@ impl Camera{
             move_sensitivity: conf.controls.move_sens,
             look_sensitivity: conf.controls.look_sens,
             fov: conf.camera.fov,
-            chromatic_aberration_shift: conf.post.chromatic_aberration_shift,
-            chromatic_aberration_strength: conf.post.chromatic_aberration_strength,
-            vignette_strength: conf.post.vignette_strength,
+            post: PostStack::new(&conf.post),
             angle_radius: if conf.camera.fisheye { FRAC_2_PI } else { 0.0 },
             distortion_coefficient: conf.post.distortion_coefficient,
+            aperture: conf.camera.aperture,
+            focal_distance: conf.camera.focal_distance,
+            blades: conf.camera.blades,
+            shutter: conf.camera.shutter,
+            motion: conf.camera.motion,
+            spin: conf.camera.spin,
+            target: None,
+        }
+    }
+
+    // position and direction at time t of the shutter interval
+    pub fn at_time(&self, t: f32) -> (Vec3, Vec3){
+        (self.pos.added(self.motion.scaled(t)), self.dir.normalized_fast().yawed(-self.spin * t))
+    }
+
+    // turn by yaw and roll in radians, the roll stops at straight up and down
+    pub fn look(&mut self, yaw: f32, roll: f32){
+        self.ori.yaw = (self.ori.yaw + yaw + PI).rem_euclid(2.0 * PI) - PI;
+        self.ori.roll = (self.ori.roll + roll).clamp(-FRAC_2_PI, FRAC_2_PI);
+        self.dir = Vec3::from_orientation(&self.ori);
+    }
+
+    pub fn look_at(&mut self, p: Vec3){
+        self.ori = p.subed(self.pos).orientation();
+        self.dir = Vec3::from_orientation(&self.ori);
+    }
+
+    // circle the orbit target by yaw and roll in radians and come closer to it by dolly
+    pub fn orbit(&mut self, yaw: f32, roll: f32, dolly: f32){
+        if let Some(target) = self.target{
+            let dist = (self.pos.dist(target) - dolly).max(Self::MIN_ORBIT_DIST);
+            self.look(yaw, roll);
+            self.pos = target.subed(self.dir.scaled(dist));
+        }
+    }
+
+    // move the camera and its orbit target along the screen
+    pub fn pan(&mut self, right: f32, up: f32){
+        let hor = self.dir.crossed(Vec3::UP).normalized_fast();
+        let ver = hor.crossed(self.dir).normalized_fast();
+        let off = hor.scaled(right).added(ver.scaled(up));
+        self.pos.add(off);
+        if let Some(target) = &mut self.target{
+            target.add(off);
         }
     }
+
+    // direction through the point u, v of the screen, from the top left in 0..1; ignores the fish eye lens
+    pub fn screen_dir(&self, u: f32, v: f32, aspect: f32) -> Vec3{
+        let cd = self.dir.normalized_fast();
+        let hor = cd.crossed(Vec3::UP).normalized_fast();
+        let ver = hor.crossed(cd).normalized_fast();
+        let uv_dist = (aspect / 2.0) / (self.fov.to_radians() / 2.0).tan();
+        cd.scaled(uv_dist).added(hor.scaled((u - 0.5) * aspect)).added(ver.scaled(0.5 - v)).normalized_fast()
+    }
+
+    // the point u, v of the screen that p is seen at, the inverse of screen_dir; none behind the camera
+    pub fn screen_point(&self, p: Vec3, aspect: f32) -> Option<(f32, f32)>{
+        let cd = self.dir.normalized_fast();
+        let hor = cd.crossed(Vec3::UP).normalized_fast();
+        let ver = hor.crossed(cd).normalized_fast();
+        let uv_dist = (aspect / 2.0) / (self.fov.to_radians() / 2.0).tan();
+        let d = p.subed(self.pos);
+        let z = d.dot(cd);
+        if z < EPSILON{
+            return None;
+        }
+        let s = uv_dist / z;
+        Some((0.5 + d.dot(hor) * s / aspect, 0.5 - d.dot(ver) * s))
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -190,6 +368,7 @@ pub struct Scene{
     pub meshes: Vec<Mesh>,
     pub models: Vec<Model>,
     pub primitives: Vec<Primitive>,
+    pub area_lights: Vec<Primitive>,
     pub sub_bvhs: Vec<Bvh>,
     pub top_bvh: Bvh,
     scene_params: [u32; Self::SCENE_PARAM_SIZE],
@@ -198,21 +377,30 @@ pub struct Scene{
     textures_ids: HashMap<String, u32>,
     indexed_textures: Vec<(String, TexType, String)>,
     textures: Vec<TraceTex>,
+    texture_files: Vec<(String, TexType)>, // where the textures came from, to reload them
     skybox: u32,
     pub sky_col: Vec3,
     pub sky_intensity: f32,
     pub sky_min: f32,
     pub sky_pow: f32,
     pub sky_box: u32,
+    pub procedural_sky: Option<ProceduralSky>,
+    pub spectral: bool, // the path tracer samples wavelengths instead of rgb
+    pub fog: Option<Medium>,
+    pub fog_distance: f32, // fog ends this far along every ray segment
+    pub sampler: SamplerType,
     pub cam: Camera,
 }
 
 impl Scene{
-    const SCENE_SIZE: u32 = 11;
-    const SCENE_PARAM_SIZE: usize = 7 * 2 + Self::SCENE_SIZE as usize;
-    const MATERIAL_SIZE: u32 = 15;
+    const SCENE_SIZE: u32 = 26;
+    const SKY_SIZE: u32 = 29;
+    const MOTION_STEPS: usize = 8;
+    const SKY_PARAM_START: usize = 7 * 2 + Self::SCENE_SIZE as usize;
+    const SCENE_PARAM_SIZE: usize = Self::SKY_PARAM_START + Self::SKY_SIZE as usize;
+    const MATERIAL_SIZE: u32 = 27;
     const MATERIAL_INDEX_SIZE: u32 = 1;
-    const LIGHT_SIZE: u32 = 7;
+    const LIGHT_SIZE: u32 = 14;
     const PLANE_SIZE: u32 = 6 + Self::MATERIAL_INDEX_SIZE;
     const SPHERE_SIZE: u32 = 4 + Self::MATERIAL_INDEX_SIZE;
     const TRIANGLE_SIZE: u32 = 9 + Self::MATERIAL_INDEX_SIZE;
@@ -226,6 +414,7 @@ impl Scene{
             meshes: Vec::new(),
             models: Vec::new(),
             primitives: Vec::new(),
+            area_lights: Vec::new(),
             sub_bvhs: Vec::new(),
             top_bvh: Bvh::default(),
             lights: Vec::new(),
@@ -236,12 +425,18 @@ impl Scene{
             textures_ids: HashMap::new(),
             indexed_textures: Vec::new(),
             textures: Vec::new(),
+            texture_files: Vec::new(),
             skybox: 0,
             sky_col: Vec3::ONE,
             sky_intensity: 1.0,
             sky_min: 0.1,
             sky_pow: 1.0,
             sky_box: 0,
+            procedural_sky: None,
+            spectral: config.base.spectral,
+            fog: None,
+            fog_distance: 0.0,
+            sampler: config.base.sampler,
             cam: Camera::new(config),
         }
     }
@@ -289,9 +484,81 @@ impl Scene{
         self.scene_params[16] = self.sky_pow.to_bits() as u32;
         self.put_in_scene_params(17, self.cam.pos);
         self.put_in_scene_params(20, self.cam.dir);
+        self.scene_params[23] = self.cam.aperture.to_bits();
+        self.scene_params[24] = self.cam.focal_distance.to_bits();
+        self.scene_params[25] = self.cam.blades;
+        self.scene_params[26] = self.cam.shutter.to_bits();
+        self.put_in_scene_params(27, self.cam.motion);
+        self.scene_params[30] = self.cam.spin.to_bits();
+        self.scene_params[31] = self.spectral as u32;
+        for (i, float) in Medium::get_data(&self.fog).iter().enumerate(){
+            self.scene_params[32 + i] = float.to_bits();
+        }
+        self.scene_params[37] = self.fog_distance.to_bits();
+        self.scene_params[38] = self.sampler as u32;
+        self.scene_params[39] = self.cam.fov.to_bits();
+        //sky
+        let start = Self::SKY_PARAM_START;
+        if let Some(sky) = &self.procedural_sky{
+            self.scene_params[start] = 1;
+            for (i, float) in sky.get_data().iter().enumerate(){
+                self.scene_params[start + 1 + i] = float.to_bits();
+            }
+        } else {
+            self.scene_params[start] = 0;
+        }
         self.scene_params.to_vec()
     }
 
+    // focus the camera on whatever is in the centre of the screen
+    pub fn autofocus(&mut self) -> bool{
+        let ray = Ray{ pos: self.cam.pos, dir: self.cam.dir.normalized_fast(), time: 0.0 };
+        let mut hit = RayHit::NULL;
+        self.top_bvh.intersect(ray, self, &mut hit);
+        if hit.is_null(){
+            return false;
+        }
+        self.cam.focal_distance = hit.t;
+        true
+    }
+
+    // the first hit through the point u, v of the screen
+    pub fn pick(&self, u: f32, v: f32, aspect: f32) -> Option<RayHit>{
+        if self.top_bvh.vertices.is_empty(){
+            return None;
+        }
+        let ray = Ray{ pos: self.cam.pos, dir: self.cam.screen_dir(u, v, aspect), time: 0.0 };
+        let mut hit = RayHit::NULL;
+        self.top_bvh.intersect(ray, self, &mut hit);
+        if hit.is_null() { None } else { Some(hit) }
+    }
+
+    // the primitive of the top level bvh through the point u, v of the screen
+    pub fn pick_primitive(&self, u: f32, v: f32, aspect: f32) -> Option<Primitive>{
+        self.pick(u, v, aspect).and_then(|hit| self.primitives.get(hit.object as usize).copied())
+    }
+
+    // the part of the screen the bounds of a primitive cover: left, top, right and bottom in 0..1
+    pub fn screen_rect(&self, prim: &Primitive, aspect: f32) -> Option<[f32; 4]>{
+        let AABB{ min, max } = self.primitive_bound(prim);
+        let mut rect = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+        for i in 0..8{
+            let corner = Vec3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            );
+            let (u, v) = self.cam.screen_point(corner, aspect)?;
+            rect = [rect[0].min(u), rect[1].min(v), rect[2].max(u), rect[3].max(v)];
+        }
+        Some(rect)
+    }
+
+    // middle of the bounds of the top level bvh
+    pub fn centre(&self) -> Vec3{
+        self.top_bvh.vertices.first().map(|v| v.bound.midpoint()).unwrap_or(Vec3::ZERO)
+    }
+
     fn put_in_scene_params(&mut self, i: usize, v: Vec3){
         self.scene_params[i    ] = v.x.to_bits() as u32;
         self.scene_params[i + 1] = v.y.to_bits() as u32;
@@ -374,6 +641,10 @@ impl Scene{
             buffer.push(model.rot.x.to_bits() as u32);
             buffer.push(model.rot.y.to_bits() as u32);
             buffer.push(model.rot.z.to_bits() as u32);
+            buffer.push(model.motion.x.to_bits());
+            buffer.push(model.motion.y.to_bits());
+            buffer.push(model.motion.z.to_bits());
+            buffer.push(model.spin.to_bits());
             buffer.push(model.mat);
             buffer.push(model.mesh);
         }
@@ -435,6 +706,7 @@ impl Scene{
                 Ok(x) => {
                     info.textures.push((name, x.pixels.len() as u64));
                     self.textures.push(x);
+                    self.texture_files.push((path, ttype));
                 },
                 Err(e) => {
                     println!("Error: could not create texture \"{}\": {:?}", name, e);
@@ -444,11 +716,42 @@ impl Scene{
         info.set_time_point("Loading textures");
     }
 
+    pub fn texture_paths(&self) -> impl Iterator<Item = &str>{
+        self.texture_files.iter().map(|(path, _)| path.as_str())
+    }
+
+    // loads the textures from a file again, false if none came from it or it could not be read
+    pub fn reload_texture(&mut self, path: &str) -> bool{
+        let mut reloaded = false;
+        for (i, (file, ttype)) in self.texture_files.iter().enumerate(){
+            if file != path { continue; }
+            let tex = if *ttype == TexType::Vector3c8bpc { TraceTex::vector_tex(path) }
+            else { TraceTex::scalar_tex(path) };
+            match tex{
+                Ok(x) => {
+                    self.textures[i] = x;
+                    reloaded = true;
+                },
+                Err(e) => println!("Error: could not reload texture {}: {:?}", path, e),
+            }
+        }
+        reloaded
+    }
+
     pub fn set_skybox(&mut self, name: &str){
         self.skybox = self.get_texture(name);
         self.sky_box = self.skybox;
     }
 
+    pub fn set_procedural_sky(&mut self, sky: ProceduralSky){
+        self.procedural_sky = Some(sky);
+    }
+
+    pub fn set_fog(&mut self, medium: Medium, distance: f32){
+        self.fog = Some(medium);
+        self.fog_distance = distance;
+    }
+
     pub fn set_sky_intensity(&mut self, int: f32, min: f32, pow: f32){
         self.sky_intensity = int;
         self.sky_min = min;
@@ -456,30 +759,33 @@ impl Scene{
     }
 
     pub fn add_light(&mut self, l: Light){
-        if self.stype == RenderType::GI { return; }
         self.lights.push(l);
     }
 
-    pub fn is_not_ok(&self, mat: MaterialIndex) -> bool{
-        let e = self.mats[mat as usize].emittance;
-        e > 0.0 && self.stype == RenderType::Whitted
+    pub fn is_emissive(&self, mat: MaterialIndex) -> bool{
+        self.mats[mat as usize].emittance > 0.0
     }
 
     pub fn add_sphere(&mut self, s: Sphere){
-        if self.is_not_ok(s.mat) { return; }
         self.spheres.push(s);
     }
 
     pub fn add_plane(&mut self, p: Plane){
-        if self.is_not_ok(p.mat) { return; }
         self.planes.push(p);
     }
 
     pub fn add_triangle(&mut self, t: Triangle){
-        if self.is_not_ok(t.mat) { return; }
         self.triangles.push(t);
     }
 
+    pub fn add_rect(&mut self, r: Rect){
+        let b = r.pos.added(r.u);
+        let c = r.pos.added(r.u).added(r.v);
+        let d = r.pos.added(r.v);
+        self.add_triangle(Triangle{ a: r.pos, b, c, mat: r.mat });
+        self.add_triangle(Triangle{ a: r.pos, b: c, c: d, mat: r.mat });
+    }
+
     pub fn add_mesh(&mut self, mesh_name: String) -> MeshIndex {
         if let Some(i) = self.meshes.iter().position(|m| *m.name == mesh_name) {
             i as u32
@@ -507,49 +813,142 @@ impl Scene{
         &self.triangles[mesh.start + index]
     }
 
-    #[inline]
-    pub fn gen_top_bvh(&mut self) {
-        // gather aabbs
-        let mut prims: Vec<Primitive> = vec![];
-        let mut aabbs: Vec<AABB> = vec![];
-        // build primitives
-        // spheres
-        for (i, sphere) in self.spheres.iter().enumerate() {
-            aabbs.push(AABB::from_point_radius(sphere.pos, sphere.rad));
-            prims.push(Primitive{
-                shape_type: Shape::SPHERE,
-                index: i
-            });
+    // bounds of a primitive of the top level bvh
+    pub fn primitive_bound(&self, prim: &Primitive) -> AABB{
+        match prim.shape_type{
+            Shape::SPHERE => {
+                let sphere = &self.spheres[prim.index];
+                AABB::from_point_radius(sphere.pos, sphere.rad)
+            },
+            Shape::TRIANGLE => {
+                let tri = &self.triangles[prim.index];
+                AABB::from_points(&[tri.a, tri.b, tri.c])
+            },
+            Shape::MODEL => self.model_bound(&self.models[prim.index]),
         }
-        for (i, model) in self.models.iter().enumerate() {
-            let sub_bvh: &Bvh = &self.sub_bvhs[model.mesh as usize];
-            let aabb = sub_bvh.vertices.first().unwrap().bound;
-            // rotate aabb and recompute surrounding aabb
-
-            // obtain 8 corner points
-            let a = aabb.min;
-            let b = aabb.max;
-            let d = b.subed(a);
+    }
 
-            let mut points = vec![a; 8];
-            points[1].x += d.x; points[2].x += d.x; points[5].x += d.x; points[6].x += d.x;
-            points[4].y += d.y; points[5].y += d.y; points[6].y += d.y; points[7].y += d.y;
-            points[2].z += d.z; points[3].z += d.z; points[6].z += d.z; points[7].z += d.z;
+    fn model_bound(&self, model: &Model) -> AABB{
+        let sub_bvh: &Bvh = &self.sub_bvhs[model.mesh as usize];
+        let aabb = sub_bvh.vertices.first().unwrap().bound;
+        // rotate aabb and recompute surrounding aabb
+
+        // obtain 8 corner points
+        let a = aabb.min;
+        let b = aabb.max;
+        let d = b.subed(a);
+
+        let mut corners = [a; 8];
+        corners[1].x += d.x; corners[2].x += d.x; corners[5].x += d.x; corners[6].x += d.x;
+        corners[4].y += d.y; corners[5].y += d.y; corners[6].y += d.y; corners[7].y += d.y;
+        corners[2].z += d.z; corners[3].z += d.z; corners[6].z += d.z; corners[7].z += d.z;
+
+        // a moving model is bounded by its boxes over the shutter interval
+        let steps = if model.is_moving() { Self::MOTION_STEPS } else { 0 };
+        let mut points = Vec::with_capacity(8 * (steps + 1));
+        for step in 0..=steps{
+            let t = if steps == 0 { 0.0 } else { step as f32 / steps as f32 };
+            let (pos, yaw) = model.transform_at(t);
+            points.extend(corners.iter().map(|corner| corner.yawed(yaw).added(pos)));
+        }
 
-            points = points.iter_mut().map(|point| point
-                .subed(aabb.midpoint()).yawed(model.rot.x).added(aabb.midpoint()) // apply model rotation
-                .added(model.pos) // add model position
-            ).collect();
+        // between samples a corner moves on an arc that bulges out of the sampled boxes
+        // by at most its sagitta, so the box is grown by that horizontally
+        let radius = corners.iter().map(|c| (c.x * c.x + c.z * c.z).sqrt()).fold(0.0, f32::max);
+        let step_yaw = if steps == 0 { 0.0 } else { model.spin.abs() / steps as f32 };
+        // past half a turn per step any point on the circle may be missed
+        let sagitta = if step_yaw < std::f32::consts::PI {
+            radius * (1.0 - (step_yaw * 0.5).cos())
+        } else {
+            2.0 * radius
+        };
+        AABB::from_points(&points).grown(Vec3::new(sagitta, 0.0, sagitta))
+    }
 
-            let aabb = AABB::from_points(&points);
-            aabbs.push(aabb);
+    #[inline]
+    pub fn gen_top_bvh(&mut self) {
+        // build primitives
+        let mut prims: Vec<Primitive> = vec![];
+        for i in 0..self.spheres.len() {
+            prims.push(Primitive::from_sphere(i));
+        }
+        for i in 0..self.models.len() {
             prims.push(Primitive {
                 shape_type: Shape::MODEL,
-                index: i as usize
+                index: i
             });
         }
+        // triangles that are not part of a mesh
+        let mut in_mesh = vec![false; self.triangles.len()];
+        for mesh in &self.meshes{
+            in_mesh[mesh.start..mesh.start + mesh.count].fill(true);
+        }
+        for (i, in_mesh) in in_mesh.iter().enumerate(){
+            if *in_mesh { continue; }
+            prims.push(Primitive::from_triangle(i));
+        }
+        // gather aabbs
+        let mut aabbs: Vec<AABB> = prims.iter().map(|prim| self.primitive_bound(prim)).collect();
         // build bvh over aabbs
         self.top_bvh = Bvh::from_primitives(&mut aabbs, &mut prims);
         self.primitives = prims;
+        self.find_area_lights();
+    }
+
+    // emissive primitives can be sampled as area lights
+    pub fn find_area_lights(&mut self){
+        self.area_lights = self.primitives.iter().filter(|prim| match prim.shape_type{
+            Shape::SPHERE => self.is_emissive(self.spheres[prim.index].mat),
+            Shape::TRIANGLE => self.is_emissive(self.triangles[prim.index].mat),
+            Shape::MODEL => false,
+        }).copied().collect();
+    }
+
+    pub fn primitive_material(&self, prim: &Primitive) -> MaterialIndex{
+        match prim.shape_type{
+            Shape::SPHERE => self.spheres[prim.index].mat,
+            Shape::TRIANGLE => self.triangles[prim.index].mat,
+            Shape::MODEL => self.models[prim.index].mat,
+        }
+    }
+
+    // a material as it is in the scene buffer: its offset and its floats
+    pub fn get_material_buffer(&self, mat: MaterialIndex) -> (usize, Vec<f32>){
+        let mut data = self.mats[mat as usize].get_data();
+        data.resize(Self::MATERIAL_SIZE as usize, 0.0);
+        // the materials come first in the scene buffer
+        (mat as usize * Self::MATERIAL_SIZE as usize, data)
+    }
+
+    // fits the top level bvh around primitives that moved, keeps its structure
+    pub fn refit_top_bvh(&mut self){
+        let aabbs: Vec<AABB> = self.primitives.iter().map(|prim| self.primitive_bound(prim)).collect();
+        self.top_bvh.refit(&aabbs);
+    }
+
+    // moves a primitive by off and turns it by yaw radians around its middle
+    pub fn transform_primitive(&mut self, prim: &Primitive, off: Vec3, yaw: f32){
+        match prim.shape_type{
+            Shape::SPHERE => self.spheres[prim.index].pos.add(off),
+            Shape::MODEL => {
+                let model = &mut self.models[prim.index];
+                model.pos.add(off);
+                // an unset rotation faces forward
+                let mut ori = if model.rot == Vec3::ZERO {
+                    Orientation{ yaw: 0.0, roll: 0.0 }
+                } else {
+                    model.rot.orientation()
+                };
+                ori.yaw += yaw;
+                model.rot = Vec3::from_orientation(&ori);
+            },
+            Shape::TRIANGLE => {
+                let tri = &mut self.triangles[prim.index];
+                let mid = tri.a.added(tri.b).added(tri.c).scaled(1.0 / 3.0);
+                for p in [&mut tri.a, &mut tri.b, &mut tri.c]{
+                    *p = p.subed(mid).yawed(yaw).added(mid).added(off);
+                }
+            },
+        }
     }
 }
@@ -1,5 +1,6 @@
-use crate::scene::{ Scene, SceneItem, Plane, Sphere };
-use crate::material::Material;
+use crate::scene::{ Scene, SceneItem, Plane, Sphere, Model };
+use crate::material::{ Material, Medium };
+use crate::spectral::Dispersion;
 use crate::vec3::{ Vec3 };
 use crate::trace_tex::{ TexType };
 use crate::consts::*;
@@ -95,6 +96,7 @@ pub fn gi_scene(scene: &mut Scene){
         mat: Material::basic()
             .as_dielectric()
             .with_refraction(1.5)
+            .with_dispersion(Dispersion::BK7)
             .with_roughness(0.01)
             .add_to_scene(scene)
     }.add(scene);
@@ -110,6 +112,32 @@ pub fn gi_scene(scene: &mut Scene){
             .add_to_scene(scene)
     }.add(scene);
 
+    // a glass ball filled with smoke
+    Sphere{
+        pos: Vec3::new(4.0, 0.0, -5.0),
+        rad: 1.0 - EPSILON,
+        mat: Material::basic()
+            .as_dielectric()
+            .with_refraction(1.5)
+            .with_roughness(0.01)
+            .with_medium(Medium::new(2.0, Vec3::uni(0.9), 0.3))
+            .add_to_scene(scene)
+    }.add(scene);
+
+    // sliding and turning while the shutter is open, blurred with a shutter in [camera]
+    Model{
+        pos: Vec3::new(10.0, 9.0, -30.0),
+        rot: Vec3::BACKWARD,
+        motion: Vec3::ZERO,
+        spin: 0.0,
+        mat: Material::basic()
+            .as_conductor()
+            .with_roughness(0.2)
+            .with_specular(GOLD_SPEC)
+            .add_to_scene(scene),
+        mesh: scene.add_mesh("assets/models/teapot-low.obj".parse().unwrap()),
+    }.with_motion(Vec3::new(2.0, 0.0, 0.0), 0.3).add(scene);
+
     Sphere{
         pos: Vec3::new(0.0, 4.0, 3.0),
         rad: 2.0,
@@ -14,6 +14,8 @@ pub fn whitted_scene(scene: &mut Scene){
     let mut dragon = Model{
         pos: Default::default(),
         rot: Default::default(),
+        motion: Vec3::ZERO,
+        spin: 0.0,
         mat: Material::basic().with_colour(Vec3::new(1.0, 0.5, 0.4)).add_to_scene(scene),
         // mat: Material::basic().as_dielectric().with_refraction(WATER_REFRACTION).add_to_scene(scene),
         mesh: scene.add_mesh("assets/models/dragon.obj".parse().unwrap())
@@ -35,9 +37,5 @@ pub fn whitted_scene(scene: &mut Scene){
         scene.add_model(dragon);
     }
 
-    Light{
-        pos: Vec3::new(0.0, 3.0, 0.0),
-        intensity: 1000.0,
-        col: Vec3::ONE,
-    }.add(scene);
+    Light::point(Vec3::new(0.0, 3.0, 0.0), 1000.0, Vec3::ONE).add(scene);
 }
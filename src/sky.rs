@@ -0,0 +1,264 @@
+use crate::vec3::Vec3;
+use crate::consts::{ PI, EPSILON };
+
+// Preetham et al. (1999): A practical analytic model for daylight
+// https://www2.cs.duke.edu/courses/cps124/spring08/assign/07_papers/p91-preetham.pdf
+
+// scales the luminance (kcd/m^2) of the model to the range the renderers work in
+pub const PREETHAM_SCALE: f32 = 0.05;
+// angular radius of the sun as seen from earth, in radians
+pub const SUN_RADIUS: f32 = 0.00465;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SkyCoefficients{
+    pub perez_y: [f32; 5],
+    pub perez_x: [f32; 5],
+    pub perez_yy: [f32; 5],
+    pub zenith: Vec3, // (Y, x, y), already divided by the perez function at the zenith
+    pub sun_dir: Vec3,
+    pub sun_cos: f32, // cosine of the sun's angular radius
+    pub sun_radiance: Vec3,
+    pub ground: Vec3,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProceduralSky{
+    pub time_of_day: f32, // hours, sunrise at 6 and sunset at 18
+    pub sun_tilt: f32, // tilt of the sun's arc from straight overhead, in radians
+    pub turbidity: f32,
+    pub ground_albedo: Vec3,
+    pub sun_radius: f32,
+    pub sun_irradiance: f32,
+    coef: SkyCoefficients,
+}
+
+impl ProceduralSky{
+    pub fn new(time_of_day: f32, turbidity: f32, ground_albedo: Vec3) -> Self{
+        let mut sky = Self{
+            time_of_day,
+            sun_tilt: 0.5,
+            turbidity,
+            ground_albedo,
+            sun_radius: SUN_RADIUS,
+            sun_irradiance: 3.0,
+            coef: SkyCoefficients{
+                perez_y: [0.0; 5],
+                perez_x: [0.0; 5],
+                perez_yy: [0.0; 5],
+                zenith: Vec3::ZERO,
+                sun_dir: Vec3::UP,
+                sun_cos: 1.0,
+                sun_radiance: Vec3::ZERO,
+                ground: Vec3::ZERO,
+            },
+        };
+        sky.update();
+        sky
+    }
+
+    pub fn with_sun_tilt(mut self, tilt: f32) -> Self{
+        self.sun_tilt = tilt;
+        self.update();
+        self
+    }
+
+    pub fn with_sun(mut self, radius: f32, irradiance: f32) -> Self{
+        self.sun_radius = radius.max(EPSILON);
+        self.sun_irradiance = irradiance;
+        self.update();
+        self
+    }
+
+    pub fn advance_time(&mut self, hours: f32){
+        self.time_of_day = (self.time_of_day + hours).rem_euclid(24.0);
+        self.update();
+    }
+
+    pub fn coefficients(&self) -> &SkyCoefficients{
+        &self.coef
+    }
+
+    // the sun moves from east (+x) over the sky to west (-x), tilted towards -z
+    pub fn sun_dir(&self) -> Vec3{
+        let a = (self.time_of_day - 6.0) / 12.0 * PI;
+        Vec3{
+            x: a.cos(),
+            y: a.sin() * self.sun_tilt.cos(),
+            z: -a.sin() * self.sun_tilt.sin(),
+        }.normalized_fast()
+    }
+
+    // solid angle of the sun disk
+    pub fn sun_solid_angle(&self) -> f32{
+        2.0 * PI * (1.0 - self.coef.sun_cos)
+    }
+
+    // recompute the coefficients after changing any of the parameters
+    pub fn update(&mut self){
+        let t = self.turbidity.max(1.0);
+        let sun_dir = self.sun_dir();
+        let theta_s = sun_dir.y.clamp(0.0, 1.0).acos();
+        let perez_y = [
+             0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+             0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let perez_x = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let perez_yy = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zen_y = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zen_x =
+            t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1) +
+            t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394) +
+            (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let zen_yy =
+            t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1) +
+            t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516) +
+            (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+        let cos_s = theta_s.cos();
+        let zenith = Vec3::new(
+            zen_y / perez(&perez_y, 1.0, theta_s, cos_s),
+            zen_x / perez(&perez_x, 1.0, theta_s, cos_s),
+            zen_yy / perez(&perez_yy, 1.0, theta_s, cos_s),
+        );
+        let sun_cos = self.sun_radius.cos();
+        self.coef = SkyCoefficients{
+            perez_y, perez_x, perez_yy, zenith, sun_dir, sun_cos,
+            sun_radiance: Vec3::ZERO,
+            ground: Vec3::ZERO,
+        };
+        // sun radiance such that the disk delivers sun_irradiance, attenuated by the atmosphere
+        let solid_angle = self.sun_solid_angle();
+        self.coef.sun_radiance = sun_transmittance(t, sun_dir.y)
+            .scaled(self.sun_irradiance / solid_angle);
+        // the ground reflects the light of the sky at the horizon
+        self.coef.ground = sky_radiance(&self.coef, horizon_away_from(sun_dir), false).muled(self.ground_albedo);
+    }
+
+    pub fn radiance(&self, dir: Vec3, with_sun: bool) -> Vec3{
+        sky_radiance(&self.coef, dir, with_sun)
+    }
+
+    // writes the coefficients in the layout the kernel expects
+    pub fn get_data(&self) -> Vec<f32>{
+        let c = &self.coef;
+        let mut data = vec![
+            c.sun_dir.x, c.sun_dir.y, c.sun_dir.z, c.sun_cos,
+            c.sun_radiance.x, c.sun_radiance.y, c.sun_radiance.z,
+        ];
+        data.extend_from_slice(&c.perez_y);
+        data.extend_from_slice(&c.perez_x);
+        data.extend_from_slice(&c.perez_yy);
+        data.extend_from_slice(&[c.zenith.x, c.zenith.y, c.zenith.z]);
+        data.extend_from_slice(&[c.ground.x, c.ground.y, c.ground.z]);
+        data
+    }
+}
+
+// the direction on the horizon opposite of the sun,
+// with the sun at the zenith every direction on the horizon is alike
+fn horizon_away_from(sun_dir: Vec3) -> Vec3{
+    let flat = Vec3::new(sun_dir.x, 0.0, sun_dir.z);
+    if flat.len() < EPSILON{
+        Vec3::RIGHT
+    } else {
+        flat.normalized().neged()
+    }
+}
+
+#[inline]
+fn perez(coef: &[f32; 5], cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32{
+    (1.0 + coef[0] * (coef[1] / cos_theta.max(0.01)).exp()) *
+    (1.0 + coef[2] * (coef[3] * gamma).exp() + coef[4] * cos_gamma * cos_gamma)
+}
+
+// https://en.wikipedia.org/wiki/SRGB
+#[inline]
+pub fn xyy_to_rgb(big_y: f32, x: f32, y: f32) -> Vec3{
+    if y < EPSILON { return Vec3::ZERO; }
+    let big_x = x / y * big_y;
+    let big_z = (1.0 - x - y) / y * big_y;
+    Vec3::new(
+         3.2406 * big_x - 1.5372 * big_y - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * big_y + 0.0415 * big_z,
+         0.0557 * big_x - 0.2040 * big_y + 1.0570 * big_z,
+    ).clamped(0.0, f32::MAX)
+}
+
+pub fn sky_radiance(c: &SkyCoefficients, dir: Vec3, with_sun: bool) -> Vec3{
+    if dir.y < 0.0 {
+        return c.ground;
+    }
+    let cos_gamma = dir.dot(c.sun_dir).clamp(-1.0, 1.0);
+    if with_sun && cos_gamma >= c.sun_cos {
+        return c.sun_radiance;
+    }
+    let gamma = cos_gamma.acos();
+    let cos_theta = dir.y;
+    let big_y = c.zenith.x * perez(&c.perez_y, cos_theta, gamma, cos_gamma);
+    let x = c.zenith.y * perez(&c.perez_x, cos_theta, gamma, cos_gamma);
+    let y = c.zenith.z * perez(&c.perez_yy, cos_theta, gamma, cos_gamma);
+    xyy_to_rgb(big_y, x, y).scaled(PREETHAM_SCALE)
+}
+
+// Rayleigh and aerosol extinction of sunlight along its path through the atmosphere
+// (Preetham et al. appendix, evaluated at a red, green and blue wavelength in micrometers)
+fn sun_transmittance(turbidity: f32, cos_theta: f32) -> Vec3{
+    if cos_theta <= 0.0 { return Vec3::ZERO; }
+    let theta_deg = cos_theta.acos().to_degrees();
+    // Kasten and Young air mass
+    let m = 1.0 / (cos_theta + 0.50572 * (96.07995 - theta_deg).powf(-1.6364));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let ext = |lambda: f32| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        (-m * (rayleigh + aerosol)).exp()
+    };
+    Vec3::new(ext(0.68), ext(0.55), ext(0.44))
+}
+
+#[cfg(test)]
+mod test{
+    use crate::sky::{ ProceduralSky, horizon_away_from };
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn sun_is_up_at_noon(){
+        let sky = ProceduralSky::new(12.0, 3.0, Vec3::uni(0.3));
+        assert!(sky.sun_dir().y > 0.8);
+        let sky = ProceduralSky::new(0.0, 3.0, Vec3::uni(0.3));
+        assert!(sky.sun_dir().y < -0.8);
+    }
+
+    #[test]
+    fn sky_is_brighter_near_the_sun(){
+        let sky = ProceduralSky::new(9.0, 3.0, Vec3::uni(0.3));
+        let sun = sky.sun_dir();
+        let near = sun.added(Vec3::new(0.0, 0.1, 0.0)).normalized();
+        let far = Vec3::new(-sun.x, 0.3, -sun.z).normalized();
+        assert!(sky.radiance(near, false).sum() > sky.radiance(far, false).sum());
+        assert!(sky.radiance(sun, true).sum() > sky.radiance(sun, false).sum());
+    }
+
+    #[test]
+    fn horizon_is_defined_with_the_sun_at_the_zenith(){
+        assert_eq!(horizon_away_from(Vec3::UP), Vec3::RIGHT);
+        let h = horizon_away_from(Vec3::new(0.6, 0.8, 0.0));
+        assert!((h.x + 1.0).abs() < 0.0001 && h.y == 0.0);
+    }
+}
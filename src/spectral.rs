@@ -0,0 +1,50 @@
+// Hero wavelength spectral sampling (Wilkie et al. 2014), done by the kernel.
+// The host only packs the dispersion of materials.
+// Wavelengths are in nanometers, dispersion formulas take micrometers.
+
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_RANGE: f32 = 340.0;
+// wavelength at which Material::refraction is defined, the sodium d-line
+pub const LAMBDA_D: f32 = 587.6;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dispersion{
+    None,
+    // n = refraction + b * (1 / lambda^2 - 1 / lambda_d^2)
+    Cauchy{ b: f32 },
+    // n^2 = 1 + sum b * lambda^2 / (lambda^2 - c), ignores Material::refraction
+    Sellmeier{ b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion{
+    pub const BK7: Self = Self::Sellmeier{
+        b: [1.039_612, 0.231_792_34, 1.010_469_5],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+
+    // type and coefficients in the layout the kernel expects
+    pub fn get_data(&self) -> [f32; 7]{
+        match self{
+            Self::None => [0.0; 7],
+            Self::Cauchy{ b } => [1.0, *b, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Self::Sellmeier{ b, c } => [2.0, b[0], b[1], b[2], c[0], c[1], c[2]],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use crate::spectral::*;
+
+    #[test]
+    fn packs_dispersion_for_the_kernel(){
+        assert_eq!(Dispersion::None.get_data(), [0.0; 7]);
+        assert_eq!(Dispersion::Cauchy{ b: 0.0117 }.get_data(), [1.0, 0.0117, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let data = Dispersion::BK7.get_data();
+        assert_eq!(data[0], 2.0);
+        // the coefficients give the catalogue index of BK7 at the d-line
+        let l2 = (LAMBDA_D * 0.001).powi(2);
+        let n2 = 1.0 + (0..3).map(|i| data[1 + i] * l2 / (l2 - data[4 + i])).sum::<f32>();
+        assert!((n2.sqrt() - 1.5168).abs() < 0.001);
+    }
+}
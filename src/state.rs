@@ -1,21 +1,44 @@
 use crate::scene::{ Scene, RenderType };
 use crate::vec3::Vec3;
-use crate::config::ControlsParsed;
+use crate::config::{ ControlsParsed, ScrollAction };
+use crate::consts::{ MIN_FOV, MAX_FOV };
+use crate::aov::Aov;
+use crate::camera_path::{ CameraPath, Keyframe };
+use crate::primitive::Primitive;
+use crate::material::{ MaterialIndex, MaterialProperty };
+use crate::bindings::{ Action, Modifiers };
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use sdl2::controller::Axis;
 
-use std::f32::consts::{ PI, FRAC_PI_2 };
+use std::path::{ Path, PathBuf };
+use std::collections::HashSet;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum LoopRequest{
     Continue,
     Stop,
     Export,
+    Screenshot,
 }
 
-const KEYS_AMOUNT: usize = 13;
-pub type Keymap = Vec<Option<Keycode>>;
+const SUN_SPEED: f32 = 0.05; // hours per frame
+// the sensitivities are per frame at this frame time
+const REFERENCE_FRAME_MS: f32 = 1000.0 / 60.0;
+// frame time steps a slow frame moves at most, a stall doesn't fling the camera away
+const MAX_FRAME_STEPS: f32 = 10.0;
+const SCROLL_SPEED_FACTOR: f32 = 1.25;
+const SCROLL_FOV_STEP: f32 = 5.0; // degrees
+// part of the distance to the orbit target a step of the scroll wheel dollies
+const SCROLL_DOLLY: f32 = 0.1;
+// pixels the mouse may move between pressing and releasing the left button for a click, more is a drag
+const CLICK_SLOP: i32 = 4;
+// sticks and triggers of a gamepad
+const AXES_AMOUNT: usize = 6;
+// where the camera is saved to
+const CAMERA_FILE: &str = "camera.toml";
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum RenderMode{
@@ -59,24 +82,47 @@ impl Settings{
 
 #[derive(Clone, Debug)]
 pub struct State{
-    pub key_map: Keymap,
-    keys: [bool; KEYS_AMOUNT],
+    pub controls: ControlsParsed,
+    held: HashSet<Action>, // actions of the keys and buttons that are down
+    axes: [f32; AXES_AMOUNT], // of the gamepad, -1 to 1 outside the deadzone
+    pub mouse_look: bool, // the window captures the mouse while the look button is held
+    pub frame_ms: f32, // duration of the last frame
+    pub window_size: (u32, u32),
     pub render_mode: RenderMode,
     pub last_frame: RenderMode,
     pub reduced_rate: usize,
     pub aa: usize,
     pub samples_taken: usize,
     pub show_bvh: bool,
+    pub show_convergence: bool,
+    pub aov: Aov, // shown instead of the image when not beauty
+    pub last_aov: Aov, // shown again when the aov view is toggled on
     pub settings: Settings,
     pub moved: bool,
+    pub reprojectable: bool, // only the view changed, the accumulation can follow it
     pub frame_energy: f32,
+    pub camera_path: CameraPath, // keyframes are recorded into it
+    pub camera_path_file: Option<PathBuf>,
+    pub selected: Option<Primitive>, // clicked object, outlined in the window
+    pub pressed_at: Option<(i32, i32)>, // where the left button went down
+    pub editing: bool, // the move and look keys move and turn the selected object
+    pub scene_changed: bool, // objects moved this frame, the gpu needs them again
+    pub material_property: MaterialProperty, // what the material keys tweak on the selected object
+    pub changed_material: Option<MaterialIndex>, // tweaked this frame
+    pub show_hud: bool, // the overlay with the frame stats
+    pub home: Option<Keyframe>, // the camera of the first frame, resetting the camera returns to it
+    pub post_changed: bool, // the post stack changed this frame, the gpu needs it again
 }
 
 impl State{
     pub fn new(conf: &ControlsParsed, settings: Settings) -> Self{
         Self{
-            key_map: conf.key_map.clone(),
-            keys: [false; KEYS_AMOUNT],
+            controls: conf.clone(),
+            held: HashSet::new(),
+            axes: [0.0; AXES_AMOUNT],
+            mouse_look: false,
+            frame_ms: 0.0,
+            window_size: (1, 1),
             render_mode: RenderMode::Reduced,
             last_frame: RenderMode::None,
             reduced_rate: 4,
@@ -84,8 +130,46 @@ impl State{
             samples_taken: 0,
             settings,
             show_bvh: false,
+            show_convergence: false,
+            aov: Aov::Beauty,
+            last_aov: Aov::Beauty.cycled(),
             moved: true,
+            reprojectable: false,
             frame_energy: 0.0,
+            camera_path: CameraPath::default(),
+            camera_path_file: None,
+            selected: None,
+            pressed_at: None,
+            editing: false,
+            scene_changed: false,
+            material_property: MaterialProperty::Roughness,
+            changed_material: None,
+            show_hud: true,
+            home: None,
+            post_changed: false,
+        }
+    }
+
+    // recorded keyframes are appended to the path in the file and written back
+    pub fn with_camera_path(mut self, file: &str) -> Self{
+        let file = PathBuf::from(file);
+        if file.exists(){
+            match CameraPath::read(&file){
+                Ok(path) => self.camera_path = path,
+                Err(e) => println!("{}", e),
+            }
+        }
+        self.camera_path_file = Some(file);
+        self
+    }
+
+    pub fn record_keyframe(&mut self, scene: &Scene){
+        self.camera_path.keys.push(Keyframe::from_camera(&scene.cam));
+        if let Some(file) = &self.camera_path_file{
+            match self.camera_path.write(file){
+                Ok(()) => println!("Recorded keyframe {} to {:?}", self.camera_path.keys.len(), file),
+                Err(e) => println!("{}", e),
+            }
         }
     }
 
@@ -110,6 +194,7 @@ pub type InputFn = fn (_events: &[Event], _scene: &mut Scene, _state: &mut State
 pub type UpdateFn = fn (_dt: f32, _state: &mut State) -> LoopRequest;
 
 pub fn std_update_fn(dt: f32, state: &mut State) -> LoopRequest {
+    state.frame_ms = dt;
     if state.last_frame == RenderMode::Reduced && dt > state.settings.max_reduced_ms{
         state.reduced_rate += 1;
     }
@@ -150,9 +235,23 @@ pub fn std_input_fn(events: &[Event], _: &mut Scene, _: &mut State) -> LoopReque
 }
 
 pub fn fps_input_fn(events: &[Event], scene: &mut Scene, state: &mut State) -> LoopRequest{
-    let cam = &mut scene.cam;
-    let old_pos = cam.pos;
-    let old_dir = cam.dir;
+    let old_pos = scene.cam.pos;
+    let old_dir = scene.cam.dir;
+    let old_fov = scene.cam.fov;
+    let mut sky_changed = false;
+    let mut refocused = false;
+    let mut edited = false;
+    let mut changed_material = None;
+    let mut restyled = false;
+    // the actions of the keys and buttons pressed this frame
+    let mut pressed = Vec::new();
+    let home = *state.home.get_or_insert_with(|| Keyframe::from_camera(&scene.cam));
+    // look in radians, from the mouse and the keys
+    let (mut yaw, mut roll) = (0.0, 0.0);
+    // movement along the view, to the right and up; dollies and pans in orbit mode
+    let (mut forward, mut right, mut up) = (0.0, 0.0, 0.0);
+    let (w, h) = state.window_size;
+    let aspect = w as f32 / h as f32;
 
     for event in events.iter() {
         match event {
@@ -160,87 +259,255 @@ pub fn fps_input_fn(events: &[Event], scene: &mut Scene, state: &mut State) -> L
             Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                 return LoopRequest::Stop;
             },
-            Event::KeyDown { keycode: Some(x), .. } if Some(*x) == state.key_map[10] => {
-                state.toggle_focus_mode();
+            Event::KeyDown { keycode: Some(x), keymod, repeat, .. } => {
+                for action in state.controls.bindings.pressed(*x, Modifiers::from_keymod(*keymod)){
+                    if action.is_held(){
+                        state.held.insert(action);
+                    } else if !*repeat || action.repeats(){
+                        pressed.push(action);
+                    }
+                }
+            },
+            Event::KeyUp { keycode: Some(x), .. } => {
+                for action in state.controls.bindings.released(*x){
+                    state.held.remove(&action);
+                }
             },
-            Event::KeyDown { keycode: Some(x), .. } if Some(*x) == state.key_map[11] => {
-                return LoopRequest::Export;
+            Event::ControllerButtonDown { button, .. } => {
+                for action in state.controls.gamepad.actions(*button){
+                    if action.is_held(){
+                        state.held.insert(action);
+                    } else {
+                        pressed.push(action);
+                    }
+                }
             },
-            Event::KeyDown { keycode: Some(x), .. } if Some(*x) == state.key_map[12] => {
-                print!("Toggle BVH rendering");
-                state.toggle_show_bvh();
+            Event::ControllerButtonUp { button, .. } => {
+                for action in state.controls.gamepad.actions(*button){
+                    state.held.remove(&action);
+                }
+            },
+            Event::ControllerAxisMotion { axis, value, .. } => {
+                // rescaled to start at the edge of the deadzone
+                let dz = state.controls.gamepad_deadzone;
+                let v = (*value as f32 / i16::MAX as f32).clamp(-1.0, 1.0);
+                state.axes[*axis as usize] = v.signum() * ((v.abs() - dz) / (1.0 - dz)).max(0.0);
+            },
+            Event::ControllerDeviceRemoved { .. } => {
+                state.axes = [0.0; AXES_AMOUNT];
+            },
+            Event::MouseButtonDown { mouse_btn: MouseButton::Right, .. } => {
+                state.mouse_look = true;
+            },
+            Event::MouseButtonUp { mouse_btn: MouseButton::Right, .. } => {
+                state.mouse_look = false;
             },
-            Event::KeyDown { keycode: Some(x), repeat: false, .. } => {
-                for (i, binding) in state.key_map.iter().enumerate(){
-                    if Some(*x) == *binding{
-                        state.keys[i] = true;
+            // in orbit mode a double click on geometry makes it the target
+            Event::MouseButtonDown { mouse_btn: MouseButton::Left, clicks: 2, x, y, .. } if scene.cam.target.is_some() => {
+                let (u, v) = ((*x as f32 + 0.5) / w as f32, (*y as f32 + 0.5) / h as f32);
+                if let Some(hit) = scene.pick(u, v, aspect){
+                    scene.cam.target = Some(hit.pos);
+                    scene.cam.look_at(hit.pos);
+                }
+            },
+            Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } => {
+                state.pressed_at = Some((*x, *y));
+            },
+            // a click selects the object under the cursor, or nothing; a drag doesn't
+            Event::MouseButtonUp { mouse_btn: MouseButton::Left, x, y, .. } => {
+                if let Some((px, py)) = state.pressed_at.take(){
+                    if (x - px).abs() <= CLICK_SLOP && (y - py).abs() <= CLICK_SLOP{
+                        let (u, v) = ((*x as f32 + 0.5) / w as f32, (*y as f32 + 0.5) / h as f32);
+                        state.selected = scene.pick_primitive(u, v, aspect);
+                        if let Some(prim) = state.selected{
+                            println!("Selected {:?} {}", prim.shape_type, prim.index);
+                        }
+                    }
+                }
+            },
+            Event::MouseMotion { mousestate, xrel, yrel, .. } => {
+                let c = &state.controls;
+                let flip = |invert: bool| if invert { -1.0 } else { 1.0 };
+                let dx = *xrel as f32 * c.mouse_sens * flip(c.invert_mouse_x);
+                let dy = *yrel as f32 * c.mouse_sens * flip(c.invert_mouse_y);
+                if let Some(target) = scene.cam.target{
+                    // drag to rotate around the target, drag with the middle button to pan
+                    if mousestate.middle(){
+                        let dist = scene.cam.pos.dist(target);
+                        right -= dx * dist;
+                        up += dy * dist;
+                    } else if mousestate.left() || mousestate.right(){
+                        yaw -= dx;
+                        roll += dy;
                     }
+                } else if state.mouse_look{
+                    yaw += dx;
+                    roll -= dy;
                 }
             },
-            Event::KeyUp { keycode: Some(x), repeat: false, .. } => {
-                for (i, binding) in state.key_map.iter().enumerate(){
-                    if Some(*x) == *binding{
-                        state.keys[i] = false;
+            Event::MouseWheel { y, .. } => {
+                let cam = &mut scene.cam;
+                if let Some(target) = cam.target{
+                    forward += cam.pos.dist(target) * (1.0 - (1.0 - SCROLL_DOLLY).powi(*y));
+                } else {
+                    match state.controls.scroll{
+                        ScrollAction::MoveSpeed => cam.move_sensitivity *= SCROLL_SPEED_FACTOR.powi(*y),
+                        ScrollAction::Fov => cam.fov = (cam.fov - *y as f32 * SCROLL_FOV_STEP).clamp(MIN_FOV, MAX_FOV),
                     }
                 }
             },
             _ => {},
         }
     }
-    let ms = cam.move_sensitivity;
-    let ls = cam.look_sensitivity;
-    for (i, active) in state.keys.iter().enumerate(){
-        if !active { continue; }
-        match i {
-            0 => { // Move Forward; Move into camera direction
-                cam.pos.add(cam.dir.scaled(ms));
+    for action in pressed{
+        match action{
+            Action::FocusMode => state.toggle_focus_mode(),
+            Action::Export => return LoopRequest::Export,
+            Action::SaveScreenshot => return LoopRequest::Screenshot,
+            Action::ShowBvh => {
+                print!("Toggle BVH rendering");
+                state.toggle_show_bvh();
             },
-            1 => { // Move Backward; Move opposite camera direction
-                cam.pos.add(cam.dir.neged().scaled(ms));
+            Action::Autofocus => refocused = scene.autofocus(),
+            Action::ConvergenceMask => state.show_convergence = !state.show_convergence,
+            Action::CycleAov => state.aov = state.aov.cycled(),
+            Action::ToggleAov => {
+                if state.aov == Aov::Beauty{
+                    state.aov = state.last_aov;
+                } else {
+                    state.last_aov = state.aov;
+                    state.aov = Aov::Beauty;
+                }
             },
-            2 => { // Move Left; Move camera direction crossed z-axis, negated
-                cam.pos.add(cam.dir.crossed(Vec3::UP).neged().scaled(ms));
+            Action::Orbit => {
+                let centre = scene.centre();
+                let cam = &mut scene.cam;
+                cam.target = if cam.target.is_some() { None } else { Some(centre) };
+                if let Some(target) = cam.target{
+                    cam.look_at(target);
+                }
             },
-            3 => { // Move Right; Move camera direction crossed z-axis
-                cam.pos.add(cam.dir.crossed(Vec3::UP).scaled(ms));
+            Action::Edit => {
+                state.editing = !state.editing;
+                println!("Editing {}", if state.editing { "on" } else { "off" });
             },
-            4 => { // Move Up; Move camera direction crossed x-axis
-                cam.pos.add(Vec3::UP.neged().scaled(ms));
+            Action::CycleMaterialProperty => {
+                state.material_property = state.material_property.cycled();
+                println!("Tweaking {}", state.material_property.name());
             },
-            5 => { // Move Down; Move camera direction crossed x-axis
-                cam.pos.add(Vec3::DOWN.neged().scaled(ms));
+            Action::MaterialPropertyUp | Action::MaterialPropertyDown => {
+                if let Some(prim) = state.selected{
+                    let mat = scene.primitive_material(&prim);
+                    let up = action == Action::MaterialPropertyUp;
+                    let value = scene.mats[mat as usize].tweak(state.material_property, up);
+                    println!("Material {} {}: {}", mat, state.material_property.name(), value);
+                    if state.material_property == MaterialProperty::Emittance{
+                        scene.find_area_lights();
+                    }
+                    changed_material = Some(mat);
+                }
             },
-            6 => { // Look Up;
-                cam.ori.roll = (cam.ori.roll + ls).min(FRAC_PI_2).max(-FRAC_PI_2);
-                cam.dir = Vec3::from_orientation(&cam.ori);
+            Action::RecordKeyframe => state.record_keyframe(scene),
+            Action::ToggleHud => state.show_hud = !state.show_hud,
+            Action::ResetCamera => {
+                home.apply(&mut scene.cam);
+                scene.cam.target = None;
             },
-            7 => { // Look Down;
-                cam.ori.roll = (cam.ori.roll - ls).min(FRAC_PI_2).max(-FRAC_PI_2);
-                cam.dir = Vec3::from_orientation(&cam.ori);
+            Action::CycleToneMap => {
+                let post = &mut scene.cam.post;
+                post.tone_map = post.tone_map.cycled();
+                println!("Tone map {:?}", post.tone_map);
+                restyled = true;
             },
-            8 => { // Look Left;
-                cam.ori.yaw -= ls;
-                if cam.ori.yaw < -PI {
-                    cam.ori.yaw += 2.0 * PI;
+            Action::SaveCamera => {
+                match Keyframe::from_camera(&scene.cam).write(Path::new(CAMERA_FILE)){
+                    Ok(()) => println!("Saved the camera to {}", CAMERA_FILE),
+                    Err(e) => println!("{}", e),
                 }
-                cam.dir = Vec3::from_orientation(&cam.ori);
             },
-            9 => { // Look Right;
-                cam.ori.yaw += ls;
-                if cam.ori.yaw > PI {
-                    cam.ori.yaw -= 2.0 * PI;
+            _ => {},
+        }
+    }
+    let cam = &scene.cam;
+    // the sensitivities are per reference frame, scaled by the frame time the speed doesn't depend on the fps
+    let steps = (state.frame_ms / REFERENCE_FRAME_MS).min(MAX_FRAME_STEPS);
+    let ms = cam.move_sensitivity * steps;
+    let ls = cam.look_sensitivity * steps;
+    // the keys and sticks move the camera, or the selected object while editing
+    let (mut key_forward, mut key_right, mut key_up, mut key_yaw, mut key_roll) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    // the sticks move and look like the keys, the triggers move down and up
+    let axis = |a: Axis| state.axes[a as usize];
+    key_forward -= axis(Axis::LeftY) * ms;
+    key_right += axis(Axis::LeftX) * ms;
+    key_up += (axis(Axis::TriggerRight) - axis(Axis::TriggerLeft)) * ms;
+    key_yaw += axis(Axis::RightX) * ls;
+    key_roll -= axis(Axis::RightY) * ls;
+    for action in state.held.iter(){
+        match action{
+            Action::MoveForward => { key_forward += ms; },
+            Action::MoveBackward => { key_forward -= ms; },
+            Action::MoveLeft => { key_right -= ms; },
+            Action::MoveRight => { key_right += ms; },
+            Action::MoveUp => { key_up += ms; },
+            Action::MoveDown => { key_up -= ms; },
+            Action::LookUp => { key_roll += ls; },
+            Action::LookDown => { key_roll -= ls; },
+            Action::LookLeft => { key_yaw -= ls; },
+            Action::LookRight => { key_yaw += ls; },
+            Action::SunEarlier | Action::SunLater => { // Move the sun of the procedural sky
+                if let Some(sky) = &mut scene.procedural_sky{
+                    sky.advance_time(if *action == Action::SunEarlier { -SUN_SPEED } else { SUN_SPEED });
+                    sky_changed = true;
                 }
-                cam.dir = Vec3::from_orientation(&cam.ori);
             },
             _ => {},
         }
     }
-    let moved = old_pos != cam.pos || old_dir != cam.dir;
+    match (state.editing, state.selected){
+        (true, Some(prim)) => {
+            // along the same axes the camera moves
+            let off = cam.dir.scaled(key_forward)
+                .added(cam.dir.crossed(Vec3::UP).scaled(key_right))
+                .added(Vec3::UP.neged().scaled(key_up));
+            if off != Vec3::ZERO || key_yaw != 0.0{
+                scene.transform_primitive(&prim, off, key_yaw);
+                scene.refit_top_bvh();
+                edited = true;
+            }
+        },
+        _ => {
+            forward += key_forward;
+            right += key_right;
+            up += key_up;
+            yaw += key_yaw;
+            roll += key_roll;
+        },
+    }
+    let cam = &mut scene.cam;
+    if cam.target.is_some(){
+        cam.pan(right, up);
+        cam.orbit(yaw, roll, forward);
+    } else {
+        // along the camera direction, its cross with the y-axis, and the y-axis
+        cam.pos.add(cam.dir.scaled(forward));
+        cam.pos.add(cam.dir.crossed(Vec3::UP).scaled(right));
+        cam.pos.add(Vec3::UP.neged().scaled(up));
+        if yaw != 0.0 || roll != 0.0{
+            cam.look(yaw, roll);
+        }
+    }
+    let zoomed = old_fov != cam.fov;
+    let retouched = changed_material.is_some();
+    let moved = old_pos != cam.pos || old_dir != cam.dir || sky_changed || refocused || zoomed || edited || retouched || restyled;
     state.render_mode = match (moved, state.render_mode){
         (true, _) => RenderMode::Reduced,
         (false, RenderMode::Reduced) => RenderMode::Full,
         _ => RenderMode::None,
     };
     state.moved = moved;
+    state.reprojectable = !sky_changed && !refocused && !zoomed && !edited && !retouched;
+    state.scene_changed = edited;
+    state.changed_material = changed_material;
+    state.post_changed = restyled;
     LoopRequest::Continue
 }
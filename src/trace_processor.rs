@@ -3,58 +3,144 @@ use crate::scene::Scene;
 use crate::info::Info;
 use crate::cl_helpers::create_five;
 use crate::misc::load_source;
-use crate::cpu::{ whitted };
+use crate::cpu::{ whitted, aov_pass };
 use crate::vec3::Vec3;
 use crate::state::{ RenderMode, State };
 use crate::config::ConfigParsed;
+use crate::sampler::{ SamplerType, blue_noise_mask };
+use crate::aov::{ Aov, ID_SIZE, aov_value, aov_display };
+use crate::denoise::{ AUX_SIZE, denoise_floatmap };
+use crate::adaptive::NOISE_CHECK_INTERVAL;
+use crate::post::AE_METER_INTERVAL;
 
 use ocl::{ Queue };
 
 use rand::prelude::*;
 
+pub const KERNEL_PATH: &str = "assets/kernels/raytrace.cl";
+
 pub trait TraceProcessor{
     fn update(&mut self, scene: &mut Scene, state: &State);
     fn render(&mut self, scene: &mut Scene, state: &mut State) -> &[u32];
+    // the last frame before post processing: three floats per pixel, top row first
+    fn linear_frame(&mut self, scene: &Scene, state: &State) -> Vec<f32>;
+    // raw values of an aov laid out like the linear frame, none if the tracer has no aovs
+    fn aov_frame(&mut self, scene: &Scene, state: &State, aov: Aov) -> Option<Vec<f32>>;
+    // the textures of the scene were reloaded
+    fn upload_textures(&mut self, scene: &Scene);
+    // builds the kernels from their source again and takes the whole scene anew, the old ones stay on an error
+    fn rebuild(&mut self, scene: &mut Scene) -> Result<(), String>;
+    // the frames are rendered at a new size, the frame buffers are allocated again
+    fn resize(&mut self, size: (u32, u32), scene: &mut Scene) -> Result<(), String>;
 }
 
 pub struct GpuWhitted{
     kernel: Box<TraceKernelWhitted>,
+    image_kernel: Box<ImageKernel>,
+    bloom_kernel: Option<Box<BloomKernel>>,
+    exposure: f32, // last metered by auto exposure, kept between meterings
+    size: (u32, u32),
     queue: Queue,
 }
 
 impl GpuWhitted{
     pub fn new((width, height): (u32, u32), scene: &mut Scene, info: &mut Info) -> Result<Self, String>{
-        let src = unpackdb!(load_source("assets/kernels/raytrace.cl"), "Could not load GpuWhitted's kernel!");
+        let src = unpackdb!(load_source(KERNEL_PATH), "Could not load GpuWhitted's kernel!");
         info.set_time_point("Loading source file");
         let (_, _, _, program, queue) = unpackdb!(create_five(&src), "Could not init GpuWhitted's program and queue!");
         info.set_time_point("Creating OpenCL objects");
         let kernel = unpackdb!(TraceKernelWhitted::new("raytracing", (width, height), &program, &queue, scene, info), "Could not create GpuWhitted!");
+        let stack = &scene.cam.post;
+        let bloom_kernel = if stack.bloom_strength > 0.0 {
+            Some(Box::new(unpackdb!(BloomKernel::new((width, height), stack, &program, &queue, kernel.get_buffer()), "Could not create GpuWhitted's bloom kernel!")))
+        } else {
+            None
+        };
+        let bloom = bloom_kernel.as_ref().map(|bk| bk.get_buffer());
+        let image_kernel = unpackdb!(ImageKernel::new("image_from_floatmap", (width, height), stack, &program, &queue, kernel.get_buffer(), bloom), "Could not create GpuWhitted's image kernel!");
         info.set_time_point("Last time stamp");
         Ok(Self{
             kernel: Box::new(kernel),
+            image_kernel: Box::new(image_kernel),
+            bloom_kernel,
+            exposure: 0.0,
+            size: (width, height),
             queue,
         })
     }
 }
 
 impl TraceProcessor for GpuWhitted{
-    fn update(&mut self, scene: &mut Scene, _: &State){
+    fn update(&mut self, scene: &mut Scene, state: &State){
         self.kernel.update(&self.queue, scene).expect("Could not update GpuWhitted's kernel!");
+        if state.scene_changed{
+            self.kernel.upload_scene(&self.queue, scene).expect("Could not upload GpuWhitted's scene!");
+        }
+        if let Some(mat) = state.changed_material{
+            self.kernel.upload_material(&self.queue, scene, mat).expect("Could not upload GpuWhitted's material!");
+        }
+        if state.post_changed{
+            self.image_kernel.set_post(&self.queue, &scene.cam.post, self.exposure).expect("Could not set GpuWhitted's image kernel's post parameters!");
+            if let Some(bk) = &mut self.bloom_kernel{
+                bk.set_post(&self.queue, &scene.cam.post, self.exposure).expect("Could not set GpuWhitted's bloom kernel's post parameters!");
+            }
+        }
     }
 
-    fn render(&mut self, _: &mut Scene, state: &mut State) -> &[u32]{
-        match state.render_mode{
-            RenderMode::Full | RenderMode::Reduced => {
-                state.last_frame = RenderMode::Full;
-                state.render_mode = RenderMode::None;
-                self.kernel.execute(&self.queue).expect("Could not execute GpuWhitted's kernel!");
-                self.kernel.get_result(&self.queue).expect("Could not get result of GpuWhitted!")
-            },
-            _ => {
-                state.last_frame = RenderMode::None;
-                self.kernel.get_result(&self.queue).expect("Could not get result of GpuWhitted!")
-            },
+    fn render(&mut self, scene: &mut Scene, state: &mut State) -> &[u32]{
+        // a change restarts the accumulation, then the aa passes soften the shadows of the lights
+        if state.render_mode != RenderMode::None{
+            state.render_mode = RenderMode::None;
+            state.samples_taken = 0;
         }
+        if state.samples_taken >= state.aa{
+            state.last_frame = RenderMode::None;
+            return self.image_kernel.get_result(&self.queue).expect("Could not get result of GpuWhitted's image kernel!");
+        }
+        state.last_frame = RenderMode::Full;
+        self.kernel.set_sample(state.samples_taken).expect("Could not set GpuWhitted's kernel's sample argument!");
+        self.kernel.execute(&self.queue).expect("Could not execute GpuWhitted's kernel!");
+        state.samples_taken += 1;
+        let stack = &scene.cam.post;
+        // metered on the first sample and every few samples after it
+        if stack.auto_exposure && (state.samples_taken == 1 || state.samples_taken % AE_METER_INTERVAL == 0){
+            self.exposure = self.kernel.meter_exposure(&self.queue, state.samples_taken);
+            self.image_kernel.set_post(&self.queue, stack, self.exposure).expect("Could not set GpuWhitted's image kernel's post parameters!");
+            if let Some(bk) = &mut self.bloom_kernel{
+                bk.set_post(&self.queue, stack, self.exposure).expect("Could not set GpuWhitted's bloom kernel's post parameters!");
+            }
+        }
+        let divider = state.samples_taken as f32;
+        if let Some(bk) = &mut self.bloom_kernel{
+            bk.set_divider(divider).expect("Could not set GpuWhitted's bloom kernel's divider argument!");
+            bk.execute(&self.queue).expect("Could not execute GpuWhitted's bloom kernel!");
+        }
+        self.image_kernel.set_divider(divider).expect("Could not set GpuWhitted's image kernel's divider argument!");
+        self.image_kernel.set_frame(state.samples_taken as u32).expect("Could not set GpuWhitted's image kernel's frame argument!");
+        self.image_kernel.execute(&self.queue).expect("Could not execute GpuWhitted's image kernel!");
+        self.image_kernel.get_result(&self.queue).expect("Could not get result of GpuWhitted's image kernel!")
+    }
+
+    fn linear_frame(&mut self, _: &Scene, state: &State) -> Vec<f32>{
+        self.kernel.linear_frame(&self.queue, state.samples_taken)
+    }
+
+    fn aov_frame(&mut self, _: &Scene, _: &State, _: Aov) -> Option<Vec<f32>>{
+        None
+    }
+
+    fn upload_textures(&mut self, scene: &Scene){
+        self.kernel.upload_textures(&self.queue, scene).expect("Could not upload GpuWhitted's textures!");
+    }
+
+    fn rebuild(&mut self, scene: &mut Scene) -> Result<(), String>{
+        *self = Self::new(self.size, scene, &mut Info::new())?;
+        Ok(())
+    }
+
+    fn resize(&mut self, size: (u32, u32), scene: &mut Scene) -> Result<(), String>{
+        *self = Self::new(size, scene, &mut Info::new())?;
+        Ok(())
     }
 }
 
@@ -62,51 +148,224 @@ pub struct GpuPath{
     trace_kernel: Box<TraceKernelPath>,
     image_kernel: Box<ImageKernel>,
     clear_kernel: Box<ClearKernel>,
+    denoise_kernel: Option<Box<DenoiseKernel>>,
+    bloom_kernel: Option<Box<BloomKernel>>,
+    reproject_kernel: Option<Box<ReprojectKernel>>,
+    mask_kernel: Option<Box<ConvergenceMaskKernel>>,
+    aov_kernel: Box<AovKernel>,
+    max_history: usize,
+    noise_target: f32,
+    exposure: f32, // last metered by auto exposure, kept between meterings
+    finished: bool, // the noise target was reached, nothing is traced until the view changes
+    size: (u32, u32),
+    options: PathOptions,
     queue: Queue,
 }
 
+// the parts of the config the kernels of GpuPath are built with
+#[derive(Clone, Copy)]
+struct PathOptions{
+    threshold: f32,
+    denoise_passes: u32,
+    denoise_sigma: f32,
+    reprojection: usize,
+    noise_target: f32,
+}
+
 impl GpuPath{
-    pub fn new((width, height): (u32, u32), scene: &mut Scene, conf: &ConfigParsed, info: &mut Info) -> Result<Self, String>{
-        let src = unpackdb!(load_source("assets/kernels/raytrace.cl"), "Could not load GpuPath's kernel!");
+    pub fn new(size: (u32, u32), scene: &mut Scene, conf: &ConfigParsed, info: &mut Info) -> Result<Self, String>{
+        let options = PathOptions{
+            threshold: conf.base.adaptive_threshold,
+            denoise_passes: conf.post.denoise_passes,
+            denoise_sigma: conf.post.denoise_sigma,
+            reprojection: conf.base.reprojection,
+            noise_target: conf.base.noise_target,
+        };
+        Self::with_options(size, scene, options, info)
+    }
+
+    fn with_options((width, height): (u32, u32), scene: &mut Scene, options: PathOptions, info: &mut Info) -> Result<Self, String>{
+        let src = unpackdb!(load_source(KERNEL_PATH), "Could not load GpuPath's kernel!");
         info.set_time_point("Loading source file");
         let (_, _, _, program, queue) = unpackdb!(create_five(&src), "Could not init GpuPath's program and queue!");
         info.set_time_point("Creating OpenCL objects");
-        let trace_kernel = unpackdb!(TraceKernelPath::new("pathtracing", (width, height), &program, &queue, scene, info), "Could not create GpuPath's trace kernel!");
-        let tm = conf.post.tone_map as u32;
-        let image_kernel = unpackdb!(ImageKernel::new("image_from_floatmap", (width, height), tm, &program, &queue, trace_kernel.get_buffer()), "Could not create GpuPath's image kernel!");
-        let clear_kernel = unpackdb!(ClearKernel::new("clear", (width, height), &program, &queue, trace_kernel.get_buffer()), "Could not create GpuPath's clear kernel!");
+        let threshold = options.threshold;
+        let trace_kernel = unpackdb!(TraceKernelPath::new("pathtracing", (width, height), threshold, &program, &queue, scene, info), "Could not create GpuPath's trace kernel!");
+        let denoise_kernel = if options.denoise_passes > 0 {
+            let (passes, sigma) = (options.denoise_passes, options.denoise_sigma);
+            Some(Box::new(unpackdb!(DenoiseKernel::new("denoise", (width, height), passes, sigma, &program, &queue, trace_kernel.get_buffer(), trace_kernel.get_aux_buffer()), "Could not create GpuPath's denoise kernel!")))
+        } else {
+            None
+        };
+        let image_input = match &denoise_kernel{
+            Some(dk) => dk.get_buffer(),
+            None => trace_kernel.get_buffer(),
+        };
+        let stack = &scene.cam.post;
+        let bloom_kernel = if stack.bloom_strength > 0.0 {
+            Some(Box::new(unpackdb!(BloomKernel::new((width, height), stack, &program, &queue, image_input), "Could not create GpuPath's bloom kernel!")))
+        } else {
+            None
+        };
+        let bloom = bloom_kernel.as_ref().map(|bk| bk.get_buffer());
+        let image_kernel = unpackdb!(ImageKernel::new("image_from_floatmap", (width, height), stack, &program, &queue, image_input, bloom), "Could not create GpuPath's image kernel!");
+        let clear_kernel = unpackdb!(ClearKernel::new("clear", (width, height), &program, &queue, trace_kernel.get_buffer(), trace_kernel.get_aux_buffer(), trace_kernel.get_stat_buffer()), "Could not create GpuPath's clear kernel!");
+        let mask_kernel = if threshold > 0.0 {
+            Some(Box::new(unpackdb!(ConvergenceMaskKernel::new("convergence_mask", (width, height), threshold, &program, &queue, trace_kernel.get_buffer(), trace_kernel.get_stat_buffer(), image_kernel.get_buffer()), "Could not create GpuPath's convergence mask kernel!")))
+        } else {
+            None
+        };
+        let aov_kernel = unpackdb!(AovKernel::new("aov_view", (width, height), &program, &queue, trace_kernel.get_buffer(), trace_kernel.get_aux_buffer(), trace_kernel.get_id_buffer(), image_kernel.get_buffer()), "Could not create GpuPath's aov kernel!");
+        let max_history = options.reprojection;
+        let reproject_kernel = if max_history > 1 {
+            let (params, prev_params) = trace_kernel.get_params_buffers();
+            Some(Box::new(unpackdb!(ReprojectKernel::new("reproject", (width, height), &program, &queue, trace_kernel.get_buffer(), trace_kernel.get_aux_buffer(), trace_kernel.get_stat_buffer(), params, prev_params), "Could not create GpuPath's reproject kernel!")))
+        } else {
+            None
+        };
         info.set_time_point("Last time stamp");
         Ok(Self{
             trace_kernel: Box::new(trace_kernel),
             image_kernel: Box::new(image_kernel),
             clear_kernel: Box::new(clear_kernel),
+            denoise_kernel,
+            bloom_kernel,
+            reproject_kernel,
+            mask_kernel,
+            aov_kernel: Box::new(aov_kernel),
+            max_history,
+            noise_target: options.noise_target,
+            exposure: 0.0,
+            finished: false,
+            size: (width, height),
+            options,
             queue,
         })
     }
 
+    // traces a sample, stops when the noise target is reached
+    fn trace(&mut self, state: &mut State){
+        // samples of the last view that are carried over into the new one
+        let mut history = 0;
+        if state.moved{
+            if let Some(rk) = &mut self.reproject_kernel{
+                if state.reprojectable && state.samples_taken > 0{
+                    history = state.samples_taken;
+                    let tk = &self.trace_kernel;
+                    rk.store_history(&self.queue, tk.get_buffer(), tk.get_aux_buffer(), tk.get_stat_buffer())
+                        .expect("Could not store GpuPath's history!");
+                }
+            }
+            self.clear_kernel.execute(&self.queue).expect("Could not execute GpuPath's clearn kernel!");
+            state.samples_taken = 0;
+        }
+        self.trace_kernel.execute(&self.queue).expect("Could not execute GpuPath's trace kernel!");
+        state.samples_taken += 1;
+        if let (Some(rk), true) = (&mut self.reproject_kernel, history > 0){
+            let samples = (history + 1).min(self.max_history);
+            rk.set_samples(history, samples).expect("Could not set GpuPath's reproject kernel's sample arguments!");
+            rk.execute(&self.queue).expect("Could not execute GpuPath's reproject kernel!");
+            state.samples_taken = samples;
+        }
+        if self.noise_target > 0.0 && state.samples_taken % NOISE_CHECK_INTERVAL == 0{
+            let noise = self.trace_kernel.noise_level(&self.queue, state.samples_taken);
+            if noise < self.noise_target{
+                println!("Noise target reached after {} samples", state.samples_taken);
+                self.finished = true;
+            }
+        }
+    }
 }
 
 impl TraceProcessor for GpuPath{
     fn update(&mut self, scene: &mut Scene, state: &State){
         self.trace_kernel.update(&self.queue, scene, state).expect("Could not update GpuPath's trace kernel!");
+        if state.scene_changed{
+            self.trace_kernel.upload_scene(&self.queue, scene).expect("Could not upload GpuPath's scene!");
+        }
+        if let Some(mat) = state.changed_material{
+            self.trace_kernel.upload_material(&self.queue, scene, mat).expect("Could not upload GpuPath's material!");
+        }
+        if state.post_changed{
+            self.image_kernel.set_post(&self.queue, &scene.cam.post, self.exposure).expect("Could not set GpuPath's image kernel's post parameters!");
+            if let Some(bk) = &mut self.bloom_kernel{
+                bk.set_post(&self.queue, &scene.cam.post, self.exposure).expect("Could not set GpuPath's bloom kernel's post parameters!");
+            }
+        }
     }
 
-    fn render(&mut self, _: &mut Scene, state: &mut State) -> &[u32]{
-        state.last_frame = RenderMode::Full;
+    fn render(&mut self, scene: &mut Scene, state: &mut State) -> &[u32]{
         state.render_mode = RenderMode::Full;
         if state.moved{
-            self.clear_kernel.execute(&self.queue).expect("Could not execute GpuPath's clearn kernel!");
-            state.samples_taken = 0;
+            self.finished = false;
+        }
+        if self.finished{
+            state.last_frame = RenderMode::None;
+        } else {
+            state.last_frame = RenderMode::Full;
+            self.trace(state);
         }
-        self.trace_kernel.execute(&self.queue).expect("Could not execute GpuPath's trace kernel!");
-        state.samples_taken += 1;
         if state.settings.calc_frame_energy{
             state.frame_energy = self.trace_kernel.frame_energy(&self.queue) / state.samples_taken as f32;
         }
-        self.image_kernel.set_divider(state.samples_taken as f32).expect("Could not set GpuPath's clear kernel's divider argument!");
+        let stack = &scene.cam.post;
+        // metered when the view changes and every few samples after it, not once the tracing stopped
+        let meter = state.moved || state.samples_taken % AE_METER_INTERVAL == 0;
+        if stack.auto_exposure && state.last_frame == RenderMode::Full && meter{
+            self.exposure = self.trace_kernel.meter_exposure(&self.queue, state.samples_taken);
+            self.image_kernel.set_post(&self.queue, stack, self.exposure).expect("Could not set GpuPath's image kernel's post parameters!");
+            if let Some(bk) = &mut self.bloom_kernel{
+                bk.set_post(&self.queue, stack, self.exposure).expect("Could not set GpuPath's bloom kernel's post parameters!");
+            }
+        }
+        // the denoiser already divides by the samples taken
+        let divider = if let Some(dk) = &mut self.denoise_kernel{
+            dk.set_samples(state.samples_taken).expect("Could not set GpuPath's denoise kernel's sample arguments!");
+            dk.execute(&self.queue).expect("Could not execute GpuPath's denoise kernel!");
+            1.0
+        } else {
+            state.samples_taken as f32
+        };
+        if let Some(bk) = &mut self.bloom_kernel{
+            bk.set_divider(divider).expect("Could not set GpuPath's bloom kernel's divider argument!");
+            bk.execute(&self.queue).expect("Could not execute GpuPath's bloom kernel!");
+        }
+        self.image_kernel.set_divider(divider).expect("Could not set GpuPath's image kernel's divider argument!");
+        self.image_kernel.set_frame(state.samples_taken as u32).expect("Could not set GpuPath's image kernel's frame argument!");
         self.image_kernel.execute(&self.queue).expect("Could not execute GpuPath's image kernel!");
+        if let (Some(mk), true) = (&mut self.mask_kernel, state.show_convergence){
+            mk.set_samples(state.samples_taken).expect("Could not set GpuPath's convergence mask kernel's samples argument!");
+            mk.execute(&self.queue).expect("Could not execute GpuPath's convergence mask kernel!");
+        }
+        if state.aov != Aov::Beauty{
+            self.aov_kernel.set_samples(state.samples_taken).expect("Could not set GpuPath's aov kernel's samples argument!");
+            self.aov_kernel.set_aov(state.aov).expect("Could not set GpuPath's aov kernel's aov argument!");
+            self.aov_kernel.execute(&self.queue).expect("Could not execute GpuPath's aov kernel!");
+        }
         self.image_kernel.get_result(&self.queue).expect("Could not get result of GpuPath's image kernel!")
     }
+
+    fn linear_frame(&mut self, _: &Scene, state: &State) -> Vec<f32>{
+        self.trace_kernel.linear_frame(&self.queue, state.samples_taken)
+    }
+
+    fn aov_frame(&mut self, _: &Scene, state: &State, aov: Aov) -> Option<Vec<f32>>{
+        Some(self.trace_kernel.aov_frame(&self.queue, state.samples_taken, aov))
+    }
+
+    fn upload_textures(&mut self, scene: &Scene){
+        self.trace_kernel.upload_textures(&self.queue, scene).expect("Could not upload GpuPath's textures!");
+    }
+
+    fn rebuild(&mut self, scene: &mut Scene) -> Result<(), String>{
+        *self = Self::with_options(self.size, scene, self.options, &mut Info::new())?;
+        Ok(())
+    }
+
+    fn resize(&mut self, size: (u32, u32), scene: &mut Scene) -> Result<(), String>{
+        *self = Self::with_options(size, scene, self.options, &mut Info::new())?;
+        Ok(())
+    }
 }
 
 pub struct CpuWhitted{
@@ -114,9 +373,16 @@ pub struct CpuWhitted{
     height: usize,
     threads: usize,
     screen_buffer: Vec<u32>,
+    aov_buffer: Vec<u32>,
     float_buffer: Vec<Vec3>,
+    guides: Vec<f32>,
+    ids: Vec<u32>,
+    shown_aov: Aov,
+    denoise_passes: u32, // the exported frame is denoised, zero disables it
+    denoise_sigma: f32,
     texture_params: Vec<u32>,
     textures: Vec<u8>,
+    blue_noise: Vec<f32>,
     rng: ThreadRng,
 }
 
@@ -129,17 +395,32 @@ impl CpuWhitted{
         let screen_buffer = vec![0; width * height];
         info.set_time_point("Creating screen buffer");
         let float_buffer = vec![Vec3::ZERO; width * height];
+        let blue_noise = if scene.sampler == SamplerType::BlueNoise { blue_noise_mask() } else { Vec::new() };
+        info.set_time_point("Generating blue noise");
         Self{
             width,
             height,
             threads,
             screen_buffer,
+            aov_buffer: vec![0; width * height],
             float_buffer,
+            guides: Vec::new(),
+            ids: Vec::new(),
+            shown_aov: Aov::Beauty,
+            denoise_passes: 0,
+            denoise_sigma: 1.0,
             texture_params,
             textures,
+            blue_noise,
             rng: rand::thread_rng(),
         }
     }
+
+    pub fn with_denoiser(mut self, passes: u32, sigma: f32) -> Self{
+        self.denoise_passes = passes;
+        self.denoise_sigma = sigma;
+        self
+    }
 }
 
 impl TraceProcessor for CpuWhitted{
@@ -148,9 +429,97 @@ impl TraceProcessor for CpuWhitted{
     fn render(&mut self, scene: &mut Scene, state: &mut State) -> &[u32]{
         whitted(
             self.width, self.height, self.threads,
-            scene, &self.texture_params, &self.textures,
+            scene, &self.texture_params, &self.textures, &self.blue_noise,
             &mut self.screen_buffer, &mut self.float_buffer, state, &mut self.rng
         );
-        &self.screen_buffer
+        if state.aov == Aov::Beauty{
+            self.shown_aov = Aov::Beauty;
+            return &self.screen_buffer;
+        }
+        if state.last_frame != RenderMode::None || self.shown_aov != state.aov{
+            self.shown_aov = state.aov;
+            let values = self.aov_values(scene, state, state.aov);
+            for (pixel, v) in self.aov_buffer.iter_mut().zip(values.chunks_exact(3)){
+                let c = aov_display(state.aov, Vec3::new(v[0], v[1], v[2])).scaled(255.0);
+                *pixel = ((c.x as u32) << 16) + ((c.y as u32) << 8) + c.z as u32;
+            }
+        }
+        &self.aov_buffer
+    }
+
+    // a full frame is denoised if the denoiser is on, its guides are traced once for all samples when it is exported
+    fn linear_frame(&mut self, scene: &Scene, state: &State) -> Vec<f32>{
+        let (w, h) = (self.width, self.height);
+        if self.denoise_passes == 0 || state.last_frame == RenderMode::Reduced{
+            return self.accumulated(state);
+        }
+        aov_pass(w, h, self.threads, scene, &self.texture_params, &self.textures, &self.blue_noise,
+            &mut self.guides, &mut self.ids);
+        let samples = state.samples_taken.max(1);
+        let floatmap: Vec<f32> = self.float_buffer.iter().take(w * h).flat_map(|c| c.as_array()).collect();
+        let auxmap: Vec<f32> = self.guides.iter().map(|v| v * samples as f32).collect();
+        denoise_floatmap(&floatmap, &auxmap, w, h, samples, self.denoise_passes, self.denoise_sigma)
+    }
+
+    fn aov_frame(&mut self, scene: &Scene, state: &State, aov: Aov) -> Option<Vec<f32>>{
+        Some(self.aov_values(scene, state, aov))
+    }
+
+    fn upload_textures(&mut self, scene: &Scene){
+        self.texture_params = scene.get_texture_params_buffer();
+        self.textures = scene.get_textures_buffer();
+    }
+
+    // nothing to build, the cpu only keeps copies of the textures and the blue noise
+    fn rebuild(&mut self, scene: &mut Scene) -> Result<(), String>{
+        self.upload_textures(scene);
+        self.blue_noise = if scene.sampler == SamplerType::BlueNoise { blue_noise_mask() } else { Vec::new() };
+        Ok(())
+    }
+
+    fn resize(&mut self, (width, height): (u32, u32), _: &mut Scene) -> Result<(), String>{
+        let (w, h) = (width as usize, height as usize);
+        self.width = w;
+        self.height = h;
+        self.screen_buffer = vec![0; w * h];
+        self.aov_buffer = vec![0; w * h];
+        self.float_buffer = vec![Vec3::ZERO; w * h];
+        self.guides.clear();
+        self.ids.clear();
+        Ok(())
+    }
+}
+
+impl CpuWhitted{
+    // a reduced frame is stored at reduced resolution with a single sample
+    fn accumulated(&self, state: &State) -> Vec<f32>{
+        let (reduce, samples) = match state.last_frame{
+            RenderMode::Reduced => (state.reduced_rate, 1),
+            _ => (1, state.samples_taken.max(1)),
+        };
+        let (rw, rh) = (self.width / reduce, self.height / reduce);
+        let mult = 1.0 / samples as f32;
+        (0..self.width * self.height).flat_map(|i| {
+            let (x, y) = (i % self.width, i / self.width);
+            self.float_buffer[(x / reduce).min(rw - 1) + (y / reduce).min(rh - 1) * rw].scaled(mult).as_array()
+        }).collect()
+    }
+
+    // traces the first hits at the resolution of the last frame and scales them up like the linear frame
+    fn aov_values(&mut self, scene: &Scene, state: &State, aov: Aov) -> Vec<f32>{
+        let reduce = match state.last_frame{
+            RenderMode::Reduced => state.reduced_rate,
+            _ => 1,
+        };
+        let (rw, rh) = (self.width / reduce, self.height / reduce);
+        aov_pass(rw, rh, self.threads, scene, &self.texture_params, &self.textures, &self.blue_noise,
+            &mut self.guides, &mut self.ids);
+        let linear = self.accumulated(state);
+        (0..self.width * self.height).flat_map(|i| {
+            let (x, y) = (i % self.width, i / self.width);
+            let r = (x / reduce).min(rw - 1) + (y / reduce).min(rh - 1) * rw;
+            let col = Vec3::new(linear[i * 3], linear[i * 3 + 1], linear[i * 3 + 2]);
+            aov_value(aov, col, &self.guides[r * AUX_SIZE..(r + 1) * AUX_SIZE], &self.ids[r * ID_SIZE..(r + 1) * ID_SIZE]).as_array()
+        }).collect()
     }
 }
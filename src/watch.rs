@@ -0,0 +1,72 @@
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant, SystemTime };
+
+// Polls the modification times of files, a few checks a second are cheap next to a frame.
+// A file that can not be read is not reported until it is back, mid-save editors often remove it shortly.
+
+pub struct FileWatcher{
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    interval: Duration,
+    last_check: Instant,
+}
+
+impl FileWatcher{
+    pub fn new(interval: Duration) -> Self{
+        Self{ files: Vec::new(), interval, last_check: Instant::now() }
+    }
+
+    pub fn watch(&mut self, path: &Path){
+        if self.files.iter().any(|(p, _)| p == path){
+            return;
+        }
+        self.files.push((path.to_path_buf(), modified(path)));
+    }
+
+    pub fn clear(&mut self){
+        self.files.clear();
+    }
+
+    // the files that changed since the last check, nothing until the interval passed
+    pub fn changed(&mut self) -> Vec<PathBuf>{
+        if self.last_check.elapsed() < self.interval{
+            return Vec::new();
+        }
+        self.last_check = Instant::now();
+        let mut res = Vec::new();
+        for (path, time) in &mut self.files{
+            let now = modified(path);
+            if now.is_some() && now != *time{
+                res.push(path.clone());
+            }
+            if now.is_some(){
+                *time = now;
+            }
+        }
+        res
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime>{
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod test{
+    use crate::watch::*;
+    use std::fs::File;
+
+    #[test]
+    fn reports_modified_files_once(){
+        let path = std::env::temp_dir().join(format!("clrays_watch_{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let mut watcher = FileWatcher::new(Duration::ZERO);
+        watcher.watch(&path);
+        watcher.watch(&path);
+        assert!(watcher.changed().is_empty());
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(watcher.changed(), vec![path.clone()]);
+        assert!(watcher.changed().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(watcher.changed().is_empty());
+    }
+}
@@ -1,26 +1,64 @@
-use crate::state::{ State, LoopRequest, InputFn, UpdateFn };
-use crate::trace_processor::TraceProcessor;
+use crate::state::{ State, LoopRequest, InputFn, UpdateFn, RenderMode };
+use crate::trace_processor::{ TraceProcessor, KERNEL_PATH };
 use crate::scene::Scene;
+use crate::config::{ ExportParsed, scaled_size };
+use crate::export::{ export_frame, needs_linear, save_screenshot };
+use crate::aov::Aov;
+use crate::watch::FileWatcher;
+use crate::info::Info;
+use crate::hud::{ hud_lines, text_image, HUD_SCALE };
 
 use stopwatch::Stopwatch;
-use sdl2::event::Event;
+use sdl2::event::{ Event, WindowEvent };
 
 use std::path::Path;
-use std::fs::File;
-use std::io::BufWriter;
-use std::time::{ SystemTime, UNIX_EPOCH };
+use std::time::Duration;
 
+// colour of the outline of the selected object
+const HIGHLIGHT: u32 = 0xffa000;
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+// pixels between the overlay and the corner of the window
+const HUD_MARGIN: i32 = 8;
+
+// builds the scene from the config file at the path
+pub type SceneFn = fn(&str) -> Result<Scene, String>;
 
 pub struct Window{
     title: String,
     width: u32,
     height: u32,
+    render_scale: f32, // of the window size, the tracer renders at this size
+    export: ExportParsed,
+    hot_reload: Option<(String, SceneFn)>, // the config file and how to build its scene
+    memory: String, // summary of the buffer sizes, shown in the overlay
 }
 
 impl Window
 {
     pub fn new(title: &str, width: u32, height: u32) -> Self{
-        Self { title: title.to_string(), width, height }
+        Self { title: title.to_string(), width, height, render_scale: 1.0, export: ExportParsed::default(), hot_reload: None, memory: String::new() }
+    }
+
+    // reloads the kernel, textures and the scene of the config when their files change
+    pub fn with_hot_reload(mut self, config: &str, scene_fn: SceneFn) -> Self{
+        self.hot_reload = Some((config.to_string(), scene_fn));
+        self
+    }
+
+    pub fn with_info(mut self, info: &Info) -> Self{
+        self.memory = info.memory_summary();
+        self
+    }
+
+    // the tracer passed to run needs to be made at the scaled size
+    pub fn with_render_scale(mut self, render_scale: f32) -> Self{
+        self.render_scale = render_scale;
+        self
+    }
+
+    pub fn with_export(mut self, export: ExportParsed) -> Self{
+        self.export = export;
+        self
     }
 
     pub fn run(
@@ -36,21 +74,28 @@ impl Window
 
         let contex = unpackdb!(sdl2::init(), "Could not init sdl2!");
         let video_subsystem = unpackdb!(contex.video(), "Could not get sdl video subsystem!");
-        let window = unpackdb!(video_subsystem.window(&self.title, self.width, self.height)
+        let mut window = unpackdb!(video_subsystem.window(&self.title, self.width, self.height)
             .position_centered()
             .opengl()
+            .resizable()
             .build(),
             "Could not create window!");
         let _gl_contex = window.gl_create_context().unwrap(); // needs to exist
         #[allow(dead_code)]
         let _gl = gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void);
 
+        let controller_subsystem = unpackdb!(contex.game_controller(), "Could not get sdl game controller subsystem!");
+        // open controllers send their events until they are dropped
+        let mut controllers = Vec::new();
+
         let mut event_pump = unpackdb!(contex.event_pump(), "Could not get sdl event pump!");
 
         let mut elapsed = watch.elapsed_ms();
         let mut texture = 0u32;
         let mut fbo = 0u32;
-        let (glw, glh) = (self.width as i32, self.height as i32);
+        let mut hud_texture = 0u32;
+        let mut hud_fbo = 0u32;
+        let mut size = scaled_size((self.width, self.height), self.render_scale);
         unsafe {
             gl::GenTextures(1, &mut texture);
             gl::BindTexture(gl::TEXTURE_2D, texture);
@@ -61,30 +106,115 @@ impl Window
             gl::GenFramebuffers(1, &mut fbo);
             gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
             gl::FramebufferTexture(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, texture, 0);
+            gl::GenTextures(1, &mut hud_texture);
+            gl::BindTexture(gl::TEXTURE_2D, hud_texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::GenFramebuffers(1, &mut hud_fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, hud_fbo);
+            gl::FramebufferTexture(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, hud_texture, 0);
         }
         println!("SDL+OpenGl setup time: {} ms", elapsed);
+        state.window_size = (self.width, self.height);
         let mut last_frame = 0.0;
+        let mut shown_aov = Aov::Beauty;
+        let mut watcher = FileWatcher::new(WATCH_INTERVAL);
+        if let Some((config, _)) = &self.hot_reload{
+            watch_files(&mut watcher, config, scene);
+        }
         loop {
             // Pump all sdl2 events into vector
             let events : Vec<Event> = event_pump.poll_iter().collect();
+            // controllers connected at the start are added too
+            for event in &events{
+                match event{
+                    Event::ControllerDeviceAdded { which, .. } => match controller_subsystem.open(*which){
+                        Ok(controller) => {
+                            println!("Gamepad connected: {}", controller.name());
+                            controllers.push(controller);
+                        },
+                        Err(e) => println!("Could not open gamepad {}: {}", which, e),
+                    },
+                    Event::ControllerDeviceRemoved { which, .. } => controllers.retain(|c| c.instance_id() != *which),
+                    _ => {},
+                }
+            }
+            // only the last size counts when the window is dragged
+            let resized = events.iter().rev().find_map(|event| match event{
+                Event::Window { win_event: WindowEvent::SizeChanged(w, h), .. } => Some((*w as u32, *h as u32)),
+                _ => None,
+            });
+            if let Some((w, h)) = resized{
+                self.resize((w, h), &mut size, state, tracer, scene);
+            }
 
             let upd_res = update_fn(last_frame, state);
             if upd_res == LoopRequest::Stop { break; };
             let inp_res = input_fn(&events, scene, state);
             if inp_res == LoopRequest::Stop { break; }
 
+            if self.hot_reload.is_some(){
+                self.reload_changed(&mut watcher, state, tracer, scene);
+            }
+
+            if state.mouse_look != contex.mouse().relative_mouse_mode(){
+                contex.mouse().set_relative_mouse_mode(state.mouse_look);
+            }
+
+            if state.aov != shown_aov{
+                shown_aov = state.aov;
+                let title = if shown_aov == Aov::Beauty {
+                    self.title.clone()
+                } else {
+                    format!("{} [{}]", self.title, shown_aov.name())
+                };
+                unpackdb!(window.set_title(&title), "Could not set window title!");
+            }
+
             tracer.update(scene, state);
             let int_tex = tracer.render(scene, state);
-            if upd_res == LoopRequest::Export || inp_res == LoopRequest::Export{
-                export(self.width, self.height, int_tex);
+            let exporting = upd_res == LoopRequest::Export || inp_res == LoopRequest::Export;
+            let export_tex = if exporting { Some(int_tex.to_vec()) } else { None };
+            if inp_res == LoopRequest::Screenshot{
+                save_screenshot(size.0, size.1, int_tex);
             }
+            // the selection is only outlined in the window, not in exported frames
+            let (tw, th) = size;
+            let aspect = tw as f32 / th as f32;
+            let outlined = state.selected.and_then(|prim| scene.screen_rect(&prim, aspect))
+                .map(|rect| outline(int_tex, tw as usize, th as usize, rect));
+            let shown_tex = outlined.as_deref().unwrap_or(int_tex);
 
+            let (glw, glh) = (self.width as i32, self.height as i32);
+            let (tw, th) = (tw as i32, th as i32);
+            // a frame rendered smaller than the window is scaled up by the blit
+            let filter = if (tw, th) == (glw, glh) { gl::NEAREST } else { gl::LINEAR };
             unsafe{
-                gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32, glw, glh, 0, gl::BGRA, gl::UNSIGNED_BYTE, int_tex.as_ptr() as *mut std::ffi::c_void);
+                gl::BindTexture(gl::TEXTURE_2D, texture);
+                gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32, tw, th, 0, gl::BGRA, gl::UNSIGNED_BYTE, shown_tex.as_ptr() as *mut std::ffi::c_void);
                 gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                gl::Viewport(0, 0, glw, glh);
                 gl::BindFramebuffer(gl::READ_FRAMEBUFFER, fbo);
-                gl::BlitFramebuffer(0, 0, glw, glh, 0, glh, glw, 0, gl::COLOR_BUFFER_BIT, gl::NEAREST);
-                window.gl_swap_window();
+                gl::BlitFramebuffer(0, 0, tw, th, 0, glh, glw, 0, gl::COLOR_BUFFER_BIT, filter);
+            }
+            // the overlay goes over the blitted frame in the top left corner
+            if state.show_hud{
+                let lines = hud_lines(state, scene, size, &self.memory);
+                let (hw, hh, hud) = text_image(&lines, HUD_SCALE);
+                let (hw, hh) = (hw as i32, hh as i32);
+                unsafe{
+                    gl::BindTexture(gl::TEXTURE_2D, hud_texture);
+                    gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32, hw, hh, 0, gl::BGRA, gl::UNSIGNED_BYTE, hud.as_ptr() as *mut std::ffi::c_void);
+                    gl::BindFramebuffer(gl::READ_FRAMEBUFFER, hud_fbo);
+                    gl::BlitFramebuffer(0, 0, hw, hh, HUD_MARGIN, glh - HUD_MARGIN, HUD_MARGIN + hw, glh - HUD_MARGIN - hh, gl::COLOR_BUFFER_BIT, gl::NEAREST);
+                }
+            }
+            window.gl_swap_window();
+            if let Some(tex) = export_tex{
+                let linear = if needs_linear(&self.export) { tracer.linear_frame(scene, state) } else { Vec::new() };
+                let aovs: Vec<_> = self.export.aovs.iter()
+                    .filter_map(|aov| tracer.aov_frame(scene, state, *aov).map(|values| (*aov, values))).collect();
+                export_frame(&self.export, size.0, size.1, &tex, &linear, &aovs, state.samples_taken);
             }
             let e = watch.elapsed_ms();
             last_frame = (e - elapsed) as f32;
@@ -92,40 +222,97 @@ impl Window
         }
         Ok(())
     }
-}
 
-fn export(w: u32, h: u32, tex: &[u32]){
-    let filename = match SystemTime::now().duration_since(UNIX_EPOCH){
-        Ok(n) => format!("{}.png", n.as_secs()),
-        Err(_) => "0.png".to_string(),
-    };
-    let path = Path::new(&filename);
-    let file = File::create(path).expect("Frag: could not open file for frame image.");
-    let writer = &mut BufWriter::new(file);
-
-    let mut encoder = png::Encoder::new(writer, w, h);
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_depth(png::BitDepth::Eight);
-    let mut writer = encoder.write_header().expect("Frag: could not write png header for frame image.");
-
-    let sw = w as usize;
-    let sh = h as usize;
-    let size = sw * sh;
-
-    // flip y
-    let mut transformed = vec![0; size * 3];
-    for y in 0..sh{
-    for x in 0..sw{
-        let int = tex[y * sw + x];
-        transformed[y * sw * 3 + x * 3 + 2] = (int & 0x000000ff) as u8;
-        transformed[y * sw * 3 + x * 3 + 1] = ((int & 0x0000ff00) >> 8) as u8;
-        transformed[y * sw * 3 + x * 3    ] = ((int & 0x00ff0000) >> 16) as u8;
+    // the window got a new size, the tracer renders at the scaled size of it from now on
+    fn resize(&mut self, (w, h): (u32, u32), size: &mut (u32, u32), state: &mut State, tracer: &mut impl TraceProcessor, scene: &mut Scene){
+        self.width = w.max(1);
+        self.height = h.max(1);
+        state.window_size = (self.width, self.height);
+        let new_size = scaled_size((self.width, self.height), self.render_scale);
+        if new_size == *size{
+            return;
+        }
+        match tracer.resize(new_size, scene){
+            Ok(()) => *size = new_size,
+            // the old frame is stretched to the window instead
+            Err(e) => println!("Could not resize the frame to {}x{}: {}", new_size.0, new_size.1, e),
+        }
+        state.moved = true;
+        state.reprojectable = false;
+        state.render_mode = RenderMode::Reduced;
+        state.samples_taken = 0;
+    }
+
+    fn reload_changed(&self, watcher: &mut FileWatcher, state: &mut State, tracer: &mut impl TraceProcessor, scene: &mut Scene){
+        let (config, scene_fn) = match &self.hot_reload{
+            Some(x) => x,
+            None => return,
+        };
+        let changed = watcher.changed();
+        for path in &changed{
+            if path == Path::new(KERNEL_PATH){
+                match tracer.rebuild(scene){
+                    Ok(()) => println!("Reloaded {}", KERNEL_PATH),
+                    Err(e) => println!("Could not reload {}, keeping the old kernel: {}", KERNEL_PATH, e),
+                }
+            } else if path == Path::new(config){
+                match scene_fn(config){
+                    Ok(mut new_scene) => {
+                        // the view stays where it is
+                        let cam = &scene.cam;
+                        new_scene.cam.pos = cam.pos;
+                        new_scene.cam.dir = cam.dir;
+                        new_scene.cam.ori = cam.ori;
+                        new_scene.cam.target = cam.target;
+                        *scene = new_scene;
+                        state.selected = None;
+                        match tracer.rebuild(scene){
+                            Ok(()) => println!("Reloaded the scene of {}", config),
+                            Err(e) => println!("Could not rebuild the scene of {}: {}", config, e),
+                        }
+                        watch_files(watcher, config, scene);
+                    },
+                    Err(e) => println!("Could not reload {}, keeping the old scene: {}", config, e),
+                }
+            } else if let Some(file) = path.to_str(){
+                if scene.reload_texture(file){
+                    tracer.upload_textures(scene);
+                    println!("Reloaded {}", file);
+                }
+            }
+        }
+        if !changed.is_empty(){
+            state.moved = true;
+            state.reprojectable = false;
+            state.render_mode = RenderMode::Reduced;
+        }
     }
+}
+
+fn watch_files(watcher: &mut FileWatcher, config: &str, scene: &Scene){
+    watcher.clear();
+    watcher.watch(Path::new(KERNEL_PATH));
+    watcher.watch(Path::new(config));
+    for path in scene.texture_paths(){
+        watcher.watch(Path::new(path));
     }
+}
 
-    if let Err(e) = writer.write_image_data(&transformed){
-        println!("Could not save frame image: {}", e);
-    } else {
-        println!("Frame exported!");
+// copy of the frame with a rectangle drawn on it; left, top, right and bottom in 0..1 of the screen
+fn outline(tex: &[u32], w: usize, h: usize, [l, t, r, b]: [f32; 4]) -> Vec<u32>{
+    let mut res = tex.to_vec();
+    if r < 0.0 || l > 1.0 || b < 0.0 || t > 1.0{
+        return res;
+    }
+    let px = |x: f32, size: usize| (x * size as f32).clamp(0.0, (size - 1) as f32) as usize;
+    let (x0, x1, y0, y1) = (px(l, w), px(r, w), px(t, h), px(b, h));
+    for x in x0..=x1{
+        res[x + y0 * w] = HIGHLIGHT;
+        res[x + y1 * w] = HIGHLIGHT;
+    }
+    for y in y0..=y1{
+        res[x0 + y * w] = HIGHLIGHT;
+        res[x1 + y * w] = HIGHLIGHT;
     }
+    res
 }